@@ -0,0 +1,139 @@
+//! Compares MmapRangeReader against SyncFileRangeReader and FileRangeReader by
+//! reading every file out of a synthetic DAT
+//!
+//! Needs the mmap feature on top of the default core and tokio features, so
+//! run with `cargo bench --features mmap`
+
+use std::{
+    fs::File,
+    hint::black_box,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use libac_rs::dat::reader::{
+    block_chain_validator::BlockChainValidator, dat_file_reader::DatFileReader,
+    file_reader::FileRangeReader, mmap_reader::MmapRangeReader,
+    sync_dat_file_reader::SyncDatFileReader, sync_file_reader::SyncFileRangeReader,
+};
+
+const BLOCK_SIZE: u32 = 1024;
+const FILE_COUNT: u32 = 2000;
+const ITERATIONS: u32 = 5;
+
+/// Lay out FILE_COUNT block chains of varying sizes back to back, returning
+/// the path of the DAT and the (offset, size) of every file in it
+fn write_synthetic_dat() -> (PathBuf, Vec<(u32, u32)>) {
+    let mut data = vec![0u8; BLOCK_SIZE as usize];
+    let mut files = Vec::new();
+
+    for i in 0..FILE_COUNT {
+        // Mix of single-block files and multi-block ones, like a portal.dat
+        let size = if i % 4 == 0 {
+            200 + i % 700
+        } else {
+            1500 + (i * 37) % 20000
+        };
+        let block_count = size.div_ceil(BLOCK_SIZE - 4);
+        let offset = data.len() as u32;
+
+        for block in 0..block_count {
+            let next = if block + 1 < block_count {
+                offset + (block + 1) * BLOCK_SIZE
+            } else {
+                0
+            };
+            data.extend_from_slice(&next.to_le_bytes());
+            data.extend(std::iter::repeat_n(
+                (i % 251) as u8,
+                (BLOCK_SIZE - 4) as usize,
+            ));
+        }
+
+        files.push((offset, size));
+    }
+
+    let path = std::env::temp_dir().join(format!("libac-bench-{}.dat", std::process::id()));
+    File::create(&path).unwrap().write_all(&data).unwrap();
+
+    (path, files)
+}
+
+fn report(name: &str, elapsed: Duration, bytes: u64) {
+    let per_iteration = elapsed / ITERATIONS;
+    let throughput = bytes as f64 / per_iteration.as_secs_f64() / (1024.0 * 1024.0);
+    println!(
+        "{:<24} {:>10.2?}/iter {:>10.1} MiB/s",
+        name, per_iteration, throughput
+    );
+}
+
+fn main() {
+    let (path, files) = write_synthetic_dat();
+    let file_size = std::fs::metadata(&path).unwrap().len() as u32;
+    let total_bytes: u64 = files.iter().map(|(_, size)| *size as u64).sum();
+    println!(
+        "{} files, {} bytes, {} iterations\n",
+        files.len(),
+        total_bytes,
+        ITERATIONS
+    );
+
+    // SyncFileRangeReader
+    let mut reader = SyncFileRangeReader::new(File::open(&path).unwrap());
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for (offset, size) in &files {
+            let mut file_reader =
+                SyncDatFileReader::new(*size as usize, BLOCK_SIZE as usize, file_size).unwrap();
+            black_box(file_reader.read_file(&mut reader, *offset).unwrap());
+        }
+    }
+    report("SyncFileRangeReader", start.elapsed(), total_bytes);
+
+    // FileRangeReader
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let elapsed = runtime.block_on(async {
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let compat_file = tokio_util::compat::TokioAsyncReadCompatExt::compat(file);
+        let mut reader = FileRangeReader::new(compat_file);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            for (offset, size) in &files {
+                let mut file_reader =
+                    DatFileReader::new(*size as usize, BLOCK_SIZE as usize, file_size).unwrap();
+                black_box(file_reader.read_file(&mut reader, *offset).await.unwrap());
+            }
+        }
+        start.elapsed()
+    });
+    report("FileRangeReader", elapsed, total_bytes);
+
+    // MmapRangeReader through the same RangeReaderSync path
+    // SAFETY: The benchmark's DAT isn't changed while it's mapped
+    let mut reader = unsafe { MmapRangeReader::open(&path) }.unwrap();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for (offset, size) in &files {
+            let mut file_reader =
+                SyncDatFileReader::new(*size as usize, BLOCK_SIZE as usize, file_size).unwrap();
+            black_box(file_reader.read_file(&mut reader, *offset).unwrap());
+        }
+    }
+    report("MmapRangeReader (copy)", start.elapsed(), total_bytes);
+
+    // MmapRangeReader borrowing where it can
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for (offset, size) in &files {
+            let mut validator = BlockChainValidator::new(BLOCK_SIZE, file_size);
+            black_box(reader.read_file(*offset, *size, &mut validator).unwrap());
+        }
+    }
+    report("MmapRangeReader (borrow)", start.elapsed(), total_bytes);
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -1,111 +1,1267 @@
-use std::error::Error;
-use std::io::Seek;
+use std::any::Any;
+use std::io::{BufReader, Read, Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
 use std::{
-    fs::{self, File, create_dir},
-    io::{Cursor, SeekFrom},
+    fs::{self, File},
+    io::Cursor,
 };
 
+use libac_rs::dat::diff::{
+    DatDiff, DatDiffChange, DatDiffEntry, content_hash_hex, read_entry_decoded,
+    texture_diff_image,
+};
+use libac_rs::dat::enums::dat_database_type::DatDatabaseType;
+use libac_rs::dat::error::DatError;
+use libac_rs::dat::info::DatInfo;
+use libac_rs::dat::overlay::{DatOverlay, OverlayDatabase, OverlayEntry, OverlayStatus};
+use libac_rs::dat::patch::DatPatch;
+use libac_rs::dat::reader::any_range_reader::{AnyRangeReader, DatUri};
+use libac_rs::dat::reader::caching_reader::CachingRangeReader;
+use libac_rs::dat::reader::dat_file_reader::DatFileReader;
+use libac_rs::dat::reader::dat_verifier::DatVerifier;
+use libac_rs::dat::reader::range_reader::RangeReader;
 use libac_rs::dat::reader::types::dat_database::DatDatabase;
+use libac_rs::dat::reader::types::dat_database_header::DatDatabaseHeader;
 use libac_rs::dat::reader::types::dat_directory_entry::DatDirectoryEntry;
+use libac_rs::dat::reader::types::dat_index::{DatFileStamp, DatIndex};
+use libac_rs::dat::writer::dat_compactor::{
+    CompactionReport, FragmentationStats, compact_database,
+};
+use libac_rs::dat::writer::dat_writer::DatWriter;
 use libac_rs::dat::{
     enums::dat_file_type::DatFileType,
-    file_types::{dat_file::DatFile, texture::Texture},
-    reader::dat_block_reader::DatBlockReader,
+    file_types::{dat_file::DatFile, texture::Texture, wave::Wave},
+    reader::{block_chain_validator::BlockChainValidator, dat_block_reader::DatBlockReader},
 };
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+/// Convert a hex object ID string (with or without a 0x prefix) to a u32
+pub fn parse_object_id(object_id: &str) -> Result<u32, DatError> {
+    let digits = object_id.strip_prefix("0x").unwrap_or(object_id);
+
+    u32::from_str_radix(digits, 16)
+        .map_err(|e| DatError::InvalidData(format!("Invalid object ID {}: {}", object_id, e)))
+}
+
+/// Load the sidecar index next to a DAT, if there's a readable, current one
+fn load_sidecar_index(dat_file_path: &str) -> Option<DatIndex> {
+    DatIndex::load_sidecar(dat_file_path).ok()
+}
+
+/// (Re)write the sidecar index next to a DAT when it's missing or stale
+///
+/// `stamp` is the DAT's, taken before it was read.
+fn update_sidecar_index(
+    dat_file_path: &str,
+    db: &DatDatabase,
+    index: Option<&DatIndex>,
+    stamp: Option<DatFileStamp>,
+) {
+    if index.is_some_and(|index| index.is_valid_for(&db.index_key)) {
+        return;
+    }
+
+    let Some(stamp) = stamp else {
+        return;
+    };
+
+    let result = DatIndex::from_database(db)
+        .and_then(|index| index.save_sidecar(dat_file_path, stamp));
+
+    if let Err(e) = result {
+        eprintln!("Warning: Failed to write index for {}: {}", dat_file_path, e);
+    }
+}
+
+/// Delete the sidecar index next to a DAT that's just been written to, so it
+/// can't be mistaken for a description of the new contents
+fn remove_sidecar_index(dat_file_path: &str) {
+    let index_path = DatIndex::sidecar_path(dat_file_path);
+
+    match fs::remove_file(&index_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Warning: Failed to remove stale index {}: {}", index_path, e),
+    }
+}
+
+/// How commands read the DATs they're given
+#[derive(Clone, Debug, Default)]
+pub struct DatOpenOptions {
+    /// Read and write the sidecar index next to local DATs
+    pub use_index: bool,
+    /// Treat every DAT as this kind instead of detecting it
+    pub database_type: Option<DatDatabaseType>,
+    /// Directory of loose files to read in place of the DAT's own (see
+    /// DatOverlay)
+    pub overlay: Option<String>,
+}
+
+impl DatOpenOptions {
+    fn apply(&self, db: DatDatabase) -> DatDatabase {
+        match &self.database_type {
+            Some(database_type) => db.with_database_type(database_type.clone()),
+            None => db,
+        }
+    }
+
+    /// Scan the overlay directory, if there is one, warning about any files
+    /// in it that can't be used
+    fn scan_overlay(&self) -> Result<Option<DatOverlay>, DatError> {
+        let Some(overlay_dir) = &self.overlay else {
+            return Ok(None);
+        };
+
+        let overlay = DatOverlay::scan(overlay_dir)?;
+
+        for path in &overlay.skipped {
+            eprintln!(
+                "Skipping {}: not named <object ID>.bin or <object ID>.png",
+                path.display()
+            );
+        }
+
+        Ok(Some(overlay))
+    }
+}
+
+/// Read a DAT's directory, going through the sidecar index next to it when
+/// it's a local file
+pub async fn read_database<R: RangeReader>(
+    reader: &mut R,
+    local_path: Option<&str>,
+    options: &DatOpenOptions,
+) -> Result<DatDatabase, DatError> {
+    let Some(dat_file_path) = local_path.filter(|_| options.use_index) else {
+        return Ok(options.apply(DatDatabase::read_async(reader).await?));
+    };
+
+    let stamp = DatFileStamp::of(dat_file_path).ok();
+    let index = load_sidecar_index(dat_file_path);
+    let db = DatDatabase::read_async_with_index(reader, index.as_ref()).await?;
+    update_sidecar_index(dat_file_path, &db, index.as_ref(), stamp);
+
+    Ok(options.apply(db))
+}
+
+/// Synchronous counterpart of read_database
+pub fn read_database_sync<R: Read + Seek>(
+    reader: &mut R,
+    local_path: Option<&str>,
+    options: &DatOpenOptions,
+) -> Result<DatDatabase, DatError> {
+    let Some(dat_file_path) = local_path.filter(|_| options.use_index) else {
+        return Ok(options.apply(DatDatabase::read(reader)?));
+    };
+
+    let stamp = DatFileStamp::of(dat_file_path).ok();
+    let index = load_sidecar_index(dat_file_path);
+    let db = DatDatabase::read_with_index(reader, index.as_ref())?;
+    update_sidecar_index(dat_file_path, &db, index.as_ref(), stamp);
+
+    Ok(options.apply(db))
+}
+
+/// Open a DAT by path or URI and read its directory
+pub async fn open_database(
+    uri: &str,
+    options: &DatOpenOptions,
+) -> Result<(AnyRangeReader, DatDatabase), DatError> {
+    let uri = DatUri::parse(uri)?;
+    let mut reader = AnyRangeReader::open_uri(&uri).await?;
+    let local_path = uri.local_path().and_then(|path| path.to_str());
+    let db = read_database(&mut reader, local_path, options).await?;
+
+    Ok((reader, db))
+}
+
+/// Most DatSource::open asks a remote DAT for in one request
+const DOWNLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Read + Seek, so DatSource can hand out either kind of reader
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A DAT for the commands that read it through Read + Seek rather than a
+/// RangeReader
+///
+/// These commands read every file in the DAT anyway, so remote DATs are
+/// downloaded into memory once up front, DOWNLOAD_CHUNK_SIZE bytes per
+/// request.
+pub enum DatSource {
+    File(String),
+    Memory(Vec<u8>),
+}
+
+impl DatSource {
+    pub async fn open(uri: &str) -> Result<DatSource, DatError> {
+        let uri = DatUri::parse(uri)?;
+
+        if let Some(path) = uri.local_path() {
+            return Ok(DatSource::File(path.to_string_lossy().into_owned()));
+        }
+
+        let mut reader = AnyRangeReader::open_uri(&uri).await?;
+        let header = DatDatabaseHeader::read_async(&mut reader).await?;
+        let file_size = header.file_size as usize;
+        let mut data = Vec::new();
+
+        while data.len() < file_size {
+            let length = DOWNLOAD_CHUNK_SIZE.min(file_size - data.len());
+            let chunk = reader.read_range(data.len() as u32, length).await?;
 
+            if chunk.len() != length {
+                return Err(DatError::InvalidData(format!(
+                    "Expected {} bytes at offset {} but got {}",
+                    length,
+                    data.len(),
+                    chunk.len()
+                )));
+            }
+
+            data.extend_from_slice(&chunk);
+            eprint!("\rDownloaded {}/{} bytes", data.len(), file_size);
+        }
+
+        if file_size > 0 {
+            eprintln!();
+        }
+
+        Ok(DatSource::Memory(data))
+    }
+
+    pub fn local_path(&self) -> Option<&str> {
+        match self {
+            DatSource::File(path) => Some(path),
+            DatSource::Memory(_) => None,
+        }
+    }
+
+    /// Open a new, independent reader on the DAT
+    pub fn reader(&self) -> Result<Box<dyn ReadSeek + '_>, DatError> {
+        match self {
+            DatSource::File(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+            DatSource::Memory(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+        }
+    }
+}
 
 pub async fn find_file_by_id(
     db: &DatDatabase,
     object_id: &str,
-) -> Result<DatDirectoryEntry, Box<dyn Error>> {
-    // TODO: Factor out into testable helper
-    // Convert hex string to u32
-    let parsed_id = if object_id.starts_with("0x") {
-        u32::from_str_radix(&object_id[2..], 16)?
+) -> Result<DatDirectoryEntry, DatError> {
+    let parsed_id = parse_object_id(object_id)?;
+
+    db.find_file(parsed_id)
+        .copied()
+        .ok_or_else(|| DatError::NotFound(format!("Object ID {} not found in DAT file", object_id)))
+}
+
+/// How a command prints its results
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for reading in a terminal
+    #[default]
+    Table,
+    Json,
+    /// CSV with a header row
+    Csv,
+}
+
+/// Print a value as pretty-printed JSON
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<(), DatError> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value).map_err(std::io::Error::from)?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+/// Print records as CSV, with a header row taken from their field names
+fn print_csv<T: Serialize>(records: impl IntoIterator<Item = T>) -> Result<(), DatError> {
+    let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+
+    for record in records {
+        writer.serialize(record).map_err(std::io::Error::from)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A DatDirectoryEntry along with its type, as `dat list` prints it
+#[derive(Serialize)]
+struct ListedFile {
+    object_id: u32,
+    file_type: DatFileType,
+    file_offset: u32,
+    file_size: u32,
+    bit_flags: u32,
+    date: u32,
+    iteration: u32,
+    /// Only listed when there's an overlay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overlay: Option<OverlayStatus>,
+}
+
+impl ListedFile {
+    fn new(entry: &DatDirectoryEntry, file_type: DatFileType) -> Self {
+        Self {
+            object_id: entry.object_id,
+            file_type,
+            file_offset: entry.file_offset,
+            file_size: entry.file_size,
+            bit_flags: entry.bit_flags,
+            date: entry.date,
+            iteration: entry.iteration,
+            overlay: None,
+        }
+    }
+
+    /// An entry in an overlay's merged listing, which has zeroes for the DAT
+    /// entry's fields when it's only in the overlay
+    fn from_overlay(file: &OverlayEntry) -> Self {
+        let entry = file.entry.unwrap_or(DatDirectoryEntry {
+            bit_flags: 0,
+            object_id: file.object_id,
+            file_offset: 0,
+            file_size: 0,
+            date: 0,
+            iteration: 0,
+        });
+
+        Self {
+            overlay: Some(file.status()),
+            ..Self::new(&entry, file.file_type.clone())
+        }
+    }
+}
+
+/// Print the files in a DAT, or just how many there are
+pub async fn list_dat(
+    uri: &str,
+    count: bool,
+    file_type: Option<&str>,
+    format: OutputFormat,
+    options: &DatOpenOptions,
+) -> Result<(), DatError> {
+    let filter_type = file_type.map(parse_file_type).transpose()?;
+    let (reader, dat) = open_database(uri, options).await?;
+
+    if let Some(overlay) = options.scan_overlay()? {
+        let db = OverlayDatabase::new(reader, dat, overlay);
+        return list_overlay(&db, count, filter_type, format);
+    }
+
+    let mut files = dat.list_files(true)?;
+
+    // Filter by type if specified
+    if let Some(filter_type) = filter_type {
+        files.retain(|file| dat.file_type(file) == filter_type);
+    }
+
+    let listed = |file: &DatDirectoryEntry| ListedFile::new(file, dat.file_type(file));
+
+    if count {
+        println!("{}", files.len());
+    } else if format == OutputFormat::Json {
+        let files: Vec<ListedFile> = files.iter().map(listed).collect();
+        print_json(&files)?;
+    } else if format == OutputFormat::Csv {
+        print_csv(files.iter().map(listed))?;
     } else {
-        u32::from_str_radix(object_id, 16)?
-    };
+        println!(
+            "{:<10} {:<10} {:<10} {:<10}",
+            "ID", "OFFSET", "SIZE", "TYPE"
+        );
+        for file in files {
+            println!(
+                "{:08X} {:<10} {:<10} {:<10}",
+                file.object_id,
+                file.file_offset,
+                file.file_size,
+                dat.file_type(&file)
+            );
+        }
+    }
+
+    Ok(())
+}
 
-    println!("parsed_id: {}", parsed_id);
-    let files = db.list_files(true)?;
-    let target_file = files.iter().find(|file| file.object_id == parsed_id);
+/// list_dat for a DAT with an overlay, which also shows where each file
+/// comes from
+fn list_overlay<R: RangeReader>(
+    db: &OverlayDatabase<R>,
+    count: bool,
+    filter_type: Option<DatFileType>,
+    format: OutputFormat,
+) -> Result<(), DatError> {
+    let mut files = db.list_files()?;
+
+    if let Some(filter_type) = filter_type {
+        files.retain(|file| file.file_type == filter_type);
+    }
+
+    if count {
+        println!("{}", files.len());
+    } else if format == OutputFormat::Json {
+        let files: Vec<ListedFile> = files.iter().map(ListedFile::from_overlay).collect();
+        print_json(&files)?;
+    } else if format == OutputFormat::Csv {
+        print_csv(files.iter().map(ListedFile::from_overlay))?;
+    } else {
+        println!(
+            "{:<10} {:<10} {:<10} {:<10} {:<10}",
+            "ID", "OFFSET", "SIZE", "TYPE", "SOURCE"
+        );
+        for file in files {
+            let (offset, size) = match &file.entry {
+                Some(entry) => (entry.file_offset.to_string(), entry.file_size.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let source = match &file.overlay_file {
+                Some(overlay_file) => format!("{} ({})", file.status(), overlay_file.path().display()),
+                None => file.status().to_string(),
+            };
 
-    match target_file {
-        Some(file) => Ok(*file),
-        None => {
-            return Err(format!("Object ID {} not found in DAT file", object_id).into());
+            println!(
+                "{:08X} {:<10} {:<10} {:<10} {}",
+                file.object_id, offset, size, file.file_type, source
+            );
         }
     }
+
+    Ok(())
 }
 
-pub async fn extract_texture_by_id(
-    dat_file_path: &str,
+/// Extract a single object into `output_dir`, decoded as export_object does
+pub async fn extract_object(
+    uri: &str,
     object_id: &str,
     output_dir: &str,
-) -> Result<(), Box<dyn Error>> {
-    // Convert hex string to u32
-    let parsed_id = if object_id.starts_with("0x") {
-        u32::from_str_radix(&object_id[2..], 16)?
+    options: &DatOpenOptions,
+) -> Result<(), DatError> {
+    let (mut range_reader, dat) = open_database(uri, options).await?;
+
+    if let Some(overlay) = options.scan_overlay()? {
+        let mut db = OverlayDatabase::new(range_reader, dat, overlay);
+        let parsed_id = parse_object_id(object_id)?;
+        let found_file = db.find_file(parsed_id).ok_or_else(|| {
+            DatError::NotFound(format!(
+                "Object ID {} not found in DAT file or overlay",
+                object_id
+            ))
+        })?;
+        let buf = db.read_entry(&found_file).await?;
+
+        fs::create_dir_all(output_dir)?;
+        let output_path = export_object(
+            &found_file.file_type,
+            buf,
+            &format!("{}/{}", output_dir.trim_end_matches('/'), object_id),
+        )?;
+        println!(
+            "Saved {:08X} ({}) to {}",
+            parsed_id,
+            found_file.status(),
+            output_path
+        );
+
+        return Ok(());
+    }
+
+    let found_file = find_file_by_id(&dat, object_id).await?;
+
+    let mut reader = DatFileReader::from_entry(&dat.header, &found_file)?;
+    let buf = reader
+        .read_file(&mut range_reader, found_file.file_offset)
+        .await?;
+
+    fs::create_dir_all(output_dir)?;
+    let output_path = export_object(
+        &dat.file_type(&found_file),
+        buf,
+        &format!("{}/{}", output_dir.trim_end_matches('/'), object_id),
+    )?;
+    println!("Saved {:08X} to {}", found_file.object_id, output_path);
+
+    Ok(())
+}
+
+/// Parse a decimal number, or a hex one with a 0x prefix
+pub fn parse_number(value: &str) -> Result<u32, DatError> {
+    let result = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => value.parse(),
+    };
+
+    result.map_err(|e| DatError::InvalidData(format!("Invalid number {}: {}", value, e)))
+}
+
+/// Print data as a hex dump, 16 bytes to a line, labelling each line with
+/// its offset counted from `base_offset`
+fn print_hex_dump(data: &[u8], base_offset: u32) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        println!(
+            "{:08X}  {:<47}  |{}|",
+            base_offset as usize + i * 16,
+            hex.join(" "),
+            ascii
+        );
+    }
+}
+
+/// Dump `size` bytes of a DAT, either straight from `offset` or from the
+/// block chain starting there, as a hex dump or raw binary on stdout
+///
+/// A block chain's blocks are printed to stderr, and its hex dump is labelled
+/// with offsets into the file the chain holds rather than into the DAT.
+pub async fn read_raw(
+    uri: &str,
+    offset: u32,
+    size: u32,
+    chain: bool,
+    binary: bool,
+) -> Result<(), DatError> {
+    let mut reader = AnyRangeReader::open(uri).await?;
+
+    let (data, base_offset) = if chain {
+        let header = DatDatabaseHeader::read_async(&mut reader).await?;
+        let mut validator = BlockChainValidator::new(header.block_size, header.file_size);
+        let data = DatBlockReader::read_async(&mut reader, offset, size, &mut validator).await?;
+
+        let blocks: Vec<String> = validator
+            .blocks()
+            .iter()
+            .map(|block| block.to_string())
+            .collect();
+        eprintln!("Blocks: {}", blocks.join(" -> "));
+
+        (data, 0)
+    } else {
+        (reader.read_range(offset, size as usize).await?, offset)
+    };
+
+    if binary {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&data)?;
+        stdout.flush()?;
+    } else {
+        print_hex_dump(&data, base_offset);
+    }
+
+    Ok(())
+}
+
+/// One line of `dat info` output
+#[derive(Serialize)]
+struct InfoField {
+    field: &'static str,
+    value: String,
+}
+
+/// Print what a DAT's header says about it, along with anything in it that
+/// doesn't look right
+///
+/// Returns whether the header looked valid so the caller can set the exit
+/// code.
+pub async fn info_dat(
+    uri: &str,
+    format: OutputFormat,
+    options: &DatOpenOptions,
+) -> Result<bool, DatError> {
+    let uri = DatUri::parse(uri)?;
+    let mut reader = AnyRangeReader::open_uri(&uri).await?;
+    let header = DatDatabaseHeader::read_async(&mut reader).await?;
+
+    // The directory can only be found through a block size that makes sense
+    let local_path = uri.local_path().and_then(|path| path.to_str());
+    let db = if header.block_size > 4 {
+        Some(read_database(&mut reader, local_path, options).await)
     } else {
-        u32::from_str_radix(object_id, 16)?
+        None
+    };
+
+    let info = match db {
+        Some(Ok(db)) => DatInfo::read(&mut reader, &db).await?,
+        Some(Err(e)) => {
+            let mut info = DatInfo::from_header(&header);
+            info.problems.push(format!("Couldn't read the directory: {}", e));
+            info
+        }
+        None => DatInfo::from_header(&header),
     };
 
-    // Read the database to find the file entry
-    let mut db_file = File::open(dat_file_path)?;
-    db_file.seek(SeekFrom::Start(0))?;
-    let db = DatDatabase::read(&mut db_file)?;
-    let files = db.list_files(true)?;
+    if format == OutputFormat::Json {
+        print_json(&info)?;
+        return Ok(info.is_valid());
+    }
 
-    // Find the file with matching object ID
-    let target_file = files.iter().find(|file| file.object_id == parsed_id);
+    let header = &info.header;
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut fields = vec![
+        (
+            "Type",
+            format!(
+                "{} (data set {})",
+                optional(info.database_type.as_ref().map(|t| t.to_string())),
+                header.data_set
+            ),
+        ),
+        ("Data subset", header.data_subset.to_string()),
+        ("Magic", format!("0x{:08X}", header.file_type)),
+        ("Block size", header.block_size.to_string()),
+        ("File size", header.file_size.to_string()),
+        ("Files", optional(info.files.map(|files| files.to_string()))),
+        (
+            "Iteration",
+            optional(info.iteration.map(|iteration| iteration.to_string())),
+        ),
+        (
+            "Release",
+            optional(info.release.as_ref().map(|release| release.name.to_string())),
+        ),
+        ("Version", info.version_major.to_string()),
+        ("Version minor", header.version_minor.to_string()),
+        ("Engine pack version", header.engine_pack_version.to_string()),
+        ("Game pack version", header.game_pack_version.to_string()),
+        ("Master map ID", format!("{:08X}", header.master_map_id)),
+        ("Directory root", header.btree.to_string()),
+        (
+            "Free blocks",
+            format!(
+                "{} (head {}, tail {})",
+                header.free_count, header.free_head, header.free_tail
+            ),
+        ),
+        (
+            "LRU",
+            format!(
+                "new {}, old {}, {}",
+                header.new_lru,
+                header.old_lru,
+                if header.use_lru { "in use" } else { "not in use" }
+            ),
+        ),
+    ];
+    fields.extend(info.problems.iter().map(|problem| ("Problem", problem.clone())));
 
-    let target_file = match target_file {
-        Some(file) => file,
-        None => {
-            eprintln!("Object ID {} not found in DAT file", object_id);
-            return Ok(());
+    match format {
+        OutputFormat::Csv => print_csv(
+            fields
+                .into_iter()
+                .map(|(field, value)| InfoField { field, value }),
+        )?,
+        _ => {
+            for (field, value) in fields {
+                println!("{:<20} {}", format!("{}:", field), value);
+            }
         }
+    }
+
+    Ok(info.is_valid())
+}
+
+/// Verify a DAT, printing one tab-separated line per issue found
+///
+/// The directory is always walked in the DAT itself. When indexes are in use
+/// the sidecar index of a local DAT that passes is checked against it too,
+/// and removed if it doesn't match.
+///
+/// Returns whether the DAT passed so the caller can set the exit code.
+pub async fn verify_dat(
+    uri: &str,
+    format: OutputFormat,
+    options: &DatOpenOptions,
+) -> Result<bool, DatError> {
+    let uri = DatUri::parse(uri)?;
+    let mut reader = CachingRangeReader::with_defaults(AnyRangeReader::open_uri(&uri).await?);
+    let report = DatVerifier::verify(&mut reader).await?;
+    let local_path = uri.local_path().and_then(|path| path.to_str());
+
+    if let Some(dat_file_path) = local_path.filter(|_| options.use_index && report.is_ok()) {
+        check_sidecar_index(&mut reader, dat_file_path).await?;
+    }
+
+    match format {
+        OutputFormat::Json => print_json(&report)?,
+        OutputFormat::Csv => print_csv(&report.issues)?,
+        OutputFormat::Table => {
+            println!("KIND\tOFFSET\tOBJECT_ID\tDETAIL");
+
+            for issue in &report.issues {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    issue.kind,
+                    issue.offset,
+                    issue
+                        .object_id
+                        .map_or(String::new(), |object_id| format!("{:08X}", object_id)),
+                    issue.detail
+                );
+            }
+        }
+    }
+
+    eprintln!(
+        "{}: {} files, {} directories, {} used blocks, {} free blocks, {} issues",
+        if report.is_ok() { "OK" } else { "FAILED" },
+        report.files,
+        report.directories,
+        report.used_blocks,
+        report.free_blocks,
+        report.issues.len()
+    );
+
+    Ok(report.is_ok())
+}
+
+/// Compare the sidecar index next to a DAT, if it has a current one, with
+/// the DAT's actual directory, removing the index if they differ
+async fn check_sidecar_index<R: RangeReader>(
+    reader: &mut R,
+    dat_file_path: &str,
+) -> Result<(), DatError> {
+    let Some(index) = load_sidecar_index(dat_file_path) else {
+        return Ok(());
+    };
+
+    let db = DatDatabase::read_async(reader).await?;
+    let key = |entry: &DatDirectoryEntry| {
+        (
+            entry.object_id,
+            entry.bit_flags,
+            entry.file_offset,
+            entry.file_size,
+            entry.date,
+            entry.iteration,
+        )
     };
+    let entries = db.list_files(true)?;
+    let matches = index.is_valid_for(&db.index_key)
+        && index.entries.iter().map(key).eq(entries.iter().map(key));
 
-    // Check if it's a texture
-    if target_file.file_type() != DatFileType::Texture {
+    if !matches {
         eprintln!(
-            "Object ID {} is not a texture (type: {:?})",
-            object_id,
-            target_file.file_type()
+            "Warning: Index {} doesn't match the DAT's directory, removing it",
+            DatIndex::sidecar_path(dat_file_path)
         );
-        return Ok(());
+        remove_sidecar_index(dat_file_path);
+    }
+
+    Ok(())
+}
+
+/// Build a new DAT from a directory of files named by hex object ID (e.g.
+/// `06000001` or `06000001.bin`), returning how many files were packed
+pub fn pack_directory(
+    input_dir: &str,
+    output_path: &str,
+    database_type: &str,
+    block_size: u32,
+) -> Result<usize, DatError> {
+    let database_type = parse_database_type(database_type)?;
+
+    let mut files = Vec::new();
+
+    for dir_entry in fs::read_dir(input_dir)? {
+        let path = dir_entry?.path();
+
+        if !path.is_file() {
+            continue;
+        }
+
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+
+        match parse_object_id(stem) {
+            Ok(object_id) => files.push((object_id, path)),
+            Err(_) => eprintln!("Skipping {}: not named by object ID", path.display()),
+        }
     }
 
-    // Set up export directory
-    if !fs::exists(output_dir)? {
-        create_dir(output_dir)?;
+    // Inserting in order keeps the B-tree's nodes evenly filled
+    files.sort();
+
+    if let Some(pair) = files.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(DatError::InvalidData(format!(
+            "{} and {} both have object ID {:08X}",
+            pair[0].1.display(),
+            pair[1].1.display(),
+            pair[0].0
+        )));
     }
 
-    // Read the texture data
-    let dat_file_buffer = DatBlockReader::read(
-        &mut db_file,
-        target_file.file_offset,
-        target_file.file_size,
-        db.header.block_size,
-    )?;
-    let mut reader = Cursor::new(dat_file_buffer);
+    let output = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)?;
+    let mut writer = DatWriter::create(output, database_type, block_size)?;
+
+    for (object_id, path) in &files {
+        writer.write_file(*object_id, &fs::read(path)?)?;
+    }
+
+    writer.finish()?;
+    remove_sidecar_index(output_path);
+
+    Ok(files.len())
+}
+
+/// Rewrite a DAT contiguously into a new file, printing fragmentation
+/// statistics from before and after
+pub async fn compact_dat(
+    uri: &str,
+    output_path: &str,
+    options: &DatOpenOptions,
+) -> Result<CompactionReport, DatError> {
+    let source = DatSource::open(uri).await?;
+
+    if source
+        .local_path()
+        .is_some_and(|path| fs::canonicalize(path).ok() == fs::canonicalize(output_path).ok())
+    {
+        return Err(DatError::InvalidData(
+            "Compacting a DAT into itself isn't supported".to_string(),
+        ));
+    }
+
+    let mut reader = source.reader()?;
+    let db = read_database_sync(&mut reader, source.local_path(), options)?;
+    let mut output = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)?;
+    let report = compact_database(&db, &mut reader, &mut output)?;
+    remove_sidecar_index(output_path);
+
+    println!("{:<18} {:>12} {:>12}", "", "BEFORE", "AFTER");
+
+    let row = |label: &str, value: fn(&FragmentationStats) -> String| {
+        println!(
+            "{:<18} {:>12} {:>12}",
+            label,
+            value(&report.before),
+            value(&report.after)
+        );
+    };
+
+    row("File size", |stats| stats.file_size.to_string());
+    row("Files", |stats| stats.files.to_string());
+    row("Fragmented files", |stats| stats.fragmented_files.to_string());
+    row("File blocks", |stats| stats.file_blocks.to_string());
+    row("Discontinuities", |stats| stats.discontinuities.to_string());
+    row("Free blocks", |stats| stats.free_blocks.to_string());
+    row("Fragmentation", |stats| {
+        format!("{:.1}%", stats.fragmentation() * 100.0)
+    });
+
+    Ok(report)
+}
+
+fn write_texture_diff_image(
+    old_data: Vec<u8>,
+    new_data: Vec<u8>,
+    output_path: &str,
+) -> Result<(), DatError> {
+    let old_texture = DatFile::<Texture>::read(&mut Cursor::new(old_data))?.inner;
+    let new_texture = DatFile::<Texture>::read(&mut Cursor::new(new_data))?.inner;
+    texture_diff_image(&old_texture, &new_texture)?.save(output_path)?;
+
+    Ok(())
+}
+
+/// A DatDiffEntry flattened into one CSV row
+#[derive(Serialize)]
+struct DiffRow {
+    change: DatDiffChange,
+    object_id: u32,
+    file_type: DatFileType,
+    old_size: Option<u32>,
+    new_size: Option<u32>,
+    old_iteration: Option<u32>,
+    new_iteration: Option<u32>,
+    old_hash: Option<String>,
+    new_hash: Option<String>,
+    /// The changed fields, comma separated
+    changes: String,
+}
+
+impl From<&DatDiffEntry> for DiffRow {
+    fn from(entry: &DatDiffEntry) -> Self {
+        Self {
+            change: entry.change,
+            object_id: entry.object_id,
+            file_type: entry.file_type.clone(),
+            old_size: entry.old.map(|e| e.file_size),
+            new_size: entry.new.map(|e| e.file_size),
+            old_iteration: entry.old.map(|e| e.iteration),
+            new_iteration: entry.new.map(|e| e.iteration),
+            old_hash: entry.old_hash.as_ref().map(content_hash_hex),
+            new_hash: entry.new_hash.as_ref().map(content_hash_hex),
+            changes: entry.changes.to_string(),
+        }
+    }
+}
+
+fn print_diff_table(diff: &DatDiff) {
+    let size = |entry: Option<DatDirectoryEntry>| entry.map_or("-".to_string(), |e| e.file_size.to_string());
+    let iteration =
+        |entry: Option<DatDirectoryEntry>| entry.map_or("-".to_string(), |e| e.iteration.to_string());
+
+    for (file_type, entries) in diff.by_type() {
+        println!("{} ({} changed)", file_type, entries.len());
+        println!(
+            "  {:<10} {:<10} {:>10} {:>10} {:>10} {:>10}  FIELDS",
+            "CHANGE", "ID", "OLD SIZE", "NEW SIZE", "OLD ITER", "NEW ITER"
+        );
+
+        for entry in entries {
+            println!(
+                "  {:<10} {:08X}   {:>10} {:>10} {:>10} {:>10}  {}",
+                entry.change,
+                entry.object_id,
+                size(entry.old),
+                size(entry.new),
+                iteration(entry.old),
+                iteration(entry.new),
+                entry.changes
+            );
+        }
+    }
+
+    println!(
+        "{} added, {} removed, {} modified",
+        diff.entries.iter().filter(|e| e.change == DatDiffChange::Added).count(),
+        diff.entries.iter().filter(|e| e.change == DatDiffChange::Removed).count(),
+        diff.entries.iter().filter(|e| e.change == DatDiffChange::Modified).count()
+    );
+}
+
+/// Compare two DATs, printing what changed (grouped by file type, for the
+/// table format) and optionally writing an image of every modified texture
+/// to `images_dir`
+///
+/// With `ignore_date`, objects whose only change is their date are left out.
+pub async fn diff_dats(
+    old_uri: &str,
+    new_uri: &str,
+    images_dir: Option<&str>,
+    format: OutputFormat,
+    ignore_date: bool,
+    options: &DatOpenOptions,
+) -> Result<DatDiff, DatError> {
+    let old_source = DatSource::open(old_uri).await?;
+    let new_source = DatSource::open(new_uri).await?;
+    let mut old_file = old_source.reader()?;
+    let mut new_file = new_source.reader()?;
+    let old_db = read_database_sync(&mut old_file, old_source.local_path(), options)?;
+    let new_db = read_database_sync(&mut new_file, new_source.local_path(), options)?;
+    let mut diff = DatDiff::compare_databases(&old_db, &mut old_file, &new_db, &mut new_file)?;
+
+    if ignore_date {
+        diff = diff.ignoring_dates();
+    }
 
-    let dat_file: DatFile<Texture> = DatFile::read(&mut reader)?;
-    let texture = dat_file.inner;
+    match format {
+        OutputFormat::Json => print_json(&diff)?,
+        OutputFormat::Csv => print_csv(diff.entries.iter().map(DiffRow::from))?,
+        OutputFormat::Table => print_diff_table(&diff),
+    }
+
+    let Some(images_dir) = images_dir else {
+        return Ok(diff);
+    };
 
-    // Export to PNG
-    let output_path = format!("{}/{}.png", output_dir, object_id);
-    texture.to_png(&output_path, 1)?;
+    fs::create_dir_all(images_dir)?;
+
+    for entry in &diff.entries {
+        let (Some(old_entry), Some(new_entry)) = (entry.old, entry.new) else {
+            continue;
+        };
+
+        if entry.file_type != DatFileType::Texture || !entry.changes.content {
+            continue;
+        }
+
+        let old_data = read_entry_decoded(&mut old_file, &old_db.header, &old_entry)?;
+        let new_data = read_entry_decoded(&mut new_file, &new_db.header, &new_entry)?;
+        let output_path = format!("{}/{:08X}.png", images_dir, entry.object_id);
+
+        if let Err(e) = write_texture_diff_image(old_data, new_data, &output_path) {
+            eprintln!(
+                "Warning: Couldn't write diff image for {:08X}: {}",
+                entry.object_id, e
+            );
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Write the patch that turns one DAT into another
+pub async fn create_patch(
+    old_uri: &str,
+    new_uri: &str,
+    output_path: &str,
+    options: &DatOpenOptions,
+) -> Result<(), DatError> {
+    let old_source = DatSource::open(old_uri).await?;
+    let new_source = DatSource::open(new_uri).await?;
+    let mut old_file = old_source.reader()?;
+    let mut new_file = new_source.reader()?;
+    let old_db = read_database_sync(&mut old_file, old_source.local_path(), options)?;
+    let new_db = read_database_sync(&mut new_file, new_source.local_path(), options)?;
+    let patch = DatPatch::create_from_databases(&old_db, &mut old_file, &new_db, &mut new_file)?;
+    patch.save(output_path)?;
 
     println!(
-        "Extracted texture {} ({}x{}) to {}",
-        object_id, texture.width, texture.height, output_path
+        "Wrote {} changes to {} ({} bytes)",
+        patch.ops.len(),
+        output_path,
+        fs::metadata(output_path)?.len()
     );
+
+    Ok(())
+}
+
+/// Apply a patch file to a DAT in place
+pub fn apply_patch(dat_file_path: &str, patch_path: &str) -> Result<(), DatError> {
+    let patch = DatPatch::load(patch_path)?;
+    let target = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dat_file_path)?;
+    let summary = patch.apply(target);
+
+    // Even a failed apply may have written to the DAT
+    remove_sidecar_index(dat_file_path);
+    let summary = summary?;
+
     println!(
-        "File info: offset={}, size={}",
-        target_file.file_offset, target_file.file_size
+        "Patched {}: {} added, {} replaced, {} deleted",
+        dat_file_path, summary.added, summary.replaced, summary.deleted
     );
 
     Ok(())
 }
+
+/// Decode a file and write it to `{output_stem}.{ext}`: PNG for textures,
+/// WAV (or MP3) for waves and the raw bytes as .bin for everything else
+///
+/// Returns the path written to.
+pub fn export_object(
+    file_type: &DatFileType,
+    data: Vec<u8>,
+    output_stem: &str,
+) -> Result<String, DatError> {
+    let mut reader = Cursor::new(data);
+
+    match file_type {
+        DatFileType::Texture => {
+            let texture = DatFile::<Texture>::read(&mut reader)?.inner;
+            let output_path = format!("{}.png", output_stem);
+            texture.to_png(&output_path, 1)?;
+
+            Ok(output_path)
+        }
+        DatFileType::Wave => {
+            let wave = DatFile::<Wave>::read(&mut reader)?.inner;
+            let output_path = format!("{}.{}", output_stem, wave.extension());
+            wave.to_file(&output_path)?;
+
+            Ok(output_path)
+        }
+        _ => {
+            let output_path = format!("{}.bin", output_stem);
+            fs::write(&output_path, reader.into_inner())?;
+
+            Ok(output_path)
+        }
+    }
+}
+
+/// Parse a DatDatabaseType name as given on the command line
+pub fn parse_database_type(database_type: &str) -> Result<DatDatabaseType, DatError> {
+    database_type.parse().map_err(|_| {
+        let valid: Vec<String> = DatDatabaseType::iter().map(|t| t.to_string()).collect();
+
+        DatError::InvalidData(format!(
+            "Invalid database type: {}. Valid types are: {}",
+            database_type,
+            valid.join(", ")
+        ))
+    })
+}
+
+/// Parse a DatFileType name as given on the command line
+pub fn parse_file_type(file_type: &str) -> Result<DatFileType, DatError> {
+    file_type.parse().map_err(|_| {
+        let valid: Vec<String> = DatFileType::iter().map(|t| t.to_string()).collect();
+
+        DatError::InvalidData(format!(
+            "Invalid file type: {}. Valid types are: {}",
+            file_type,
+            valid.join(", ")
+        ))
+    })
+}
+
+/// Objects that couldn't be extracted by extract_all, with why
+pub type ExtractFailures = Vec<(u32, DatError)>;
+
+/// Extract every object (or every object of one type) in a DAT into
+/// `{output_dir}/{Type}/{ID}.{ext}`, returning how many were extracted and
+/// which ones failed
+///
+/// Objects are extracted in parallel, one thread per CPU, each with its own
+/// reader on the DAT. Failures don't stop the rest from being extracted and
+/// are returned at the end.
+pub fn extract_all(
+    source: &DatSource,
+    output_dir: &str,
+    file_type: Option<DatFileType>,
+    options: &DatOpenOptions,
+) -> Result<(usize, ExtractFailures), DatError> {
+    let db = read_database_sync(&mut source.reader()?, source.local_path(), options)?;
+    let mut files = db.list_files(true)?;
+
+    if let Some(file_type) = &file_type {
+        files.retain(|file| db.file_type(file) == *file_type);
+    }
+
+    for file_type in DatFileType::iter() {
+        if files.iter().any(|file| db.file_type(file) == file_type) {
+            fs::create_dir_all(format!("{}/{}", output_dir, file_type))?;
+        }
+    }
+
+    let total = files.len();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    let progress_step = (total / 100).max(1);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    // A worker that can't open the DAT fails the whole extract, rather than
+    // leaving its share of the files to the others unnoticed
+    let opened = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(total))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), DatError> {
+                    let mut reader = source.reader()?;
+
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+
+                        let Some(entry) = files.get(i) else {
+                            break;
+                        };
+
+                        let file_type = db.file_type(entry);
+                        let output_stem =
+                            format!("{}/{}/{:08X}", output_dir, file_type, entry.object_id);
+                        // A panic on one object (e.g. a decoder bug) is that
+                        // object's failure, not the end of the extract
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            read_entry_decoded(&mut reader, &db.header, entry)
+                                .and_then(|data| export_object(&file_type, data, &output_stem))
+                        }))
+                        .unwrap_or_else(|payload| Err(panicked(payload)));
+
+                        if let Err(e) = result {
+                            failures.lock().unwrap().push((entry.object_id, e));
+                        }
+
+                        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+
+                        if done.is_multiple_of(progress_step) || done == total {
+                            eprint!("\rExtracted {}/{}", done, total);
+                        }
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect();
+
+        workers.into_iter().try_for_each(|worker| {
+            worker
+                .join()
+                .unwrap_or_else(|payload| Err(panicked(payload)))
+        })
+    });
+
+    if total > 0 {
+        eprintln!();
+    }
+
+    opened?;
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|(object_id, _)| *object_id);
+
+    Ok((done.into_inner() - failures.len(), failures))
+}
+
+/// Turn a caught panic into an error, keeping its message
+fn panicked(payload: Box<dyn Any + Send>) -> DatError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+
+    DatError::InvalidData(format!("Panicked: {}", message))
+}
+
+/// Run extract_all and report how it went, returning whether every object
+/// was extracted
+pub async fn extract_all_command(
+    uri: &str,
+    output_dir: &str,
+    file_type: Option<&str>,
+    options: &DatOpenOptions,
+) -> Result<bool, DatError> {
+    let file_type = file_type.map(parse_file_type).transpose()?;
+    let source = DatSource::open(uri).await?;
+    let (extracted, failures) = extract_all(&source, output_dir, file_type, options)?;
+
+    for (object_id, e) in &failures {
+        eprintln!("Failed to extract {:08X}: {}", object_id, e);
+    }
+
+    println!(
+        "Extracted {} objects to {} ({} failed)",
+        extracted,
+        output_dir,
+        failures.len()
+    );
+
+    Ok(failures.is_empty())
+}
@@ -0,0 +1,505 @@
+use strum::{Display, EnumIter, IntoEnumIterator};
+
+use crate::dat::{
+    enums::{dat_database_type::DatDatabaseType, dat_file_type::DatFileType},
+    error::DatError,
+    reader::{
+        dat_file_reader::DatFileReader,
+        range_reader::RangeReader,
+        types::{dat_database::DatDatabase, dat_directory_entry::DatDirectoryEntry},
+    },
+};
+
+#[cfg(feature = "core")]
+use crate::dat::reader::any_range_reader::{AnyRangeReader, DatUri};
+
+/// The DATs a client install is made up of
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, EnumIter)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DatCollectionSlot {
+    Portal,
+    Cell,
+    /// Higher resolution versions of some portal textures
+    HighRes,
+    Language,
+}
+
+impl DatCollectionSlot {
+    /// The name the DAT has in a client install
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            DatCollectionSlot::Portal => "client_portal.dat",
+            DatCollectionSlot::Cell => "client_cell_1.dat",
+            DatCollectionSlot::HighRes => "client_highres.dat",
+            DatCollectionSlot::Language => "client_local_English.dat",
+        }
+    }
+
+    /// The kind of database the DAT in this slot is (highres is a portal-type
+    /// DAT)
+    pub fn database_type(&self) -> DatDatabaseType {
+        match self {
+            DatCollectionSlot::Portal | DatCollectionSlot::HighRes => DatDatabaseType::Portal,
+            DatCollectionSlot::Cell => DatDatabaseType::Cell,
+            DatCollectionSlot::Language => DatDatabaseType::Language,
+        }
+    }
+}
+
+/// A DAT in a DatCollection, along with the reader it's read through
+pub struct CollectionDat<R: RangeReader> {
+    pub reader: R,
+    pub database: DatDatabase,
+}
+
+/// A file found in a DatCollection, and which DAT it was found in
+#[derive(Clone, Debug)]
+pub struct CollectionEntry {
+    pub slot: DatCollectionSlot,
+    pub entry: DatDirectoryEntry,
+    pub file_type: DatFileType,
+}
+
+/// The portal, cell, highres and language DATs of a client, looked up as one
+///
+/// Objects in one DAT refer to objects in the others (e.g. a LandblockInfo in
+/// the cell DAT refers to Setups in the portal DAT), so this routes each
+/// object to the DAT that holds it, given the kind of DAT the reference is
+/// to. Any of the DATs can be left out, and each can be read through any
+/// RangeReader backend.
+pub struct DatCollection<R: RangeReader> {
+    pub portal: Option<CollectionDat<R>>,
+    pub cell: Option<CollectionDat<R>>,
+    pub highres: Option<CollectionDat<R>>,
+    pub language: Option<CollectionDat<R>>,
+}
+
+impl<R: RangeReader> Default for DatCollection<R> {
+    fn default() -> Self {
+        Self {
+            portal: None,
+            cell: None,
+            highres: None,
+            language: None,
+        }
+    }
+}
+
+impl<R: RangeReader> DatCollection<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a DAT's database through the given reader and put it in a slot,
+    /// replacing whatever was there
+    ///
+    /// The database is treated as the slot's type whatever its header says,
+    /// since e.g. the highres DAT can't be told apart from a portal DAT.
+    pub async fn open(&mut self, slot: DatCollectionSlot, mut reader: R) -> Result<(), DatError> {
+        let database = DatDatabase::read_async(&mut reader)
+            .await?
+            .with_database_type(slot.database_type());
+
+        self.insert(slot, reader, database);
+
+        Ok(())
+    }
+
+    /// Put an already read database in a slot, replacing whatever was there
+    pub fn insert(&mut self, slot: DatCollectionSlot, reader: R, database: DatDatabase) {
+        *self.slot_mut(slot) = Some(CollectionDat { reader, database });
+    }
+
+    pub fn get(&self, slot: DatCollectionSlot) -> Option<&CollectionDat<R>> {
+        match slot {
+            DatCollectionSlot::Portal => self.portal.as_ref(),
+            DatCollectionSlot::Cell => self.cell.as_ref(),
+            DatCollectionSlot::HighRes => self.highres.as_ref(),
+            DatCollectionSlot::Language => self.language.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, slot: DatCollectionSlot) -> Option<&mut CollectionDat<R>> {
+        self.slot_mut(slot).as_mut()
+    }
+
+    fn slot_mut(&mut self, slot: DatCollectionSlot) -> &mut Option<CollectionDat<R>> {
+        match slot {
+            DatCollectionSlot::Portal => &mut self.portal,
+            DatCollectionSlot::Cell => &mut self.cell,
+            DatCollectionSlot::HighRes => &mut self.highres,
+            DatCollectionSlot::Language => &mut self.language,
+        }
+    }
+
+    /// The slots that have a DAT in them
+    pub fn slots(&self) -> Vec<DatCollectionSlot> {
+        DatCollectionSlot::iter()
+            .filter(|&slot| self.get(slot).is_some())
+            .collect()
+    }
+
+    /// The slots an object of the given kind of DAT could be in, most
+    /// preferred first
+    ///
+    /// Textures come from the highres DAT when it has them, falling back to
+    /// the portal DAT.
+    pub fn route(database_type: &DatDatabaseType, object_id: u32) -> &'static [DatCollectionSlot] {
+        use DatCollectionSlot::*;
+
+        match database_type {
+            DatDatabaseType::Portal => {
+                match DatFileType::from_object_id(object_id, &DatDatabaseType::Portal) {
+                    DatFileType::Texture => &[HighRes, Portal],
+                    _ => &[Portal, HighRes],
+                }
+            }
+            DatDatabaseType::Cell => &[Cell],
+            DatDatabaseType::Language => &[Language],
+        }
+    }
+
+    /// Find the entry for an object in the given kind of DAT
+    ///
+    /// Cell, portal and language IDs overlap (e.g. 0x06000105 is both an
+    /// EnvCell and a portal Texture), so the caller has to say which kind of
+    /// object it's after. Slots that are empty, or don't have the object, are
+    /// skipped over in route order.
+    pub fn find_file(
+        &self,
+        database_type: &DatDatabaseType,
+        object_id: u32,
+    ) -> Option<CollectionEntry> {
+        Self::route(database_type, object_id)
+            .iter()
+            .find_map(|&slot| self.find_in_slot(slot, object_id))
+    }
+
+    fn find_in_slot(&self, slot: DatCollectionSlot, object_id: u32) -> Option<CollectionEntry> {
+        let dat = self.get(slot)?;
+        let entry = *dat.database.find_file(object_id)?;
+
+        Some(CollectionEntry {
+            slot,
+            entry,
+            file_type: dat.database.file_type(&entry),
+        })
+    }
+
+    /// Find the entry for an object ID in whichever kind of DAT has it
+    ///
+    /// This is only for IDs that aren't ambiguous: if more than one kind of
+    /// DAT has the object it's an error, and find_file should be used
+    /// instead.
+    pub fn find_any(&self, object_id: u32) -> Result<Option<CollectionEntry>, DatError> {
+        let found: Vec<CollectionEntry> = DatDatabaseType::iter()
+            .filter_map(|database_type| self.find_file(&database_type, object_id))
+            .collect();
+
+        match found.as_slice() {
+            [] => Ok(None),
+            [found] => Ok(Some(found.clone())),
+            [first, second, ..] => Err(DatError::InvalidData(format!(
+                "Object {:08X} is in both the {} and {} DATs, so which one is meant has to be given",
+                object_id, first.slot, second.slot
+            ))),
+        }
+    }
+
+    /// Read (and decompress, if needed) an object from the given kind of DAT
+    pub async fn read_file(
+        &mut self,
+        database_type: &DatDatabaseType,
+        object_id: u32,
+    ) -> Result<Vec<u8>, DatError> {
+        let found = self.find_file(database_type, object_id).ok_or_else(|| {
+            DatError::NotFound(format!(
+                "Object {:08X} in the {} DATs",
+                object_id, database_type
+            ))
+        })?;
+
+        self.read_entry(&found).await
+    }
+
+    /// Read (and decompress, if needed) a file found with find_file
+    pub async fn read_entry(&mut self, found: &CollectionEntry) -> Result<Vec<u8>, DatError> {
+        let dat = self
+            .get_mut(found.slot)
+            .ok_or_else(|| DatError::NotFound(format!("{} DAT in the collection", found.slot)))?;
+
+        DatFileReader::from_entry(&dat.database.header, &found.entry)?
+            .read_file(&mut dat.reader, found.entry.file_offset)
+            .await
+    }
+
+    /// Every file the collection supplies, each from the DAT find_file would
+    /// pick it from, in object ID order
+    ///
+    /// An object only shadows one with the same ID in a DAT of the same kind
+    /// (i.e. highres textures shadow portal ones), so the same ID can be
+    /// listed once for each kind of DAT.
+    pub fn list_files(&self) -> Result<Vec<CollectionEntry>, DatError> {
+        let mut files = Vec::new();
+
+        for slot in self.slots() {
+            let dat = self.get(slot).expect("slots only lists filled slots");
+
+            for entry in dat.database.list_files(true)? {
+                let shadowed = Self::route(&slot.database_type(), entry.object_id)
+                    .iter()
+                    .take_while(|&&preferred| preferred != slot)
+                    .any(|&preferred| {
+                        self.get(preferred).is_some_and(|other| {
+                            other.database.find_file(entry.object_id).is_some()
+                        })
+                    });
+
+                if !shadowed {
+                    files.push(CollectionEntry {
+                        slot,
+                        entry,
+                        file_type: dat.database.file_type(&entry),
+                    });
+                }
+            }
+        }
+
+        files.sort_by_key(|found| found.entry.object_id);
+
+        Ok(files)
+    }
+}
+
+#[cfg(feature = "core")]
+impl DatCollection<AnyRangeReader> {
+    /// Open the DATs of a client install from a directory (or a URI the DAT
+    /// file names can be appended to)
+    ///
+    /// Local DATs that don't exist are left out. Remote DATs are all expected
+    /// to exist, since there's no cheap way to tell a missing one from a
+    /// failed request.
+    pub async fn open_dir(base: &str) -> Result<DatCollection<AnyRangeReader>, DatError> {
+        let mut collection = DatCollection::new();
+
+        for slot in DatCollectionSlot::iter() {
+            let uri = DatUri::parse(&format!(
+                "{}/{}",
+                base.trim_end_matches('/'),
+                slot.file_name()
+            ))?;
+
+            if let Some(path) = uri.local_path()
+                && !path.exists()
+            {
+                continue;
+            }
+
+            let reader = AnyRangeReader::open_uri(&uri).await?;
+            collection.open(slot, reader).await?;
+        }
+
+        if collection.slots().is_empty() {
+            return Err(DatError::NotFound(format!("Any client DATs in {}", base)));
+        }
+
+        Ok(collection)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{
+        reader::sync_file_reader::SyncFileRangeReader, writer::dat_writer::DatWriter,
+    };
+
+    type MemoryReader = SyncFileRangeReader<Cursor<Vec<u8>>>;
+
+    /// An EnvCell in the cell DAT and a Texture in the portal DAT
+    const OVERLAPPING_ID: u32 = 0x06000105;
+    const HIGHRES_TEXTURE_ID: u32 = 0x06000106;
+    const PORTAL_TEXTURE_ID: u32 = 0x06000107;
+    const GFX_OBJ_ID: u32 = 0x01000105;
+
+    fn dat(database_type: DatDatabaseType, files: &[(u32, &[u8])]) -> MemoryReader {
+        let mut writer = DatWriter::create(Cursor::new(Vec::new()), database_type, 256).unwrap();
+
+        for (object_id, data) in files {
+            writer.write_file(*object_id, data).unwrap();
+        }
+
+        SyncFileRangeReader::new(Cursor::new(writer.finish().unwrap().into_inner()))
+    }
+
+    async fn collection(slots: &[DatCollectionSlot]) -> DatCollection<MemoryReader> {
+        let mut collection = DatCollection::new();
+
+        for &slot in slots {
+            let reader = match slot {
+                DatCollectionSlot::Portal => dat(
+                    DatDatabaseType::Portal,
+                    &[
+                        (OVERLAPPING_ID, b"portal texture"),
+                        (HIGHRES_TEXTURE_ID, b"portal texture"),
+                        (PORTAL_TEXTURE_ID, b"portal texture"),
+                        (GFX_OBJ_ID, b"gfx obj"),
+                    ],
+                ),
+                DatCollectionSlot::HighRes => dat(
+                    DatDatabaseType::Portal,
+                    &[(HIGHRES_TEXTURE_ID, b"highres texture")],
+                ),
+                DatCollectionSlot::Cell => {
+                    dat(DatDatabaseType::Cell, &[(OVERLAPPING_ID, b"env cell")])
+                }
+                DatCollectionSlot::Language => {
+                    dat(DatDatabaseType::Language, &[(0x23000001, b"strings")])
+                }
+            };
+
+            collection.open(slot, reader).await.unwrap();
+        }
+
+        collection
+    }
+
+    fn slot_of(
+        collection: &DatCollection<MemoryReader>,
+        database_type: DatDatabaseType,
+        object_id: u32,
+    ) -> Option<DatCollectionSlot> {
+        collection
+            .find_file(&database_type, object_id)
+            .map(|found| found.slot)
+    }
+
+    #[tokio::test]
+    async fn highres_textures_take_precedence_over_portal_ones() {
+        use DatCollectionSlot::*;
+
+        let mut collection = collection(&[Portal, HighRes]).await;
+
+        assert_eq!(
+            slot_of(&collection, DatDatabaseType::Portal, HIGHRES_TEXTURE_ID),
+            Some(HighRes)
+        );
+        assert_eq!(
+            slot_of(&collection, DatDatabaseType::Portal, PORTAL_TEXTURE_ID),
+            Some(Portal)
+        );
+        assert_eq!(
+            collection
+                .read_file(&DatDatabaseType::Portal, HIGHRES_TEXTURE_ID)
+                .await
+                .unwrap(),
+            b"highres texture"
+        );
+    }
+
+    #[tokio::test]
+    async fn overlapping_ids_are_found_in_the_kind_of_dat_asked_for() {
+        use DatCollectionSlot::*;
+
+        let mut collection = collection(&[Portal, Cell, HighRes, Language]).await;
+
+        let cell = collection
+            .find_file(&DatDatabaseType::Cell, OVERLAPPING_ID)
+            .unwrap();
+        assert_eq!(cell.slot, Cell);
+        assert_eq!(cell.file_type, DatFileType::EnvCell);
+
+        let portal = collection
+            .find_file(&DatDatabaseType::Portal, OVERLAPPING_ID)
+            .unwrap();
+        assert_eq!(portal.slot, Portal);
+        assert_eq!(portal.file_type, DatFileType::Texture);
+
+        assert_eq!(
+            collection
+                .read_file(&DatDatabaseType::Cell, OVERLAPPING_ID)
+                .await
+                .unwrap(),
+            b"env cell"
+        );
+        assert_eq!(
+            collection
+                .read_file(&DatDatabaseType::Portal, OVERLAPPING_ID)
+                .await
+                .unwrap(),
+            b"portal texture"
+        );
+
+        // Only IDs that one kind of DAT has can be looked up by ID alone
+        assert!(collection.find_any(OVERLAPPING_ID).is_err());
+        assert_eq!(
+            collection
+                .find_any(GFX_OBJ_ID)
+                .unwrap()
+                .map(|found| found.slot),
+            Some(Portal)
+        );
+        assert_eq!(
+            slot_of(&collection, DatDatabaseType::Cell, GFX_OBJ_ID),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_slots_are_skipped() {
+        use DatCollectionSlot::*;
+
+        let mut collection = collection(&[Portal]).await;
+
+        assert_eq!(collection.slots(), vec![Portal]);
+        assert_eq!(
+            slot_of(&collection, DatDatabaseType::Portal, HIGHRES_TEXTURE_ID),
+            Some(Portal)
+        );
+        assert_eq!(
+            slot_of(&collection, DatDatabaseType::Cell, OVERLAPPING_ID),
+            None
+        );
+        assert!(matches!(
+            collection
+                .read_file(&DatDatabaseType::Cell, OVERLAPPING_ID)
+                .await,
+            Err(DatError::NotFound(_))
+        ));
+        assert!(collection.find_any(0x06000999).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn list_files_only_shadows_within_a_kind_of_dat() {
+        use DatCollectionSlot::*;
+
+        let collection = collection(&[Portal, Cell, HighRes, Language]).await;
+        let files: Vec<(u32, DatCollectionSlot)> = collection
+            .list_files()
+            .unwrap()
+            .iter()
+            .map(|found| (found.entry.object_id, found.slot))
+            .collect();
+
+        // Entries with the same ID are in no particular order
+        let slots_of = |object_id: u32| {
+            let mut slots: Vec<String> = files
+                .iter()
+                .filter(|(id, _)| *id == object_id)
+                .map(|(_, slot)| slot.to_string())
+                .collect();
+            slots.sort();
+            slots
+        };
+
+        assert_eq!(files.len(), 6);
+        assert_eq!(slots_of(OVERLAPPING_ID), ["Cell", "Portal"]);
+        assert_eq!(slots_of(HIGHRES_TEXTURE_ID), ["HighRes"]);
+        assert_eq!(slots_of(PORTAL_TEXTURE_ID), ["Portal"]);
+        assert_eq!(slots_of(GFX_OBJ_ID), ["Portal"]);
+        assert_eq!(slots_of(0x23000001), ["Language"]);
+        assert!(files.is_sorted_by_key(|(object_id, _)| *object_id));
+    }
+}
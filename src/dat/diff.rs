@@ -0,0 +1,389 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Seek},
+};
+
+use image::{Rgba, RgbaImage, imageops};
+use sha2::{Digest, Sha256};
+use strum::Display;
+
+use crate::dat::{
+    enums::dat_file_type::DatFileType,
+    error::DatError,
+    file_types::texture::Texture,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        types::{
+            dat_database::DatDatabase, dat_database_header::DatDatabaseHeader,
+            dat_directory_entry::DatDirectoryEntry,
+        },
+    },
+};
+
+/// SHA-256 of a file's contents
+pub type ContentHash = [u8; 32];
+
+pub fn content_hash(data: &[u8]) -> ContentHash {
+    Sha256::digest(data).into()
+}
+
+/// Format a ContentHash as lowercase hex
+pub fn content_hash_hex(hash: &ContentHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Serialize a ContentHash as hex rather than an array of numbers
+#[cfg(feature = "serde")]
+fn serialize_content_hash<S: serde::Serializer>(
+    hash: &Option<ContentHash>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match hash {
+        Some(hash) => serializer.serialize_some(&content_hash_hex(hash)),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DatDiffChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Which parts of an entry differ between the two DATs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatEntryChanges {
+    pub size: bool,
+    pub iteration: bool,
+    pub date: bool,
+    /// bit_flags, e.g. a file that's now stored compressed
+    pub flags: bool,
+    /// The contents as stored, so recompressing a file counts as a change
+    pub content: bool,
+}
+
+impl DatEntryChanges {
+    pub fn any(&self) -> bool {
+        self.size || self.iteration || self.date || self.flags || self.content
+    }
+
+    /// Whether anything other than the date changed
+    ///
+    /// Packing the same files twice gives every entry a new date, which is
+    /// usually not worth reporting; see DatDiff::ignoring_dates.
+    pub fn any_besides_date(&self) -> bool {
+        self.size || self.iteration || self.flags || self.content
+    }
+}
+
+impl std::fmt::Display for DatEntryChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (self.size, "size"),
+            (self.iteration, "iteration"),
+            (self.date, "date"),
+            (self.flags, "flags"),
+            (self.content, "content"),
+        ];
+        let changed: Vec<&str> = names
+            .iter()
+            .filter(|(changed, _)| *changed)
+            .map(|(_, name)| *name)
+            .collect();
+
+        write!(f, "{}", changed.join(","))
+    }
+}
+
+/// One object that differs between two DATs
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatDiffEntry {
+    pub change: DatDiffChange,
+    pub object_id: u32,
+    pub file_type: DatFileType,
+    pub old: Option<DatDirectoryEntry>,
+    pub new: Option<DatDirectoryEntry>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_content_hash"))]
+    pub old_hash: Option<ContentHash>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_content_hash"))]
+    pub new_hash: Option<ContentHash>,
+    /// What changed, for Modified entries
+    pub changes: DatEntryChanges,
+}
+
+/// Every object added, removed or modified between two DATs, in object ID
+/// order
+///
+/// An object whose entry differs only in its date counts as modified.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatDiff {
+    pub entries: Vec<DatDiffEntry>,
+}
+
+impl DatDiff {
+    /// Compare two DATs, reading the contents of every file in both to hash
+    /// them
+    pub fn compare<A, B>(old: &mut A, new: &mut B) -> Result<DatDiff, DatError>
+    where
+        A: Read + Seek,
+        B: Read + Seek,
+    {
+        let old_db = DatDatabase::read(old)?;
+        let new_db = DatDatabase::read(new)?;
+
+        Self::compare_databases(&old_db, old, &new_db, new)
+    }
+
+    /// Compare two DATs whose directories have already been read, e.g.
+    /// through an index or with their type overridden
+    pub fn compare_databases<A, B>(
+        old_db: &DatDatabase,
+        old: &mut A,
+        new_db: &DatDatabase,
+        new: &mut B,
+    ) -> Result<DatDiff, DatError>
+    where
+        A: Read + Seek,
+        B: Read + Seek,
+    {
+        let mut objects: BTreeMap<u32, (Option<DatDirectoryEntry>, Option<DatDirectoryEntry>)> =
+            BTreeMap::new();
+
+        for entry in old_db.list_files(true)? {
+            objects.entry(entry.object_id).or_default().0 = Some(entry);
+        }
+
+        for entry in new_db.list_files(true)? {
+            objects.entry(entry.object_id).or_default().1 = Some(entry);
+        }
+
+        let mut entries = Vec::new();
+
+        for (object_id, (old_entry, new_entry)) in objects {
+            let old_hash = match &old_entry {
+                Some(entry) => Some(content_hash(&read_entry(old, &old_db.header, entry)?)),
+                None => None,
+            };
+            let new_hash = match &new_entry {
+                Some(entry) => Some(content_hash(&read_entry(new, &new_db.header, entry)?)),
+                None => None,
+            };
+
+            let (change, changes) = match (&old_entry, &new_entry) {
+                (Some(old_entry), Some(new_entry)) => {
+                    let changes = DatEntryChanges {
+                        size: old_entry.file_size != new_entry.file_size,
+                        iteration: old_entry.iteration != new_entry.iteration,
+                        date: old_entry.date != new_entry.date,
+                        flags: old_entry.bit_flags != new_entry.bit_flags,
+                        content: old_hash != new_hash,
+                    };
+
+                    if !changes.any() {
+                        continue;
+                    }
+
+                    (DatDiffChange::Modified, changes)
+                }
+                (None, Some(_)) => (DatDiffChange::Added, DatEntryChanges::default()),
+                _ => (DatDiffChange::Removed, DatEntryChanges::default()),
+            };
+
+            let file_type = match (&new_entry, &old_entry) {
+                (Some(entry), _) => new_db.file_type(entry),
+                (None, Some(entry)) => old_db.file_type(entry),
+                (None, None) => DatFileType::Unknown,
+            };
+
+            entries.push(DatDiffEntry {
+                change,
+                object_id,
+                file_type,
+                old: old_entry,
+                new: new_entry,
+                old_hash,
+                new_hash,
+                changes,
+            });
+        }
+
+        Ok(DatDiff { entries })
+    }
+
+    /// Leave out objects whose entries differ only in their date
+    pub fn ignoring_dates(mut self) -> DatDiff {
+        self.entries.retain(|entry| {
+            entry.change != DatDiffChange::Modified || entry.changes.any_besides_date()
+        });
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries grouped by the type of file they are
+    pub fn by_type(&self) -> Vec<(DatFileType, Vec<&DatDiffEntry>)> {
+        use strum::IntoEnumIterator;
+
+        DatFileType::iter()
+            .map(|file_type| {
+                let entries: Vec<&DatDiffEntry> = self
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.file_type == file_type)
+                    .collect();
+
+                (file_type, entries)
+            })
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect()
+    }
+}
+
+/// Read the contents of the file an entry points to, as stored in the DAT
+/// (i.e. still compressed if the entry is)
+pub fn read_entry<R: Read + Seek>(
+    reader: &mut R,
+    header: &DatDatabaseHeader,
+    entry: &DatDirectoryEntry,
+) -> Result<Vec<u8>, DatError> {
+    let mut validator =
+        BlockChainValidator::new(header.block_size, header.file_size).for_object(entry.object_id);
+
+    DatBlockReader::read(reader, entry.file_offset, entry.file_size, &mut validator)
+}
+
+/// Read the actual contents of the file an entry points to, decompressing
+/// them if needed
+pub fn read_entry_decoded<R: Read + Seek>(
+    reader: &mut R,
+    header: &DatDatabaseHeader,
+    entry: &DatDirectoryEntry,
+) -> Result<Vec<u8>, DatError> {
+    entry.decode(read_entry(reader, header, entry)?)
+}
+
+/// Render two versions of a texture side by side, followed by an image
+/// highlighting every pixel that differs
+///
+/// The highlight image is only included when both versions are the same
+/// size. Differing pixels are shown as the absolute difference of each
+/// channel over a black background, with full alpha.
+pub fn texture_diff_image(old: &Texture, new: &Texture) -> Result<RgbaImage, DatError> {
+    let old_image = old.to_image(1)?.to_rgba8();
+    let new_image = new.to_image(1)?.to_rgba8();
+
+    let same_size = old_image.dimensions() == new_image.dimensions();
+    let panels = if same_size { 3 } else { 2 };
+    let panel_width = old_image.width().max(new_image.width());
+    let height = old_image.height().max(new_image.height());
+
+    let mut canvas = RgbaImage::new(panel_width * panels, height);
+    imageops::replace(&mut canvas, &old_image, 0, 0);
+    imageops::replace(&mut canvas, &new_image, panel_width as i64, 0);
+
+    if same_size {
+        let diff = RgbaImage::from_fn(old_image.width(), old_image.height(), |x, y| {
+            let a = old_image.get_pixel(x, y).0;
+            let b = new_image.get_pixel(x, y).0;
+
+            Rgba([
+                a[0].abs_diff(b[0]).max(a[3].abs_diff(b[3])),
+                a[1].abs_diff(b[1]).max(a[3].abs_diff(b[3])),
+                a[2].abs_diff(b[2]).max(a[3].abs_diff(b[3])),
+                255,
+            ])
+        });
+
+        imageops::replace(&mut canvas, &diff, (panel_width * 2) as i64, 0);
+    }
+
+    Ok(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{enums::dat_database_type::DatDatabaseType, writer::dat_writer::DatWriter};
+
+    /// A DAT of three files, all dated `date`, with the last one's contents
+    /// given
+    fn build(date: u32, last: &[u8]) -> Cursor<Vec<u8>> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+
+        for (object_id, data) in [
+            (0x06000001, &b"one"[..]),
+            (0x06000002, b"two"),
+            (0x06000003, last),
+        ] {
+            let entry = DatDirectoryEntry {
+                bit_flags: 0,
+                object_id,
+                file_offset: 0,
+                file_size: 0,
+                date,
+                iteration: 1,
+            };
+            writer.insert_entry(entry, data).unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn date_only_changes_are_reported() {
+        let diff =
+            DatDiff::compare(&mut build(1000, b"three"), &mut build(2000, b"three")).unwrap();
+
+        assert_eq!(diff.entries.len(), 3);
+
+        for entry in &diff.entries {
+            assert_eq!(entry.change, DatDiffChange::Modified);
+            assert_eq!(
+                entry.changes,
+                DatEntryChanges {
+                    date: true,
+                    ..Default::default()
+                }
+            );
+        }
+
+        assert!(diff.ignoring_dates().is_empty());
+    }
+
+    #[test]
+    fn identical_dats_have_no_changes() {
+        let diff =
+            DatDiff::compare(&mut build(1000, b"three"), &mut build(1000, b"three")).unwrap();
+
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn content_changes_are_reported_with_the_date() {
+        let diff = DatDiff::compare(&mut build(1000, b"three"), &mut build(2000, b"3")).unwrap();
+
+        assert_eq!(diff.entries.len(), 3);
+
+        let entry = &diff.entries[2];
+        assert_eq!(entry.object_id, 0x06000003);
+        assert_eq!(entry.change, DatDiffChange::Modified);
+        assert!(entry.changes.content && entry.changes.size && entry.changes.date);
+        assert!(!entry.changes.iteration && !entry.changes.flags);
+
+        let diff = diff.ignoring_dates();
+        assert_eq!(diff.entries.len(), 1);
+        assert_eq!(diff.entries[0].object_id, 0x06000003);
+    }
+}
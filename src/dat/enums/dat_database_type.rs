@@ -1,10 +1,15 @@
-use strum::{Display, EnumIter, IntoEnumIterator};
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
 
-#[derive(Clone, Debug, Display, PartialEq, EnumIter)]
+/// Which kind of DAT a database is, as stored in DatDatabaseHeader::data_set
+#[derive(Clone, Debug, Display, PartialEq, EnumIter, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[strum(ascii_case_insensitive)]
 #[repr(u32)]
 pub enum DatDatabaseType {
-    Portal,
-    Cell,
+    Portal = 1,
+    Cell = 2,
+    /// client_local_English.dat and the like
+    Language = 3,
 }
 
 impl DatDatabaseType {
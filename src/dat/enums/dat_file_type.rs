@@ -1,13 +1,46 @@
-use strum::{Display, EnumIter, IntoEnumIterator};
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
 
-#[derive(Clone, Debug, Display, PartialEq, EnumIter)]
+use crate::dat::enums::dat_database_type::DatDatabaseType;
+
+#[derive(Clone, Debug, Display, PartialEq, EnumIter, EnumString)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[strum(ascii_case_insensitive)]
 #[repr(u32)]
 pub enum DatFileType {
     Texture,
+    Wave,
+    /// A landblock's terrain (xxyyFFFF), in a cell DAT
+    Landblock,
+    /// The objects and cells in a landblock (xxyyFFFE), in a cell DAT
+    LandblockInfo,
+    /// An indoor cell (xxyy0100 and up), in a cell DAT
+    EnvCell,
     Unknown,
 }
 
 impl DatFileType {
+    /// Classify an object by its ID
+    ///
+    /// The same ID means different things in different kinds of DAT: every
+    /// cell DAT ID is a landblock (xxyy) followed by a cell number, so e.g.
+    /// 0x0A01FFFF is a landblock there but would be a wave in a portal DAT.
+    pub fn from_object_id(object_id: u32, database_type: &DatDatabaseType) -> DatFileType {
+        match database_type {
+            DatDatabaseType::Portal => match object_id {
+                0x06000000..=0x07FFFFFF => DatFileType::Texture,
+                0x0A000000..=0x0AFFFFFF => DatFileType::Wave,
+                _ => DatFileType::Unknown,
+            },
+            DatDatabaseType::Cell => match object_id & 0xFFFF {
+                0xFFFF => DatFileType::Landblock,
+                0xFFFE => DatFileType::LandblockInfo,
+                0x0100..=0xFFFD => DatFileType::EnvCell,
+                _ => DatFileType::Unknown,
+            },
+            DatDatabaseType::Language => DatFileType::Unknown,
+        }
+    }
+
     pub fn as_u32(&self) -> u32 {
         self.clone() as u32
     }
@@ -18,6 +51,7 @@ impl DatFileType {
 }
 
 #[derive(Clone, Debug, Display, PartialEq, EnumIter)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u32)]
 pub enum DatFileSubtype {
     Icon,
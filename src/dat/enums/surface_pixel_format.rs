@@ -2,6 +2,7 @@ use num_derive::FromPrimitive;
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(i32)]
 pub enum SurfacePixelFormat {
     PFID_UNKNOWN = 0,
@@ -0,0 +1,110 @@
+use std::fmt;
+
+/// The error type used throughout the DAT reader stack
+///
+/// Unlike `Box<dyn std::error::Error>`, this is `Send + Sync`, so results
+/// (and the futures producing them) can be moved across threads and tokio tasks.
+#[derive(Debug)]
+pub enum DatError {
+    /// Reading from or writing to the underlying file failed
+    Io(std::io::Error),
+    /// An HTTP or R2 request failed
+    Http(String),
+    /// A read or write fell outside of the underlying resource
+    OutOfBounds { offset: u64, length: u64, size: u64 },
+    /// A block chain couldn't be followed, e.g. because it loops, points past
+    /// the end of the file or ends before all of its data has been read
+    CorruptBlockChain {
+        object_id: Option<u32>,
+        offset: u32,
+        reason: String,
+    },
+    /// A texture uses a pixel format we can't parse or convert
+    UnknownPixelFormat(i32),
+    /// An object (or the DAT itself) couldn't be found
+    NotFound(String),
+    /// The object is of a type we don't know how to handle
+    UnsupportedType(String),
+    /// A patch doesn't match the DAT it's being applied to, e.g. because it's
+    /// already been applied or an earlier patch is missing
+    PatchConflict { object_id: u32, reason: String },
+    /// The data was readable but didn't make sense, or an argument was invalid
+    InvalidData(String),
+    /// Encoding or decoding an image failed
+    Image(image::ImageError),
+}
+
+impl fmt::Display for DatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatError::Io(e) => write!(f, "I/O error: {}", e),
+            DatError::Http(message) => write!(f, "HTTP error: {}", message),
+            DatError::OutOfBounds {
+                offset,
+                length,
+                size,
+            } => write!(
+                f,
+                "Read of {} bytes at offset {} is out of bounds (size is {})",
+                length, offset, size
+            ),
+            DatError::CorruptBlockChain {
+                object_id: Some(object_id),
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Corrupt block chain for object {:08X} at block offset {}: {}",
+                object_id, offset, reason
+            ),
+            DatError::CorruptBlockChain {
+                object_id: None,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Corrupt block chain at block offset {}: {}",
+                offset, reason
+            ),
+            DatError::UnknownPixelFormat(format) => {
+                write!(f, "Unknown or unsupported pixel format: {}", format)
+            }
+            DatError::NotFound(what) => write!(f, "Not found: {}", what),
+            DatError::UnsupportedType(what) => write!(f, "Unsupported type: {}", what),
+            DatError::PatchConflict { object_id, reason } => {
+                write!(f, "Patch conflict for object {:08X}: {}", object_id, reason)
+            }
+            DatError::InvalidData(message) => write!(f, "Invalid data: {}", message),
+            DatError::Image(e) => write!(f, "Image error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DatError::Io(e) => Some(e),
+            DatError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DatError {
+    fn from(e: std::io::Error) -> Self {
+        DatError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for DatError {
+    fn from(e: image::ImageError) -> Self {
+        DatError::Image(e)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<reqwest::Error> for DatError {
+    fn from(e: reqwest::Error) -> Self {
+        DatError::Http(e.to_string())
+    }
+}
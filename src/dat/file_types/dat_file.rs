@@ -1,22 +1,45 @@
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
-use std::io::{Read, Result};
+use byteorder::WriteBytesExt;
+use std::io::{Read, Write};
+
+use crate::dat::error::DatError;
 
 pub trait DatFileRead: Sized {
-    fn read<R: Read>(reader: &mut R) -> Result<Self>;
+    fn read<R: Read>(reader: &mut R) -> Result<Self, DatError>;
+}
+
+/// The inverse of DatFileRead, for file types we can build ourselves
+pub trait DatFileWrite {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError>;
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DatFile<T> {
     pub id: i32,
     pub inner: T,
 }
 
 impl<T: DatFileRead> DatFile<T> {
-    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, DatError> {
         let id = reader.read_i32::<LittleEndian>()?;
-        let inner = T::read(reader)?.into();
+        let inner = T::read(reader)?;
 
         Ok(Self { id, inner })
     }
 }
+
+impl<T: DatFileWrite> DatFile<T> {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_i32::<LittleEndian>(self.id)?;
+        self.inner.write(writer)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DatError> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)?;
+
+        Ok(bytes)
+    }
+}
@@ -1,2 +1,3 @@
 pub mod dat_file;
 pub mod texture;
+pub mod wave;
@@ -1,14 +1,17 @@
 use crate::dat::enums::surface_pixel_format::SurfacePixelFormat;
+use crate::dat::error::DatError;
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
 use image::{DynamicImage, ImageBuffer, RgbaImage};
 use num_traits::FromPrimitive;
-use std::io::{Error, ErrorKind, Read};
+use std::io::{Read, Write};
 use std::{fs::File, io::BufWriter};
 
-use super::dat_file::DatFileRead;
+use super::dat_file::{DatFileRead, DatFileWrite};
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Texture {
     pub unknown: i32, // This is sometimes 6? Seems used somehow.
     pub width: i32,
@@ -20,23 +23,35 @@ pub struct Texture {
 }
 
 impl DatFileRead for Texture {
-    fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+    fn read<R: Read>(reader: &mut R) -> Result<Self, DatError> {
         let unknown = reader.read_i32::<LittleEndian>()?;
         let width = reader.read_i32::<LittleEndian>()?;
         let height = reader.read_i32::<LittleEndian>()?;
 
         let format_value = reader.read_i32::<LittleEndian>()?;
-        let format = FromPrimitive::from_i32(format_value).ok_or_else(|| {
-            Error::new(
-                ErrorKind::InvalidData,
-                format!("Invalid pixel format: {}", format_value),
-            )
-        })?;
+        let format = FromPrimitive::from_i32(format_value)
+            .ok_or(DatError::UnknownPixelFormat(format_value))?;
         let length = reader.read_i32::<LittleEndian>()?;
 
-        // data
-        let mut data = vec![0u8; length as usize];
-        reader.read_exact(&mut data)?;
+        if length < 0 {
+            return Err(DatError::InvalidData(format!(
+                "Texture has a negative data length ({})",
+                length
+            )));
+        }
+
+        // data, read without trusting length for the allocation so a corrupt
+        // length fails when the input runs out
+        let mut data = Vec::new();
+        reader.take(length as u64).read_to_end(&mut data)?;
+
+        if data.len() < length as usize {
+            return Err(DatError::InvalidData(format!(
+                "Texture data is {} bytes but only {} remain",
+                length,
+                data.len()
+            )));
+        }
 
         // default_palette_id
         let mut palette_id_bytes = [0u8; 4];
@@ -65,11 +80,58 @@ impl DatFileRead for Texture {
     }
 }
 
+impl DatFileWrite for Texture {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_i32::<LittleEndian>(self.unknown)?;
+        writer.write_i32::<LittleEndian>(self.width)?;
+        writer.write_i32::<LittleEndian>(self.height)?;
+        writer.write_i32::<LittleEndian>(self.format.clone() as i32)?;
+        writer.write_i32::<LittleEndian>(self.data.len() as i32)?;
+        writer.write_all(&self.data)?;
+
+        if let Some(default_palette_id) = self.default_palette_id {
+            writer.write_u32::<LittleEndian>(default_palette_id)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Texture {
+    /// Build an uncompressed PFID_A8R8G8B8 texture from an image
+    pub fn from_image(image: &DynamicImage) -> Result<Texture, DatError> {
+        let image = image.to_rgba8();
+        let too_large = || {
+            DatError::InvalidData(format!(
+                "Image is too large for a texture ({}x{})",
+                image.width(),
+                image.height()
+            ))
+        };
+
+        let data: Vec<u8> = image
+            .pixels()
+            .flat_map(|pixel| {
+                // [R,G,B,A] -> [B,G,R,A]
+                [pixel[2], pixel[1], pixel[0], pixel[3]]
+            })
+            .collect();
+
+        Ok(Texture {
+            unknown: 0,
+            width: i32::try_from(image.width()).map_err(|_| too_large())?,
+            height: i32::try_from(image.height()).map_err(|_| too_large())?,
+            format: SurfacePixelFormat::PFID_A8R8G8B8,
+            length: i32::try_from(data.len()).map_err(|_| too_large())?,
+            data,
+            default_palette_id: None,
+        })
+    }
+
     /// export underlying file buffer to rgba-ordered Vec<u8>
     ///
     /// Normalizes input into [R,G,B,A] to simplify downstream code
-    pub fn export(&self) -> Result<Vec<u8>, Error> {
+    pub fn export(&self) -> Result<Vec<u8>, DatError> {
         match self.format {
             SurfacePixelFormat::PFID_R8G8B8 => {
                 // TODO: This is untested (PFID_A8R8G8B8 is tested)
@@ -96,14 +158,19 @@ impl Texture {
 
                 Ok(result)
             }
-            _ => todo!(),
+            _ => Err(DatError::UnknownPixelFormat(self.format.clone() as i32)),
         }
     }
 
-    pub fn to_image(&self, scale: u32) -> Result<DynamicImage, Error> {
+    pub fn to_image(&self, scale: u32) -> Result<DynamicImage, DatError> {
         let buf = self.export()?;
         let img: RgbaImage = ImageBuffer::from_raw(self.width as u32, self.height as u32, buf)
-            .expect("Failed to create ImageBuffer from exported texture.");
+            .ok_or_else(|| {
+                DatError::InvalidData(format!(
+                    "Texture data is too small for its dimensions ({}x{})",
+                    self.width, self.height
+                ))
+            })?;
 
         let mut dynamic_image = DynamicImage::ImageRgba8(img);
 
@@ -118,15 +185,63 @@ impl Texture {
         Ok(dynamic_image)
     }
 
-    pub fn to_png(&self, path: &str, scale: u32) -> Result<(), Error> {
+    pub fn to_png(&self, path: &str, scale: u32) -> Result<(), DatError> {
         let image = self.to_image(scale)?;
         let file = File::create(path)?;
         let mut writer = BufWriter::new(file);
 
-        image
-            .write_to(&mut writer, image::ImageFormat::Png)
-            .expect("Failed to write image.");
+        image.write_to(&mut writer, image::ImageFormat::Png)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture_bytes(length: i32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        for value in [0, 1, 1, SurfacePixelFormat::PFID_A8R8G8B8 as i32, length] {
+            bytes.write_i32::<LittleEndian>(value).unwrap();
+        }
+
+        bytes.extend_from_slice(body);
+
+        bytes
+    }
+
+    #[test]
+    fn reads_data() {
+        let bytes = texture_bytes(4, &[1, 2, 3, 4]);
+        let texture = Texture::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(texture.data, [1, 2, 3, 4]);
+        assert_eq!(texture.export().unwrap(), [3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn rejects_negative_lengths() {
+        for length in [-1, i32::MIN] {
+            let bytes = texture_bytes(length, &[0; 8]);
+
+            assert!(matches!(
+                Texture::read(&mut bytes.as_slice()),
+                Err(DatError::InvalidData(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end_of_the_input() {
+        for length in [5, i32::MAX] {
+            let bytes = texture_bytes(length, &[1, 2, 3, 4]);
+
+            assert!(matches!(
+                Texture::read(&mut bytes.as_slice()),
+                Err(DatError::InvalidData(_))
+            ));
+        }
+    }
+}
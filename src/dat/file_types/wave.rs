@@ -0,0 +1,153 @@
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use std::io::{Read, Write};
+
+use crate::dat::error::DatError;
+
+use super::dat_file::DatFileRead;
+
+/// WAVEFORMATEX format tag for MPEG Layer 3 audio
+pub const WAVE_FORMAT_MPEGLAYER3: u16 = 0x55;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Wave {
+    /// A WAVEFORMATEX describing the audio
+    pub header: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl DatFileRead for Wave {
+    fn read<R: Read>(reader: &mut R) -> Result<Self, DatError> {
+        let header_size = reader.read_i32::<LittleEndian>()?;
+        let data_size = reader.read_i32::<LittleEndian>()?;
+
+        if header_size < 2 || data_size < 0 {
+            return Err(DatError::InvalidData(format!(
+                "Wave has an invalid header size ({}) or data size ({})",
+                header_size, data_size
+            )));
+        }
+
+        let header = read_sized(reader, header_size as u64, "header")?;
+        let data = read_sized(reader, data_size as u64, "data")?;
+
+        Ok(Wave { header, data })
+    }
+}
+
+/// Read `size` bytes without trusting `size` for the allocation, so a corrupt
+/// size fails when the input runs out rather than allocating it up front
+fn read_sized<R: Read>(reader: &mut R, size: u64, what: &str) -> Result<Vec<u8>, DatError> {
+    let mut buffer = Vec::new();
+    reader.take(size).read_to_end(&mut buffer)?;
+
+    if (buffer.len() as u64) < size {
+        return Err(DatError::InvalidData(format!(
+            "Wave {} is {} bytes but only {} remain",
+            what,
+            size,
+            buffer.len()
+        )));
+    }
+
+    Ok(buffer)
+}
+
+impl Wave {
+    pub fn format_tag(&self) -> u16 {
+        u16::from_le_bytes([self.header[0], self.header[1]])
+    }
+
+    /// Extension to use for the output of `export`
+    pub fn extension(&self) -> &'static str {
+        match self.format_tag() {
+            WAVE_FORMAT_MPEGLAYER3 => "mp3",
+            _ => "wav",
+        }
+    }
+
+    /// Export as a playable file: MP3 audio as-is, anything else wrapped in a
+    /// RIFF/WAVE container
+    pub fn export(&self) -> Result<Vec<u8>, DatError> {
+        if self.format_tag() == WAVE_FORMAT_MPEGLAYER3 {
+            return Ok(self.data.clone());
+        }
+
+        let fmt_size = self.header.len() + self.header.len() % 2;
+        let data_size = self.data.len() + self.data.len() % 2;
+        let riff_size = 4 + 8 + fmt_size + 8 + data_size;
+
+        let mut buffer = Vec::with_capacity(8 + riff_size);
+        buffer.write_all(b"RIFF")?;
+        buffer.write_u32::<LittleEndian>(riff_size as u32)?;
+        buffer.write_all(b"WAVE")?;
+
+        buffer.write_all(b"fmt ")?;
+        buffer.write_u32::<LittleEndian>(self.header.len() as u32)?;
+        buffer.write_all(&self.header)?;
+        buffer.resize(buffer.len() + self.header.len() % 2, 0);
+
+        buffer.write_all(b"data")?;
+        buffer.write_u32::<LittleEndian>(self.data.len() as u32)?;
+        buffer.write_all(&self.data)?;
+        buffer.resize(buffer.len() + self.data.len() % 2, 0);
+
+        Ok(buffer)
+    }
+
+    pub fn to_file(&self, path: &str) -> Result<(), DatError> {
+        std::fs::write(path, self.export()?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wave_bytes(header_size: i32, data_size: i32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_i32::<LittleEndian>(header_size).unwrap();
+        bytes.write_i32::<LittleEndian>(data_size).unwrap();
+        bytes.extend_from_slice(body);
+
+        bytes
+    }
+
+    #[test]
+    fn reads_header_and_data() {
+        let bytes = wave_bytes(2, 3, &[0x55, 0x00, 1, 2, 3]);
+        let wave = Wave::read(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(wave.header, [0x55, 0x00]);
+        assert_eq!(wave.data, [1, 2, 3]);
+        assert_eq!(wave.extension(), "mp3");
+    }
+
+    #[test]
+    fn rejects_negative_sizes() {
+        for (header_size, data_size) in [(-1, 0), (2, -1), (i32::MIN, i32::MIN)] {
+            let bytes = wave_bytes(header_size, data_size, &[0; 8]);
+
+            assert!(matches!(
+                Wave::read(&mut bytes.as_slice()),
+                Err(DatError::InvalidData(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_end_of_the_input() {
+        for (header_size, data_size) in [(i32::MAX, 0), (2, i32::MAX), (2, 4)] {
+            let bytes = wave_bytes(header_size, data_size, &[0x55, 0x00, 1, 2, 3]);
+
+            assert!(matches!(
+                Wave::read(&mut bytes.as_slice()),
+                Err(DatError::InvalidData(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::dat::{
+    enums::dat_database_type::DatDatabaseType,
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        range_reader::RangeReader,
+        types::{dat_database::DatDatabase, dat_database_header::DatDatabaseHeader},
+    },
+};
+
+/// Object ID of the file every DAT keeps its iteration (content version) in
+///
+/// Unlike most files it doesn't start with its own ID: the first u32 is the
+/// iteration, and whatever follows it isn't needed here.
+pub const DAT_ITERATION_FILE_ID: u32 = 0xFFFF0001;
+
+/// DatDatabaseHeader::version_major, decoded
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "kind", content = "value", rename_all = "snake_case")
+)]
+pub enum DatVersionMajor {
+    /// All zeroes
+    Empty,
+    /// NUL-padded ASCII text
+    Text(String),
+    /// Anything else, shown as a GUID
+    Guid(String),
+}
+
+impl DatVersionMajor {
+    pub fn decode(bytes: &[u8]) -> DatVersionMajor {
+        if bytes.iter().all(|&byte| byte == 0) {
+            return DatVersionMajor::Empty;
+        }
+
+        let text = match bytes.iter().position(|&byte| byte == 0) {
+            Some(end) => &bytes[..end],
+            None => bytes,
+        };
+        let padding = &bytes[text.len()..];
+
+        if !text.is_empty()
+            && text
+                .iter()
+                .all(|&byte| byte.is_ascii_graphic() || byte == b' ')
+            && padding.iter().all(|&byte| byte == 0)
+        {
+            return DatVersionMajor::Text(String::from_utf8_lossy(text).into_owned());
+        }
+
+        let mut guid = [0u8; 16];
+        let len = bytes.len().min(16);
+        guid[..len].copy_from_slice(&bytes[..len]);
+
+        // Laid out like a Windows GUID, so the first three groups are little
+        // endian
+        DatVersionMajor::Guid(format!(
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+            u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]),
+            u16::from_le_bytes([guid[4], guid[5]]),
+            u16::from_le_bytes([guid[6], guid[7]]),
+            guid[8],
+            guid[9],
+            guid[10..]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<String>()
+        ))
+    }
+}
+
+impl std::fmt::Display for DatVersionMajor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatVersionMajor::Empty => write!(f, "(none)"),
+            DatVersionMajor::Text(text) => write!(f, "\"{}\"", text),
+            DatVersionMajor::Guid(guid) => write!(f, "{{{}}}", guid),
+        }
+    }
+}
+
+/// A client release that shipped a DAT with a known iteration
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct KnownRelease {
+    pub name: &'static str,
+    pub database_type: DatDatabaseType,
+    pub iteration: u32,
+}
+
+/// Releases we can recognise a DAT as coming from
+///
+/// The highres DAT is a portal-type DAT, so the two are told apart by
+/// iteration alone.
+pub const KNOWN_RELEASES: &[KnownRelease] = &[
+    KnownRelease {
+        name: "End of Retail (client_portal.dat)",
+        database_type: DatDatabaseType::Portal,
+        iteration: 2072,
+    },
+    KnownRelease {
+        name: "End of Retail (client_highres.dat)",
+        database_type: DatDatabaseType::Portal,
+        iteration: 497,
+    },
+    KnownRelease {
+        name: "End of Retail (client_cell_1.dat)",
+        database_type: DatDatabaseType::Cell,
+        iteration: 982,
+    },
+    KnownRelease {
+        name: "End of Retail (client_local_English.dat)",
+        database_type: DatDatabaseType::Language,
+        iteration: 994,
+    },
+];
+
+impl KnownRelease {
+    pub fn find(database_type: &DatDatabaseType, iteration: u32) -> Option<&'static KnownRelease> {
+        KNOWN_RELEASES.iter().find(|release| {
+            release.database_type == *database_type && release.iteration == iteration
+        })
+    }
+}
+
+/// Everything we can tell about a DAT from its header and iteration file
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatInfo {
+    pub header: DatDatabaseHeader,
+    pub database_type: Option<DatDatabaseType>,
+    pub version_major: DatVersionMajor,
+    /// Number of files in the directory, if it was read
+    pub files: Option<usize>,
+    /// From the DAT_ITERATION_FILE_ID file, if the DAT has one
+    pub iteration: Option<u32>,
+    pub release: Option<KnownRelease>,
+    /// See DatDatabaseHeader::problems
+    pub problems: Vec<String>,
+}
+
+impl DatInfo {
+    /// Interpret just a header
+    pub fn from_header(header: &DatDatabaseHeader) -> DatInfo {
+        DatInfo {
+            header: header.clone(),
+            database_type: header.database_type(),
+            version_major: DatVersionMajor::decode(&header.version_major),
+            files: None,
+            iteration: None,
+            release: None,
+            problems: header.problems(),
+        }
+    }
+
+    /// Interpret a DAT's header along with its directory and iteration file
+    pub async fn read<R: RangeReader>(
+        reader: &mut R,
+        db: &DatDatabase,
+    ) -> Result<DatInfo, DatError> {
+        let mut info = Self::from_header(&db.header);
+        info.database_type = Some(db.database_type.clone());
+        let files = db.list_files(true)?;
+        info.files = Some(files.len());
+
+        let Some(entry) = files
+            .iter()
+            .find(|entry| entry.object_id == DAT_ITERATION_FILE_ID)
+        else {
+            return Ok(info);
+        };
+
+        let mut validator = BlockChainValidator::new(db.header.block_size, db.header.file_size)
+            .for_object(entry.object_id);
+        let data =
+            DatBlockReader::read_async(reader, entry.file_offset, entry.file_size, &mut validator)
+                .await?;
+        let data = entry.decode(data)?;
+
+        // See DAT_ITERATION_FILE_ID for the layout
+        let iteration = data
+            .first_chunk::<4>()
+            .map(|iteration| u32::from_le_bytes(*iteration));
+
+        info.iteration = iteration;
+        info.release = match (&info.database_type, iteration) {
+            (Some(database_type), Some(iteration)) => {
+                KnownRelease::find(database_type, iteration).cloned()
+            }
+            _ => None,
+        };
+
+        Ok(info)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{
+        reader::sync_file_reader::SyncFileRangeReader, writer::dat_writer::DatWriter,
+    };
+
+    /// A portal DAT holding just an iteration file with the given contents
+    fn dat(iteration_file: &[u8]) -> Vec<u8> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 1024).unwrap();
+        writer
+            .write_file(DAT_ITERATION_FILE_ID, iteration_file)
+            .unwrap();
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn header() -> DatDatabaseHeader {
+        DatDatabase::read(&mut Cursor::new(dat(&[])))
+            .unwrap()
+            .header
+    }
+
+    fn has_problem(header: &DatDatabaseHeader, problem: &str) -> bool {
+        header
+            .problems()
+            .iter()
+            .any(|found| found.starts_with(problem))
+    }
+
+    #[test]
+    fn decodes_empty_version_major() {
+        assert_eq!(DatVersionMajor::decode(&[0; 16]), DatVersionMajor::Empty);
+        assert_eq!(DatVersionMajor::decode(&[]), DatVersionMajor::Empty);
+    }
+
+    #[test]
+    fn decodes_text_version_major() {
+        let mut bytes = [0; 16];
+        bytes[..9].copy_from_slice(b"Retail v1");
+
+        assert_eq!(
+            DatVersionMajor::decode(&bytes),
+            DatVersionMajor::Text("Retail v1".to_string())
+        );
+
+        // Anything after the NUL makes it not text
+        bytes[12] = b'x';
+        assert!(matches!(
+            DatVersionMajor::decode(&bytes),
+            DatVersionMajor::Guid(_)
+        ));
+    }
+
+    #[test]
+    fn decodes_guids_in_windows_byte_order() {
+        let bytes: Vec<u8> = (0..16).collect();
+
+        assert_eq!(
+            DatVersionMajor::decode(&bytes),
+            DatVersionMajor::Guid("03020100-0504-0706-0809-0A0B0C0D0E0F".to_string())
+        );
+        assert_eq!(
+            DatVersionMajor::decode(&bytes).to_string(),
+            "{03020100-0504-0706-0809-0A0B0C0D0E0F}"
+        );
+    }
+
+    #[test]
+    fn written_headers_have_no_problems() {
+        assert_eq!(header().problems(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn finds_header_problems() {
+        let mut bad_magic = header();
+        bad_magic.file_type = 0x1234;
+        assert!(has_problem(&bad_magic, "Magic is 0x00001234"));
+
+        let mut bad_block_size = header();
+        bad_block_size.block_size = 1000;
+        assert!(has_problem(
+            &bad_block_size,
+            "Block size 1000 isn't a power"
+        ));
+
+        let mut misaligned_btree = header();
+        misaligned_btree.btree += 4;
+        assert!(has_problem(&misaligned_btree, "Directory root"));
+
+        let mut misaligned_free_list = header();
+        misaligned_free_list.free_count = 1;
+        misaligned_free_list.free_head = misaligned_free_list.btree + 4;
+        misaligned_free_list.free_tail = misaligned_free_list.btree;
+        assert!(has_problem(&misaligned_free_list, "Free list head"));
+        assert!(!has_problem(&misaligned_free_list, "Free list tail"));
+    }
+
+    async fn info(iteration_file: &[u8]) -> DatInfo {
+        let mut dat = Cursor::new(dat(iteration_file));
+        let db = DatDatabase::read(&mut dat).unwrap();
+
+        DatInfo::read(&mut SyncFileRangeReader::new(dat), &db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_the_iteration_file() {
+        let mut iteration_file = 2072u32.to_le_bytes().to_vec();
+        iteration_file.extend_from_slice(&[0xFF; 5]);
+        let info = info(&iteration_file).await;
+
+        assert_eq!(info.files, Some(1));
+        assert_eq!(info.iteration, Some(2072));
+        assert_eq!(
+            info.release.as_ref().map(|release| release.name),
+            Some("End of Retail (client_portal.dat)")
+        );
+        assert!(info.is_valid());
+    }
+
+    #[tokio::test]
+    async fn short_iteration_files_have_no_iteration() {
+        let info = info(&[1, 2, 3]).await;
+
+        assert_eq!(info.iteration, None);
+        assert_eq!(info.release, None);
+    }
+}
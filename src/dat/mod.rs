@@ -1,3 +1,10 @@
+pub mod collection;
+pub mod diff;
 pub mod enums;
+pub mod error;
 pub mod file_types;
+pub mod info;
+pub mod overlay;
+pub mod patch;
 pub mod reader;
+pub mod writer;
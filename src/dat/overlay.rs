@@ -0,0 +1,493 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use crate::dat::{
+    enums::dat_file_type::DatFileType,
+    error::DatError,
+    file_types::{dat_file::DatFile, texture::Texture},
+    reader::{
+        dat_file_reader::DatFileReader,
+        range_reader::RangeReader,
+        types::{dat_database::DatDatabase, dat_directory_entry::DatDirectoryEntry},
+    },
+};
+
+/// A loose file standing in for an object in a DAT
+#[derive(Clone, Debug, PartialEq)]
+pub enum OverlayFile {
+    /// `<hexid>.bin`, used as the object's contents as-is
+    Raw(PathBuf),
+    /// `<hexid>.png`, encoded as a texture when read
+    Png(PathBuf),
+}
+
+impl OverlayFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            OverlayFile::Raw(path) | OverlayFile::Png(path) => path,
+        }
+    }
+}
+
+/// A directory of loose files to use in place of a DAT's objects
+#[derive(Clone, Debug, Default)]
+pub struct DatOverlay {
+    files: BTreeMap<u32, OverlayFile>,
+    /// Files in the directory that aren't named like an override, so
+    /// callers can warn about them
+    pub skipped: Vec<PathBuf>,
+}
+
+impl DatOverlay {
+    /// Find the overrides in a directory (not including its subdirectories)
+    ///
+    /// Files are named by hex object ID, with or without a 0x prefix, and
+    /// either a .bin or .png extension.
+    pub fn scan<P: AsRef<Path>>(dir: P) -> Result<DatOverlay, DatError> {
+        let mut overlay = DatOverlay::default();
+
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default();
+            let object_id = u32::from_str_radix(stem.strip_prefix("0x").unwrap_or(stem), 16);
+            let extension = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_ascii_lowercase());
+
+            let file = match (object_id, extension.as_deref()) {
+                (Ok(object_id), Some("bin")) => (object_id, OverlayFile::Raw(path)),
+                (Ok(object_id), Some("png")) => (object_id, OverlayFile::Png(path)),
+                _ => {
+                    overlay.skipped.push(path);
+                    continue;
+                }
+            };
+
+            if let Some(existing) = overlay.files.get(&file.0) {
+                return Err(DatError::InvalidData(format!(
+                    "{} and {} both override {:08X}",
+                    existing.path().display(),
+                    file.1.path().display(),
+                    file.0
+                )));
+            }
+
+            overlay.files.insert(file.0, file.1);
+        }
+
+        overlay.skipped.sort();
+
+        Ok(overlay)
+    }
+
+    pub fn get(&self, object_id: u32) -> Option<&OverlayFile> {
+        self.files.get(&object_id)
+    }
+
+    /// The IDs of every overridden object, in order
+    pub fn object_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.files.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Read an override as the contents the object would have in a DAT
+    ///
+    /// PNGs become PFID_A8R8G8B8 textures, keeping the unknown field of the
+    /// texture they replace (when there is one) since its meaning isn't
+    /// known.
+    pub fn read_file(
+        &self,
+        object_id: u32,
+        file: &OverlayFile,
+        file_type: &DatFileType,
+        original: Option<&[u8]>,
+    ) -> Result<Vec<u8>, DatError> {
+        let png_path = match file {
+            OverlayFile::Raw(path) => return Ok(fs::read(path)?),
+            OverlayFile::Png(path) => path,
+        };
+
+        if *file_type != DatFileType::Texture {
+            return Err(DatError::UnsupportedType(format!(
+                "{} overrides {:08X}, which is a {} rather than a Texture",
+                png_path.display(),
+                object_id,
+                file_type
+            )));
+        }
+
+        let mut texture = Texture::from_image(&image::open(png_path)?)?;
+
+        if let Some(original) = original
+            && let Ok(original) = DatFile::<Texture>::read(&mut Cursor::new(original))
+        {
+            texture.unknown = original.inner.unknown;
+        }
+
+        DatFile {
+            id: object_id as i32,
+            inner: texture,
+        }
+        .to_bytes()
+    }
+}
+
+/// Where an OverlayEntry's contents come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OverlayStatus {
+    /// The DAT, untouched
+    Dat,
+    /// An override, in place of the DAT's file
+    Overridden,
+    /// An override for an object the DAT doesn't have
+    Added,
+}
+
+impl std::fmt::Display for OverlayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlayStatus::Dat => write!(f, "dat"),
+            OverlayStatus::Overridden => write!(f, "overridden"),
+            OverlayStatus::Added => write!(f, "added"),
+        }
+    }
+}
+
+/// A file in an OverlayDatabase's merged listing
+#[derive(Clone, Debug)]
+pub struct OverlayEntry {
+    pub object_id: u32,
+    pub file_type: DatFileType,
+    /// The DAT's entry, if the DAT has the object
+    pub entry: Option<DatDirectoryEntry>,
+    /// The override, if there is one
+    pub overlay_file: Option<OverlayFile>,
+}
+
+impl OverlayEntry {
+    pub fn status(&self) -> OverlayStatus {
+        match (&self.entry, &self.overlay_file) {
+            (_, None) => OverlayStatus::Dat,
+            (Some(_), Some(_)) => OverlayStatus::Overridden,
+            (None, Some(_)) => OverlayStatus::Added,
+        }
+    }
+}
+
+/// A DAT as seen through a DatOverlay: overrides are read in place of the
+/// DAT's own files, which lets replacements be tried without rewriting the
+/// DAT
+pub struct OverlayDatabase<R: RangeReader> {
+    pub reader: R,
+    pub database: DatDatabase,
+    pub overlay: DatOverlay,
+}
+
+impl<R: RangeReader> OverlayDatabase<R> {
+    pub fn new(reader: R, database: DatDatabase, overlay: DatOverlay) -> Self {
+        Self {
+            reader,
+            database,
+            overlay,
+        }
+    }
+
+    /// Every file in the DAT and the overlay, in object ID order
+    pub fn list_files(&self) -> Result<Vec<OverlayEntry>, DatError> {
+        let mut files: BTreeMap<u32, OverlayEntry> = BTreeMap::new();
+
+        for entry in self.database.list_files(true)? {
+            files.insert(
+                entry.object_id,
+                OverlayEntry {
+                    object_id: entry.object_id,
+                    file_type: self.database.file_type(&entry),
+                    entry: Some(entry),
+                    overlay_file: self.overlay.get(entry.object_id).cloned(),
+                },
+            );
+        }
+
+        for object_id in self.overlay.object_ids() {
+            files.entry(object_id).or_insert_with(|| OverlayEntry {
+                object_id,
+                file_type: DatFileType::from_object_id(object_id, &self.database.database_type),
+                entry: None,
+                overlay_file: self.overlay.get(object_id).cloned(),
+            });
+        }
+
+        Ok(files.into_values().collect())
+    }
+
+    pub fn find_file(&self, object_id: u32) -> Option<OverlayEntry> {
+        let entry = self.database.find_file(object_id).copied();
+        let overlay_file = self.overlay.get(object_id).cloned();
+
+        if entry.is_none() && overlay_file.is_none() {
+            return None;
+        }
+
+        Some(OverlayEntry {
+            object_id,
+            file_type: DatFileType::from_object_id(object_id, &self.database.database_type),
+            entry,
+            overlay_file,
+        })
+    }
+
+    /// Read an object, from its override if it has one and from the DAT
+    /// (decompressing it if needed) otherwise
+    pub async fn read_file(&mut self, object_id: u32) -> Result<Vec<u8>, DatError> {
+        let found = self
+            .find_file(object_id)
+            .ok_or_else(|| DatError::NotFound(format!("Object {:08X}", object_id)))?;
+
+        self.read_entry(&found).await
+    }
+
+    /// Read a file found with find_file or list_files
+    pub async fn read_entry(&mut self, found: &OverlayEntry) -> Result<Vec<u8>, DatError> {
+        let Some(overlay_file) = &found.overlay_file else {
+            let entry = found
+                .entry
+                .as_ref()
+                .ok_or_else(|| DatError::NotFound(format!("Object {:08X}", found.object_id)))?;
+
+            return self.read_dat_file(entry).await;
+        };
+
+        // Only PNGs look at the file they replace, and can do without it
+        let original = match (&found.entry, overlay_file) {
+            (Some(entry), OverlayFile::Png(_)) => self.read_dat_file(entry).await.ok(),
+            _ => None,
+        };
+
+        self.overlay.read_file(
+            found.object_id,
+            overlay_file,
+            &found.file_type,
+            original.as_deref(),
+        )
+    }
+
+    async fn read_dat_file(&mut self, entry: &DatDirectoryEntry) -> Result<Vec<u8>, DatError> {
+        DatFileReader::from_entry(&self.database.header, entry)?
+            .read_file(&mut self.reader, entry.file_offset)
+            .await
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::dat::{
+        enums::{dat_database_type::DatDatabaseType, surface_pixel_format::SurfacePixelFormat},
+        reader::sync_file_reader::SyncFileRangeReader,
+        writer::dat_writer::DatWriter,
+    };
+
+    const TEXTURE_ID: u32 = 0x06000001;
+    const UNTOUCHED_TEXTURE_ID: u32 = 0x06000002;
+    const ADDED_ID: u32 = 0x06000003;
+    const GFX_OBJ_ID: u32 = 0x01000001;
+
+    /// A fresh, empty directory for a test's overrides
+    fn overlay_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libac-rs-overlay-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn texture_bytes(object_id: u32, unknown: i32) -> Vec<u8> {
+        DatFile {
+            id: object_id as i32,
+            inner: Texture {
+                unknown,
+                width: 1,
+                height: 1,
+                format: SurfacePixelFormat::PFID_A8R8G8B8,
+                length: 4,
+                data: vec![1, 2, 3, 4],
+                default_palette_id: None,
+            },
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    fn overlay_database(dir: &Path) -> OverlayDatabase<SyncFileRangeReader<Cursor<Vec<u8>>>> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+        writer
+            .write_file(TEXTURE_ID, &texture_bytes(TEXTURE_ID, 6))
+            .unwrap();
+        writer
+            .write_file(
+                UNTOUCHED_TEXTURE_ID,
+                &texture_bytes(UNTOUCHED_TEXTURE_ID, 0),
+            )
+            .unwrap();
+        writer.write_file(GFX_OBJ_ID, b"gfx obj").unwrap();
+
+        let mut dat = writer.finish().unwrap();
+        let database = DatDatabase::read(&mut dat).unwrap();
+
+        OverlayDatabase::new(
+            SyncFileRangeReader::new(dat),
+            database,
+            DatOverlay::scan(dir).unwrap(),
+        )
+    }
+
+    #[test]
+    fn scan_finds_bin_and_png_overrides() {
+        let dir = overlay_dir("scan");
+
+        for name in [
+            "06000001.bin",
+            "0x06000002.PNG",
+            "6000003.png",
+            "notes.txt",
+            "not-an-id.bin",
+            "06000004",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        fs::create_dir(dir.join("06000005.bin")).unwrap();
+
+        let overlay = DatOverlay::scan(&dir).unwrap();
+
+        assert_eq!(
+            overlay.object_ids().collect::<Vec<_>>(),
+            [0x06000001, 0x06000002, 0x06000003]
+        );
+        assert_eq!(
+            overlay.get(0x06000001),
+            Some(&OverlayFile::Raw(dir.join("06000001.bin")))
+        );
+        assert_eq!(
+            overlay.get(0x06000002),
+            Some(&OverlayFile::Png(dir.join("0x06000002.PNG")))
+        );
+        assert_eq!(
+            overlay.skipped,
+            [
+                dir.join("06000004"),
+                dir.join("not-an-id.bin"),
+                dir.join("notes.txt")
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_rejects_two_overrides_of_one_object() {
+        let dir = overlay_dir("duplicate");
+        fs::write(dir.join("06000001.bin"), b"").unwrap();
+        fs::write(dir.join("0x06000001.png"), b"").unwrap();
+
+        match DatOverlay::scan(&dir) {
+            Err(DatError::InvalidData(message)) => {
+                assert!(message.ends_with("both override 06000001"), "{}", message)
+            }
+            result => panic!("Expected a duplicate error, got {:?}", result),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_and_reads_through_the_overlay() {
+        let dir = overlay_dir("read");
+        fs::write(dir.join(format!("{:08X}.bin", GFX_OBJ_ID)), b"new gfx obj").unwrap();
+        fs::write(dir.join(format!("{:08X}.bin", ADDED_ID)), b"added").unwrap();
+        RgbaImage::from_pixel(2, 1, Rgba([10, 20, 30, 255]))
+            .save(dir.join(format!("{:08X}.png", TEXTURE_ID)))
+            .unwrap();
+
+        let mut db = overlay_database(&dir);
+        let statuses: Vec<(u32, OverlayStatus)> = db
+            .list_files()
+            .unwrap()
+            .iter()
+            .map(|file| (file.object_id, file.status()))
+            .collect();
+
+        assert_eq!(
+            statuses,
+            [
+                (GFX_OBJ_ID, OverlayStatus::Overridden),
+                (TEXTURE_ID, OverlayStatus::Overridden),
+                (UNTOUCHED_TEXTURE_ID, OverlayStatus::Dat),
+                (ADDED_ID, OverlayStatus::Added),
+            ]
+        );
+
+        assert_eq!(db.read_file(GFX_OBJ_ID).await.unwrap(), b"new gfx obj");
+        assert_eq!(db.read_file(ADDED_ID).await.unwrap(), b"added");
+        assert_eq!(
+            db.read_file(UNTOUCHED_TEXTURE_ID).await.unwrap(),
+            texture_bytes(UNTOUCHED_TEXTURE_ID, 0)
+        );
+
+        // The PNG is encoded as a texture, keeping the unknown field of the
+        // one it replaces
+        let data = db.read_file(TEXTURE_ID).await.unwrap();
+        let texture = DatFile::<Texture>::read(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(texture.id, TEXTURE_ID as i32);
+        assert_eq!(texture.inner.unknown, 6);
+        assert_eq!((texture.inner.width, texture.inner.height), (2, 1));
+        assert_eq!(texture.inner.format, SurfacePixelFormat::PFID_A8R8G8B8);
+        assert_eq!(texture.inner.data, [30, 20, 10, 255, 30, 20, 10, 255]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pngs_only_override_textures() {
+        let dir = overlay_dir("png-over-gfx-obj");
+        RgbaImage::new(1, 1)
+            .save(dir.join(format!("{:08X}.png", GFX_OBJ_ID)))
+            .unwrap();
+
+        let mut db = overlay_database(&dir);
+
+        assert!(matches!(
+            db.read_file(GFX_OBJ_ID).await,
+            Err(DatError::UnsupportedType(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dat::{
+    diff::{ContentHash, DatDiff, DatDiffChange, content_hash, read_entry},
+    error::DatError,
+    reader::types::{dat_database::DatDatabase, dat_directory_entry::DatDirectoryEntry},
+    writer::dat_writer::DatWriter,
+};
+
+pub const DAT_PATCH_MAGIC: &[u8; 4] = b"ACDP";
+pub const DAT_PATCH_VERSION: u32 = 1;
+
+/// The version of an entry a patch operation expects to find in the DAT it's
+/// applied to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatPatchBase {
+    pub iteration: u32,
+    pub hash: ContentHash,
+}
+
+/// A single change in a DatPatch
+#[derive(Debug, Clone)]
+pub enum DatPatchOp {
+    /// Add a file, or replace the one matching `base`
+    Put {
+        base: Option<DatPatchBase>,
+        entry: DatDirectoryEntry,
+        data: Vec<u8>,
+    },
+    /// Remove the file matching `base`
+    Delete { object_id: u32, base: DatPatchBase },
+}
+
+impl DatPatchOp {
+    pub fn object_id(&self) -> u32 {
+        match self {
+            DatPatchOp::Put { entry, .. } => entry.object_id,
+            DatPatchOp::Delete { object_id, .. } => *object_id,
+        }
+    }
+}
+
+/// The changes that turn one DAT into another
+///
+/// Only entries that differ are stored. Every replaced or deleted entry
+/// records the iteration and content hash it had in the old DAT, and a patch
+/// is only applied if the target still has exactly those; otherwise the
+/// patch is refused as out of order (or already applied) without anything
+/// being written. Added and replaced entries keep the flags, date and
+/// iteration they have in the new DAT.
+#[derive(Debug, Clone)]
+pub struct DatPatch {
+    /// DatDatabaseHeader::data_set of the DATs the patch was made from
+    pub data_set: u32,
+    pub engine_pack_version: u32,
+    pub game_pack_version: u32,
+    pub version_major: Vec<u8>,
+    pub version_minor: u32,
+    pub ops: Vec<DatPatchOp>,
+}
+
+/// What applying a patch did
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatPatchSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub deleted: usize,
+}
+
+impl DatPatch {
+    /// Build the patch that turns `old` into `new`
+    ///
+    /// Every entry that differs at all is included, even if only its date
+    /// changed, so the patched DAT's entries match `new`'s exactly.
+    pub fn create<A, B>(old: &mut A, new: &mut B) -> Result<DatPatch, DatError>
+    where
+        A: Read + Seek,
+        B: Read + Seek,
+    {
+        let old_db = DatDatabase::read(old)?;
+        let new_db = DatDatabase::read(new)?;
+
+        Self::create_from_databases(&old_db, old, &new_db, new)
+    }
+
+    /// Build the patch that turns `old` into `new` from directories that have
+    /// already been read, e.g. through an index
+    pub fn create_from_databases<A, B>(
+        old_db: &DatDatabase,
+        old: &mut A,
+        new_db: &DatDatabase,
+        new: &mut B,
+    ) -> Result<DatPatch, DatError>
+    where
+        A: Read + Seek,
+        B: Read + Seek,
+    {
+        let diff = DatDiff::compare_databases(old_db, old, new_db, new)?;
+        let new_header = &new_db.header;
+
+        let mut ops = Vec::new();
+
+        for diff_entry in &diff.entries {
+            let base = match (diff_entry.old, diff_entry.old_hash) {
+                (Some(old_entry), Some(hash)) => Some(DatPatchBase {
+                    iteration: old_entry.iteration,
+                    hash,
+                }),
+                _ => None,
+            };
+
+            match (diff_entry.change, diff_entry.new, base) {
+                (DatDiffChange::Removed, _, Some(base)) => ops.push(DatPatchOp::Delete {
+                    object_id: diff_entry.object_id,
+                    base,
+                }),
+                (_, Some(entry), base) => ops.push(DatPatchOp::Put {
+                    base,
+                    entry,
+                    data: read_entry(new, new_header, &entry)?,
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(DatPatch {
+            data_set: new_header.data_set,
+            engine_pack_version: new_header.engine_pack_version,
+            game_pack_version: new_header.game_pack_version,
+            version_major: new_header.version_major.clone(),
+            version_minor: new_header.version_minor,
+            ops,
+        })
+    }
+
+    /// Apply the patch to a DAT in place
+    ///
+    /// Every operation is checked against the DAT before anything is written,
+    /// so a refused patch leaves the DAT untouched. Files are written to new
+    /// blocks and the old ones are only freed after the new header is in
+    /// place (see DatWriter), so an apply that fails part way through leaves
+    /// the DAT's files as they were, though its free list may need repairing
+    /// by compacting the DAT.
+    pub fn apply<W: Read + Write + Seek>(&self, target: W) -> Result<DatPatchSummary, DatError> {
+        let mut writer = DatWriter::open(target)?;
+
+        if writer.header().data_set != self.data_set {
+            return Err(DatError::InvalidData(format!(
+                "Patch is for data set {} but the DAT is data set {}",
+                self.data_set,
+                writer.header().data_set
+            )));
+        }
+
+        for op in &self.ops {
+            self.check(&mut writer, op)?;
+        }
+
+        let mut summary = DatPatchSummary::default();
+
+        for op in &self.ops {
+            match op {
+                DatPatchOp::Put { base, entry, data } => {
+                    writer.insert_entry(*entry, data)?;
+
+                    match base {
+                        Some(_) => summary.replaced += 1,
+                        None => summary.added += 1,
+                    }
+                }
+                DatPatchOp::Delete { object_id, .. } => {
+                    writer.delete_file(*object_id)?;
+                    summary.deleted += 1;
+                }
+            }
+        }
+
+        let header = writer.header_mut();
+        header.engine_pack_version = self.engine_pack_version;
+        header.game_pack_version = self.game_pack_version;
+        header.version_major = self.version_major.clone();
+        header.version_minor = self.version_minor;
+
+        writer.finish()?;
+
+        Ok(summary)
+    }
+
+    /// Make sure the DAT holds exactly the version of the entry the operation
+    /// was made against
+    fn check<W: Read + Write + Seek>(
+        &self,
+        writer: &mut DatWriter<W>,
+        op: &DatPatchOp,
+    ) -> Result<(), DatError> {
+        let object_id = op.object_id();
+        let conflict = |reason: String| DatError::PatchConflict { object_id, reason };
+
+        let (base, new_iteration) = match op {
+            DatPatchOp::Put { base, entry, .. } => (*base, Some(entry.iteration)),
+            DatPatchOp::Delete { base, .. } => (Some(*base), None),
+        };
+
+        let current = writer.get_entry(object_id);
+
+        match (base, current) {
+            (None, None) => Ok(()),
+            (None, Some(current)) => Err(conflict(format!(
+                "Patch adds it but the DAT already has iteration {}{}",
+                current.iteration,
+                if new_iteration.is_some_and(|iteration| current.iteration >= iteration) {
+                    " (patch already applied?)"
+                } else {
+                    ""
+                }
+            ))),
+            (Some(base), None) => Err(conflict(format!(
+                "Patch expects iteration {} but the DAT doesn't have it (patch already applied, or an earlier one is missing?)",
+                base.iteration
+            ))),
+            (Some(base), Some(current)) if current.iteration > base.iteration => {
+                Err(conflict(format!(
+                    "DAT has iteration {}, newer than the {} the patch expects (patch already applied?)",
+                    current.iteration, base.iteration
+                )))
+            }
+            (Some(base), Some(current)) if current.iteration < base.iteration => {
+                Err(conflict(format!(
+                    "DAT has iteration {}, older than the {} the patch expects (apply earlier patches first)",
+                    current.iteration, base.iteration
+                )))
+            }
+            (Some(base), Some(_)) => {
+                let data = writer.read_file(object_id)?.unwrap_or_default();
+
+                if content_hash(&data) != base.hash {
+                    return Err(conflict(
+                        "DAT's contents don't match what the patch expects".to_string(),
+                    ));
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<DatPatch, DatError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != DAT_PATCH_MAGIC {
+            return Err(DatError::InvalidData(
+                "Not a DAT patch file (bad magic)".to_string(),
+            ));
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+
+        if version != DAT_PATCH_VERSION {
+            return Err(DatError::InvalidData(format!(
+                "Unsupported DAT patch version: {}",
+                version
+            )));
+        }
+
+        let data_set = reader.read_u32::<LittleEndian>()?;
+        let engine_pack_version = reader.read_u32::<LittleEndian>()?;
+        let game_pack_version = reader.read_u32::<LittleEndian>()?;
+        let mut version_major = vec![0; 16];
+        reader.read_exact(&mut version_major)?;
+        let version_minor = reader.read_u32::<LittleEndian>()?;
+
+        let op_count = reader.read_u32::<LittleEndian>()?;
+        let mut ops = Vec::new();
+
+        for _ in 0..op_count {
+            let kind = reader.read_u8()?;
+            let base = match reader.read_u8()? {
+                0 => None,
+                _ => {
+                    let iteration = reader.read_u32::<LittleEndian>()?;
+                    let mut hash = [0u8; 32];
+                    reader.read_exact(&mut hash)?;
+
+                    Some(DatPatchBase { iteration, hash })
+                }
+            };
+
+            let op = match (kind, base) {
+                (0, base) => {
+                    let entry = DatDirectoryEntry::read(reader)?;
+                    let length = reader.read_u32::<LittleEndian>()?;
+
+                    // Read through take so a bad length can't allocate more
+                    // than the patch actually holds
+                    let mut data = Vec::new();
+                    reader.take(length as u64).read_to_end(&mut data)?;
+
+                    if data.len() != length as usize {
+                        return Err(DatError::InvalidData(format!(
+                            "Patch data for {:08X} is truncated",
+                            entry.object_id
+                        )));
+                    }
+
+                    DatPatchOp::Put { base, entry, data }
+                }
+                (1, Some(base)) => DatPatchOp::Delete {
+                    object_id: reader.read_u32::<LittleEndian>()?,
+                    base,
+                },
+                (kind, _) => {
+                    return Err(DatError::InvalidData(format!(
+                        "Invalid DAT patch operation: {}",
+                        kind
+                    )));
+                }
+            };
+
+            ops.push(op);
+        }
+
+        Ok(DatPatch {
+            data_set,
+            engine_pack_version,
+            game_pack_version,
+            version_major,
+            version_minor,
+            ops,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_all(DAT_PATCH_MAGIC)?;
+        writer.write_u32::<LittleEndian>(DAT_PATCH_VERSION)?;
+        writer.write_u32::<LittleEndian>(self.data_set)?;
+        writer.write_u32::<LittleEndian>(self.engine_pack_version)?;
+        writer.write_u32::<LittleEndian>(self.game_pack_version)?;
+
+        let mut version_major = self.version_major.clone();
+        version_major.resize(16, 0);
+        writer.write_all(&version_major)?;
+        writer.write_u32::<LittleEndian>(self.version_minor)?;
+
+        writer.write_u32::<LittleEndian>(self.ops.len() as u32)?;
+
+        for op in &self.ops {
+            let (kind, base) = match op {
+                DatPatchOp::Put { base, .. } => (0, *base),
+                DatPatchOp::Delete { base, .. } => (1, Some(*base)),
+            };
+
+            writer.write_u8(kind)?;
+
+            match base {
+                Some(base) => {
+                    writer.write_u8(1)?;
+                    writer.write_u32::<LittleEndian>(base.iteration)?;
+                    writer.write_all(&base.hash)?;
+                }
+                None => writer.write_u8(0)?,
+            }
+
+            match op {
+                DatPatchOp::Put { entry, data, .. } => {
+                    entry.write(writer)?;
+                    writer.write_u32::<LittleEndian>(data.len() as u32)?;
+                    writer.write_all(data)?;
+                }
+                DatPatchOp::Delete { object_id, .. } => {
+                    writer.write_u32::<LittleEndian>(*object_id)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DatPatch, DatError> {
+        Self::read(&mut Cursor::new(bytes))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DatError> {
+        let mut buffer = Vec::new();
+        self.write(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DatPatch, DatError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        Self::read(&mut reader)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DatError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Cursor};
+
+    use super::*;
+    use crate::dat::{
+        enums::dat_database_type::DatDatabaseType, reader::types::dat_database::DatDatabase,
+    };
+
+    /// Fails every write once `writes_left` runs out, like a disk filling up
+    struct FailingWriter {
+        inner: Cursor<Vec<u8>>,
+        writes_left: usize,
+    }
+
+    impl Read for FailingWriter {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.writes_left == 0 {
+                return Err(std::io::Error::other("out of space"));
+            }
+
+            self.writes_left -= 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingWriter {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// Everything about each entry except where it's stored
+    fn entries(dat: &[u8]) -> BTreeMap<u32, (u32, u32, u32, u32)> {
+        let db = DatDatabase::read(&mut Cursor::new(dat)).unwrap();
+
+        db.list_files(true)
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.object_id,
+                    (
+                        entry.bit_flags,
+                        entry.file_size,
+                        entry.date,
+                        entry.iteration,
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    fn contents(dat: &[u8]) -> BTreeMap<u32, Vec<u8>> {
+        let mut dat = Cursor::new(dat);
+        let db = DatDatabase::read(&mut dat).unwrap();
+
+        db.list_files(true)
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.object_id,
+                    read_entry(&mut dat, &db.header, entry).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// An old DAT, and a new one with files replaced, added and deleted
+    fn old_and_new() -> (Vec<u8>, Vec<u8>) {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+
+        for object_id in 0x06000000..0x06000100 {
+            writer
+                .write_file(object_id, &vec![object_id as u8; 300])
+                .unwrap();
+        }
+
+        let old = writer.finish().unwrap().into_inner();
+        let mut writer = DatWriter::open(Cursor::new(old.clone())).unwrap();
+
+        for object_id in (0x06000000..0x06000100).step_by(5) {
+            writer.write_file(object_id, &vec![0xEE; 700]).unwrap();
+        }
+
+        for object_id in (0x06000001..0x06000100).step_by(7) {
+            writer.delete_file(object_id).unwrap();
+        }
+
+        for object_id in 0x06000100..0x06000110 {
+            writer.write_file(object_id, b"new").unwrap();
+        }
+
+        (old, writer.finish().unwrap().into_inner())
+    }
+
+    #[test]
+    fn failed_apply_leaves_the_old_files() {
+        let (old, new) = old_and_new();
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+        let (old_contents, new_contents) = (contents(&old), contents(&new));
+
+        let mut failures = 0;
+
+        for writes_left in 0.. {
+            let mut target = FailingWriter {
+                inner: Cursor::new(old.clone()),
+                writes_left,
+            };
+            let result = patch.apply(&mut target);
+            let patched = contents(target.inner.get_ref());
+
+            if result.is_ok() {
+                assert_eq!(patched, new_contents);
+                break;
+            }
+
+            // The last writes free the old blocks after the header's already
+            // pointing at the new files
+            assert!(patched == old_contents || patched == new_contents);
+            failures += 1;
+        }
+
+        assert!(failures > 100);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let (old, new) = old_and_new();
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+        let bytes = patch.to_bytes().unwrap();
+        let read = DatPatch::from_bytes(&bytes).unwrap();
+
+        assert_eq!(&bytes[..4], DAT_PATCH_MAGIC);
+        assert_eq!(read.data_set, patch.data_set);
+        assert_eq!(read.version_major, patch.version_major);
+        assert_eq!(
+            read.ops
+                .iter()
+                .map(DatPatchOp::object_id)
+                .collect::<Vec<_>>(),
+            patch
+                .ops
+                .iter()
+                .map(DatPatchOp::object_id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(read.to_bytes().unwrap(), bytes);
+
+        assert!(DatPatch::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn applying_gives_the_new_dat() {
+        let (old, new) = old_and_new();
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+        let patch = DatPatch::from_bytes(&patch.to_bytes().unwrap()).unwrap();
+
+        let mut target = Cursor::new(old);
+        let summary = patch.apply(&mut target).unwrap();
+        let patched = target.into_inner();
+
+        assert_eq!(summary.added, 0x10);
+        assert!(summary.replaced > 0 && summary.deleted > 0);
+        assert_eq!(entries(&patched), entries(&new));
+        assert_eq!(contents(&patched), contents(&new));
+    }
+
+    #[test]
+    fn date_only_changes_are_patched() {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+        let entry = writer.write_file(0x06000001, b"one").unwrap();
+        let old = writer.finish().unwrap().into_inner();
+
+        let mut writer = DatWriter::open(Cursor::new(old.clone())).unwrap();
+        let entry = DatDirectoryEntry {
+            date: entry.date + 1000,
+            ..entry
+        };
+        writer.insert_entry(entry, b"one").unwrap();
+        let new = writer.finish().unwrap().into_inner();
+
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+        assert_eq!(patch.ops.len(), 1);
+
+        let mut target = Cursor::new(old);
+        patch.apply(&mut target).unwrap();
+        assert_eq!(entries(target.get_ref()), entries(&new));
+    }
+
+    #[test]
+    fn refuses_to_apply_twice() {
+        let (old, new) = old_and_new();
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+
+        let mut target = Cursor::new(old);
+        patch.apply(&mut target).unwrap();
+        let patched = target.into_inner();
+
+        let mut target = Cursor::new(patched.clone());
+        let result = patch.apply(&mut target);
+
+        assert!(
+            matches!(result, Err(DatError::PatchConflict { .. })),
+            "{:?}",
+            result
+        );
+        assert_eq!(target.into_inner(), patched);
+    }
+
+    #[test]
+    fn refuses_a_different_base() {
+        let (old, new) = old_and_new();
+        let patch = DatPatch::create(&mut Cursor::new(&old), &mut Cursor::new(&new)).unwrap();
+
+        // 0x06000005 is replaced by the patch, so it must be exactly as it
+        // was in the old DAT
+        let mut writer = DatWriter::open(Cursor::new(old.clone())).unwrap();
+        let entry = writer.get_entry(0x06000005).unwrap();
+        writer.write_file(0x06000005, b"newer").unwrap();
+        let newer_iteration = writer.finish().unwrap().into_inner();
+
+        let mut writer = DatWriter::open(Cursor::new(old)).unwrap();
+        writer.insert_entry(entry, b"same iteration").unwrap();
+        let different_contents = writer.finish().unwrap().into_inner();
+
+        for (dat, reason) in [
+            (newer_iteration, "newer than"),
+            (different_contents, "contents don't match"),
+        ] {
+            let mut target = Cursor::new(dat.clone());
+
+            match patch.apply(&mut target) {
+                Err(DatError::PatchConflict {
+                    object_id: 0x06000005,
+                    reason: message,
+                }) => assert!(message.contains(reason), "{}", message),
+                result => panic!("Expected a conflict, got {:?}", result),
+            }
+
+            assert_eq!(target.into_inner(), dat);
+        }
+    }
+}
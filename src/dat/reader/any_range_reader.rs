@@ -0,0 +1,137 @@
+use std::path::PathBuf;
+
+use crate::dat::{
+    error::DatError,
+    reader::{
+        file_reader::FileRangeReader,
+        range_reader::{DynRangeReader, RangeReader},
+    },
+};
+
+#[cfg(feature = "http")]
+use crate::dat::reader::http_reader::HttpRangeReader;
+
+/// The file handle local DATs are read through
+#[cfg(feature = "tokio")]
+pub type LocalFile = tokio_util::compat::Compat<tokio::fs::File>;
+#[cfg(not(feature = "tokio"))]
+pub type LocalFile = futures::io::AllowStdIo<std::fs::File>;
+
+/// Where a DAT lives, as given by a path or URI
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatUri {
+    /// A plain path or a `file://` URI
+    File(PathBuf),
+    /// An `http://` or `https://` URL
+    Http(String),
+}
+
+impl DatUri {
+    pub fn parse(uri: &str) -> Result<DatUri, DatError> {
+        if uri.starts_with("http://") || uri.starts_with("https://") {
+            return Ok(DatUri::Http(uri.to_string()));
+        }
+
+        if let Some(path) = uri.strip_prefix("file://") {
+            return Ok(DatUri::File(PathBuf::from(path)));
+        }
+
+        // Anything else that looks like a URI is a scheme we don't support,
+        // rather than a relative path with "://" in it
+        if let Some((scheme, _)) = uri.split_once("://")
+            && !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        {
+            return Err(DatError::UnsupportedType(format!(
+                "URI scheme {}:// (expected a path, file://, http:// or https://)",
+                scheme
+            )));
+        }
+
+        Ok(DatUri::File(PathBuf::from(uri)))
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, DatUri::File(_))
+    }
+
+    /// The local path, for things (like writing or the sidecar index) that
+    /// only work on local DATs
+    pub fn local_path(&self) -> Option<&PathBuf> {
+        match self {
+            DatUri::File(path) => Some(path),
+            DatUri::Http(_) => None,
+        }
+    }
+}
+
+/// A RangeReader for any DatUri, so code can be written once against this
+/// and work the same on local and remote DATs
+pub enum AnyRangeReader {
+    File(FileRangeReader<LocalFile>),
+    #[cfg(feature = "http")]
+    Http(HttpRangeReader),
+}
+
+impl AnyRangeReader {
+    /// Open a DAT by path or URI, picking the backend from its scheme
+    pub async fn open(uri: &str) -> Result<AnyRangeReader, DatError> {
+        Self::open_uri(&DatUri::parse(uri)?).await
+    }
+
+    pub async fn open_uri(uri: &DatUri) -> Result<AnyRangeReader, DatError> {
+        match uri {
+            DatUri::File(path) => Ok(AnyRangeReader::File(FileRangeReader::new(
+                open_local_file(path).await?,
+            ))),
+            #[cfg(feature = "http")]
+            DatUri::Http(url) => Ok(AnyRangeReader::Http(HttpRangeReader::with_default_client(
+                url.clone(),
+            ))),
+            #[cfg(not(feature = "http"))]
+            DatUri::Http(url) => Err(DatError::UnsupportedType(format!(
+                "{} (reading over HTTP needs the http feature)",
+                url
+            ))),
+        }
+    }
+
+    /// Open a DAT by path or URI as a boxed DynRangeReader, for code that
+    /// picks between these and other backends (e.g. WorkerR2RangeReader) at
+    /// runtime
+    pub async fn open_dyn(uri: &str) -> Result<Box<dyn DynRangeReader>, DatError> {
+        Ok(Box::new(Self::open(uri).await?))
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn open_local_file(path: &PathBuf) -> Result<LocalFile, DatError> {
+    let file = tokio::fs::File::open(path).await?;
+
+    Ok(tokio_util::compat::TokioAsyncReadCompatExt::compat(file))
+}
+
+#[cfg(not(feature = "tokio"))]
+async fn open_local_file(path: &PathBuf) -> Result<LocalFile, DatError> {
+    Ok(futures::io::AllowStdIo::new(std::fs::File::open(path)?))
+}
+
+impl RangeReader for AnyRangeReader {
+    async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        match self {
+            AnyRangeReader::File(reader) => reader.read_range(offset, length).await,
+            #[cfg(feature = "http")]
+            AnyRangeReader::Http(reader) => reader.read_range(offset, length).await,
+        }
+    }
+
+    async fn read_ranges(&mut self, ranges: &[(u32, usize)]) -> Result<Vec<Vec<u8>>, DatError> {
+        match self {
+            AnyRangeReader::File(reader) => reader.read_ranges(ranges).await,
+            #[cfg(feature = "http")]
+            AnyRangeReader::Http(reader) => reader.read_ranges(ranges).await,
+        }
+    }
+}
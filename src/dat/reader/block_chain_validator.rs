@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use crate::dat::error::DatError;
+
+/// Checks each block of a chain as it's followed
+///
+/// A corrupted or truncated DAT can contain pointers that loop back on
+/// themselves, point past the end of the file or between blocks, or end
+/// early, and a garbage
+/// file size can ask for far more memory than the DAT could hold. Readers
+/// visit every block through a validator so these cases turn into a
+/// DatError::CorruptBlockChain naming the object and block instead.
+#[derive(Debug, Clone)]
+pub struct BlockChainValidator {
+    object_id: Option<u32>,
+    block_size: u32,
+    file_size: u32,
+    blocks: Vec<u32>,
+    visited: HashSet<u32>,
+}
+
+impl BlockChainValidator {
+    /// Create a validator for chains in a DAT with the given block size and
+    /// total size, as found in its DatDatabaseHeader
+    pub fn new(block_size: u32, file_size: u32) -> Self {
+        Self {
+            object_id: None,
+            block_size,
+            file_size,
+            blocks: Vec::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Name the object being read in any errors
+    pub fn for_object(mut self, object_id: u32) -> Self {
+        self.object_id = Some(object_id);
+        self
+    }
+
+    pub fn object_id(&self) -> Option<u32> {
+        self.object_id
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn file_size(&self) -> u32 {
+        self.file_size
+    }
+
+    /// Offsets of every block visited so far, in chain order
+    pub fn blocks(&self) -> &[u32] {
+        &self.blocks
+    }
+
+    /// Build a corruption error for the chain being validated
+    pub fn corrupt(&self, offset: u32, reason: String) -> DatError {
+        DatError::CorruptBlockChain {
+            object_id: self.object_id,
+            offset,
+            reason,
+        }
+    }
+
+    /// Check that a chain starting at `offset` holding `size` bytes could fit
+    /// in the DAT at all, before anything is allocated for it
+    pub fn check_size(&self, offset: u32, size: u32) -> Result<(), DatError> {
+        if self.block_size <= 4 {
+            return Err(self.corrupt(
+                offset,
+                format!(
+                    "Block size {} is too small to hold a pointer and data",
+                    self.block_size
+                ),
+            ));
+        }
+
+        let block_count = (size as u64).div_ceil(self.block_size as u64 - 4).max(1);
+
+        if block_count * self.block_size as u64 > self.file_size as u64 {
+            return Err(self.corrupt(
+                offset,
+                format!(
+                    "Size of {} bytes needs {} blocks, more than the DAT ({} bytes) can hold",
+                    size, block_count, self.file_size
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Record that the block at `offset` is about to be read, checking that
+    /// it's a valid place for a block with data still left to read
+    pub fn visit(&mut self, offset: u32) -> Result<(), DatError> {
+        if offset == 0 {
+            let previous = self.blocks.last().copied().unwrap_or(0);
+
+            return Err(self.corrupt(
+                previous,
+                "Chain ends with a zero pointer before all of its data was read".to_string(),
+            ));
+        }
+
+        if offset as u64 + self.block_size as u64 > self.file_size as u64 {
+            return Err(self.corrupt(
+                offset,
+                format!(
+                    "Block extends past the end of the DAT ({} bytes)",
+                    self.file_size
+                ),
+            ));
+        }
+
+        if !offset.is_multiple_of(self.block_size) {
+            return Err(self.corrupt(
+                offset,
+                format!("Block isn't on a {} byte boundary", self.block_size),
+            ));
+        }
+
+        if !self.visited.insert(offset) {
+            return Err(self.corrupt(
+                offset,
+                format!(
+                    "Chain loops back to a block it already visited (after {} blocks)",
+                    self.blocks.len()
+                ),
+            ));
+        }
+
+        self.blocks.push(offset);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A validator for a 4 KiB DAT of 1 KiB blocks
+    fn validator() -> BlockChainValidator {
+        BlockChainValidator::new(1024, 4096).for_object(0x06000001)
+    }
+
+    fn reason(result: Result<(), DatError>, expected_offset: u32) -> String {
+        match result {
+            Err(DatError::CorruptBlockChain {
+                object_id: Some(0x06000001),
+                offset,
+                reason,
+            }) if offset == expected_offset => reason,
+            other => panic!(
+                "Expected corruption at {}, got {:?}",
+                expected_offset, other
+            ),
+        }
+    }
+
+    #[test]
+    fn follows_a_valid_chain() {
+        let mut validator = validator();
+
+        for offset in [2048, 1024, 3072] {
+            validator.visit(offset).unwrap();
+        }
+
+        assert_eq!(validator.blocks(), [2048, 1024, 3072]);
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let mut validator = validator();
+        validator.visit(1024).unwrap();
+        validator.visit(2048).unwrap();
+
+        assert!(reason(validator.visit(1024), 1024).contains("loops back"));
+    }
+
+    #[test]
+    fn rejects_blocks_past_the_end() {
+        let mut validator = validator();
+
+        // The last block fits exactly; one starting inside it doesn't
+        validator.visit(3072).unwrap();
+        assert!(reason(validator.visit(4096), 4096).contains("past the end"));
+        assert!(reason(validator.visit(u32::MAX - 1023), u32::MAX - 1023).contains("past the end"));
+    }
+
+    #[test]
+    fn rejects_misaligned_blocks() {
+        let mut validator = validator();
+
+        assert!(reason(validator.visit(1030), 1030).contains("boundary"));
+        assert!(validator.blocks().is_empty());
+    }
+
+    #[test]
+    fn rejects_chains_that_end_early() {
+        let mut validator = validator();
+        validator.visit(1024).unwrap();
+        validator.visit(2048).unwrap();
+
+        // Reported at the last block, whose pointer is the zero
+        assert!(reason(validator.visit(0), 2048).contains("zero pointer"));
+    }
+
+    #[test]
+    fn rejects_sizes_the_dat_cannot_hold() {
+        let validator = validator();
+
+        // Four blocks of 1020 bytes fit, a fifth doesn't
+        validator.check_size(1024, 4 * 1020).unwrap();
+        assert!(reason(validator.check_size(1024, 4 * 1020 + 1), 1024).contains("more than"));
+        assert!(
+            reason(
+                BlockChainValidator::new(4, 4096)
+                    .for_object(0x06000001)
+                    .check_size(1024, 1),
+                1024
+            )
+            .contains("too small")
+        );
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::dat::{error::DatError, reader::range_reader::RangeReader};
+
+pub struct CachingRangeReaderOptions {
+    /// Size of each cached page in bytes. Reads are rounded out to whole pages.
+    pub page_size: usize,
+    /// Maximum number of pages kept in the cache before the least recently
+    /// used ones are evicted
+    pub capacity: usize,
+    /// Number of extra pages to fetch after the end of a read that missed
+    pub read_ahead: usize,
+    /// Total size of the underlying resource, if known. Used to avoid
+    /// requesting pages past the end of the file.
+    pub file_size: Option<u32>,
+}
+
+impl Default for CachingRangeReaderOptions {
+    fn default() -> Self {
+        CachingRangeReaderOptions {
+            page_size: 16 * 1024,
+            capacity: 1024,
+            read_ahead: 4,
+            file_size: None,
+        }
+    }
+}
+
+/// Counters for measuring how effective the cache is
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CachingRangeReaderStats {
+    /// Number of ranges read through the cache
+    pub reads: u64,
+    /// Number of ranges requested from the underlying reader, whether one
+    /// at a time or batched with read_ranges
+    pub requests: u64,
+    /// Total bytes requested from the underlying reader
+    pub bytes_fetched: u64,
+    /// Pages served from the cache
+    pub hits: u64,
+    /// Pages that had to be fetched
+    pub misses: u64,
+}
+
+struct CachedPage {
+    data: Vec<u8>,
+    last_used: u64,
+}
+
+/// A run of adjacent pages missing from the cache
+#[derive(Debug, Clone, Copy)]
+struct PageRun {
+    first_page: u64,
+    pages: u64,
+    /// Pages to fetch after the run as read-ahead
+    read_ahead: u64,
+    /// Whether the run reaches the end of the read it's for
+    reaches_end: bool,
+}
+
+/// RangeReader decorator adding an LRU page cache, read-ahead and coalescing
+///
+/// Every read is split into fixed-size pages. Pages already in the cache are
+/// served from memory and runs of adjacent missing pages are fetched with a
+/// single request to the underlying reader, extended by `read_ahead` pages so
+/// the next block in a chain is usually already cached. This turns the many
+/// small reads DatBlockReader and DatDirectory make into a handful of larger
+/// ones, which matters a lot for HTTP and R2.
+pub struct CachingRangeReader<R> {
+    inner: R,
+    options: CachingRangeReaderOptions,
+    pages: HashMap<u64, CachedPage>,
+    /// Cached pages by when they were last used, oldest first
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: CachingRangeReaderStats,
+}
+
+impl<R> CachingRangeReader<R>
+where
+    R: RangeReader,
+{
+    pub fn new(inner: R, options: CachingRangeReaderOptions) -> Self {
+        Self {
+            inner,
+            options: CachingRangeReaderOptions {
+                page_size: options.page_size.max(1),
+                capacity: options.capacity.max(1),
+                ..options
+            },
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: CachingRangeReaderStats::default(),
+        }
+    }
+
+    /// Convenience constructor using the default options
+    pub fn with_defaults(inner: R) -> Self {
+        Self::new(inner, CachingRangeReaderOptions::default())
+    }
+
+    /// Set the size of the underlying resource once it's known, e.g. from
+    /// DatDatabaseHeader::file_size
+    pub fn set_file_size(&mut self, file_size: u32) {
+        self.options.file_size = Some(file_size);
+    }
+
+    pub fn stats(&self) -> CachingRangeReaderStats {
+        self.stats
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn page_count_limit(&self) -> Option<u64> {
+        self.options
+            .file_size
+            .map(|size| (size as u64).div_ceil(self.options.page_size as u64))
+    }
+
+    /// The offset and length of a run of consecutive pages, stopping early
+    /// at the end of the file (if known) and at `clip_end` (an absolute
+    /// offset) if given
+    fn page_span(
+        &self,
+        first_page: u64,
+        page_count: u64,
+        clip_end: Option<u64>,
+    ) -> Result<(u32, usize), DatError> {
+        let page_size = self.options.page_size as u64;
+        let start = first_page * page_size;
+        let mut end = start + page_count * page_size;
+
+        if let Some(file_size) = self.options.file_size {
+            end = end.min(file_size as u64);
+        }
+
+        if let Some(clip_end) = clip_end {
+            end = end.min(clip_end);
+        }
+
+        let offset = u32::try_from(start).map_err(|_| DatError::OutOfBounds {
+            offset: start,
+            length: end - start,
+            size: u32::MAX as u64,
+        })?;
+
+        Ok((offset, end.saturating_sub(start) as usize))
+    }
+
+    /// Fetch a run of consecutive pages in one request and add them to the
+    /// cache
+    async fn fetch_pages(
+        &mut self,
+        first_page: u64,
+        page_count: u64,
+        clip_end: Option<u64>,
+    ) -> Result<(), DatError> {
+        let (offset, length) = self.page_span(first_page, page_count, clip_end)?;
+        self.stats.requests += 1;
+        self.stats.bytes_fetched += length as u64;
+        let data = self.inner.read_range(offset, length).await?;
+        self.insert_pages(first_page, &data);
+
+        Ok(())
+    }
+
+    /// Fetch a missing run along with its read-ahead
+    async fn fetch_run(&mut self, run: PageRun, end: u64) -> Result<(), DatError> {
+        let result = self
+            .fetch_pages(run.first_page, run.pages + run.read_ahead, None)
+            .await;
+
+        // Whole pages can run past the end of the file when its size isn't
+        // known, so retry without read-ahead, stopping where the read ends
+        if result.is_err() && self.options.file_size.is_none() {
+            let clip_end = run.reaches_end.then_some(end);
+            self.fetch_pages(run.first_page, run.pages, clip_end).await
+        } else {
+            result
+        }
+    }
+
+    fn insert_pages(&mut self, first_page: u64, data: &[u8]) {
+        for (i, chunk) in data.chunks(self.options.page_size).enumerate() {
+            let page = first_page + i as u64;
+            self.tick += 1;
+
+            let cached = CachedPage {
+                data: chunk.to_vec(),
+                last_used: self.tick,
+            };
+
+            if let Some(replaced) = self.pages.insert(page, cached) {
+                self.lru.remove(&replaced.last_used);
+            }
+
+            self.lru.insert(self.tick, page);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.options.capacity {
+            let Some((_, page)) = self.lru.pop_first() else {
+                break;
+            };
+
+            self.pages.remove(&page);
+        }
+    }
+
+    /// Whether the cached copy of a page covers everything up to `end`
+    ///
+    /// Pages can be shorter than page_size when they were fetched up to the
+    /// end of a read rather than in full.
+    fn is_cached(&self, page: u64, end: u64) -> bool {
+        let page_size = self.options.page_size as u64;
+        let needed = (end - page * page_size).min(page_size) as usize;
+
+        self.pages
+            .get(&page)
+            .is_some_and(|cached| cached.data.len() >= needed)
+    }
+
+    /// Group the pages of a read that aren't cached into runs of adjacent
+    /// pages, so each run can be fetched with a single request, and count
+    /// the hits and misses
+    fn missing_runs(&mut self, start: u64, end: u64) -> Vec<PageRun> {
+        let page_size = self.options.page_size as u64;
+        let first_page = start / page_size;
+        let last_page = (end - 1) / page_size;
+        let mut runs: Vec<PageRun> = Vec::new();
+
+        for page in first_page..=last_page {
+            if self.is_cached(page, end) {
+                self.stats.hits += 1;
+                continue;
+            }
+
+            self.stats.misses += 1;
+
+            match runs.last_mut() {
+                Some(run) if run.first_page + run.pages == page => run.pages += 1,
+                _ => runs.push(PageRun {
+                    first_page: page,
+                    pages: 1,
+                    read_ahead: 0,
+                    reaches_end: false,
+                }),
+            }
+        }
+
+        let page_limit = self.page_count_limit();
+
+        // Extend a run that reaches the end of the read with read-ahead
+        // pages, stopping at anything already cached or past the end
+        if let Some(run) = runs.last_mut()
+            && run.first_page + run.pages == last_page + 1
+        {
+            run.reaches_end = true;
+
+            while run.read_ahead < self.options.read_ahead as u64 {
+                let page = last_page + 1 + run.read_ahead;
+
+                if page_limit.is_some_and(|limit| page >= limit) || self.pages.contains_key(&page) {
+                    break;
+                }
+
+                run.read_ahead += 1;
+            }
+        }
+
+        runs
+    }
+
+    /// Copy a read out of the cache, marking its pages as used
+    fn assemble(&mut self, start: u64, length: usize) -> Result<Vec<u8>, DatError> {
+        let page_size = self.options.page_size as u64;
+        let end = start + length as u64;
+        let mut buffer = Vec::with_capacity(length);
+
+        for page in start / page_size..=(end - 1) / page_size {
+            self.tick += 1;
+            let cached = self.pages.get_mut(&page).ok_or_else(|| {
+                DatError::InvalidData("Page missing from cache after fetch".to_string())
+            })?;
+            self.lru.remove(&cached.last_used);
+            cached.last_used = self.tick;
+            self.lru.insert(self.tick, page);
+
+            let page_start = page * page_size;
+            let from = start.saturating_sub(page_start) as usize;
+            let to = ((end - page_start) as usize).min(cached.data.len());
+
+            if from >= to {
+                return Err(DatError::OutOfBounds {
+                    offset: start,
+                    length: length as u64,
+                    size: page_start + cached.data.len() as u64,
+                });
+            }
+
+            buffer.extend_from_slice(&cached.data[from..to]);
+        }
+
+        if buffer.len() != length {
+            return Err(DatError::OutOfBounds {
+                offset: start,
+                length: length as u64,
+                size: start + buffer.len() as u64,
+            });
+        }
+
+        Ok(buffer)
+    }
+}
+
+impl<R> RangeReader for CachingRangeReader<R>
+where
+    R: RangeReader,
+{
+    async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        self.stats.reads += 1;
+
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        let start = offset as u64;
+        let end = start + length as u64;
+
+        for run in self.missing_runs(start, end) {
+            self.fetch_run(run, end).await?;
+        }
+
+        let result = self.assemble(start, length);
+        self.evict();
+
+        result
+    }
+
+    /// Read several ranges, fetching every page missing from any of them
+    /// with one call to the underlying reader's read_ranges
+    async fn read_ranges(&mut self, ranges: &[(u32, usize)]) -> Result<Vec<Vec<u8>>, DatError> {
+        self.stats.reads += ranges.len() as u64;
+
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+
+        for &(offset, length) in ranges.iter().filter(|(_, length)| *length > 0) {
+            let start = offset as u64;
+
+            for run in self.missing_runs(start, start + length as u64) {
+                runs.push((run.first_page, run.first_page + run.pages + run.read_ahead));
+            }
+        }
+
+        // Runs from different reads can overlap or touch, so they're merged
+        // before being turned into requests
+        runs.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+
+        for (first, end) in runs {
+            match merged.last_mut() {
+                Some((_, merged_end)) if first <= *merged_end => {
+                    *merged_end = (*merged_end).max(end)
+                }
+                _ => merged.push((first, end)),
+            }
+        }
+
+        if !merged.is_empty() {
+            let spans = merged
+                .iter()
+                .map(|&(first, end)| self.page_span(first, end - first, None))
+                .collect::<Result<Vec<_>, _>>()?;
+            self.stats.requests += spans.len() as u64;
+            self.stats.bytes_fetched += spans.iter().map(|&(_, length)| length as u64).sum::<u64>();
+
+            match self.inner.read_ranges(&spans).await {
+                Ok(data) => {
+                    for ((first, _), data) in merged.iter().zip(data) {
+                        self.insert_pages(*first, &data);
+                    }
+                }
+                Err(e) if self.options.file_size.is_some() => return Err(e),
+                // Whole pages can run past the end of the file when its size
+                // isn't known, so fetch each read's pages on its own, where
+                // they can be clipped to the end of the read
+                Err(_) => {
+                    // The pages were already counted the first time round
+                    let counted = (self.stats.hits, self.stats.misses);
+
+                    for &(offset, length) in ranges.iter().filter(|(_, length)| *length > 0) {
+                        let start = offset as u64;
+                        let end = start + length as u64;
+
+                        for run in self.missing_runs(start, end) {
+                            self.fetch_run(run, end).await?;
+                        }
+                    }
+
+                    (self.stats.hits, self.stats.misses) = counted;
+                }
+            }
+        }
+
+        let mut results = Vec::with_capacity(ranges.len());
+
+        for &(offset, length) in ranges {
+            if length == 0 {
+                results.push(Vec::new());
+                continue;
+            }
+
+            match self.assemble(offset as u64, length) {
+                Ok(data) => results.push(data),
+                Err(e) => {
+                    self.evict();
+                    return Err(e);
+                }
+            }
+        }
+
+        self.evict();
+
+        Ok(results)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+
+    const FILE_SIZE: usize = 10_000;
+
+    /// A RangeReader over an in-memory file that records every range asked
+    /// of it, failing reads past the end the way an HTTP server does
+    struct CountingReader {
+        data: Vec<u8>,
+        requests: Vec<(u32, usize)>,
+        batches: usize,
+    }
+
+    impl CountingReader {
+        fn new() -> Self {
+            Self {
+                data: (0..FILE_SIZE).map(|i| (i % 251) as u8).collect(),
+                requests: Vec::new(),
+                batches: 0,
+            }
+        }
+
+        fn read(&self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+            let start = offset as usize;
+
+            self.data
+                .get(start..start + length)
+                .map(|data| data.to_vec())
+                .ok_or(DatError::OutOfBounds {
+                    offset: offset as u64,
+                    length: length as u64,
+                    size: self.data.len() as u64,
+                })
+        }
+    }
+
+    impl RangeReader for CountingReader {
+        async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+            self.requests.push((offset, length));
+            self.read(offset, length)
+        }
+
+        async fn read_ranges(&mut self, ranges: &[(u32, usize)]) -> Result<Vec<Vec<u8>>, DatError> {
+            self.batches += 1;
+            self.requests.extend_from_slice(ranges);
+
+            ranges
+                .iter()
+                .map(|&(offset, length)| self.read(offset, length))
+                .collect()
+        }
+    }
+
+    fn cache(
+        capacity: usize,
+        read_ahead: usize,
+        file_size: Option<u32>,
+    ) -> CachingRangeReader<CountingReader> {
+        CachingRangeReader::new(
+            CountingReader::new(),
+            CachingRangeReaderOptions {
+                page_size: 1024,
+                capacity,
+                read_ahead,
+                file_size,
+            },
+        )
+    }
+
+    fn expected(offset: u32, length: usize) -> Vec<u8> {
+        CountingReader::new().read(offset, length).unwrap()
+    }
+
+    #[tokio::test]
+    async fn coalesces_adjacent_missing_pages() {
+        let mut reader = cache(64, 0, None);
+
+        reader.read_range(0, 10).await.unwrap();
+        reader.read_range(2048, 10).await.unwrap();
+        reader.inner.requests.clear();
+
+        // Pages 0 and 2 are cached, so 1 and 3-4 are fetched as two runs
+        let data = reader.read_range(0, 5 * 1024).await.unwrap();
+
+        assert_eq!(data, expected(0, 5 * 1024));
+        assert_eq!(reader.inner.requests, vec![(1024, 1024), (3072, 2048)]);
+        assert_eq!(reader.stats().hits, 2);
+    }
+
+    #[tokio::test]
+    async fn reads_ahead_up_to_the_end_of_the_file() {
+        let mut reader = cache(64, 2, Some(FILE_SIZE as u32));
+
+        reader.read_range(0, 10).await.unwrap();
+        assert_eq!(
+            reader.read_range(2048, 10).await.unwrap(),
+            expected(2048, 10)
+        );
+        assert_eq!(reader.inner.requests, vec![(0, 3072)]);
+
+        // The last page is short, and nothing past it is asked for
+        reader.inner.requests.clear();
+        assert_eq!(
+            reader.read_range(9216, 100).await.unwrap(),
+            expected(9216, 100)
+        );
+        assert_eq!(reader.inner.requests, vec![(9216, 784)]);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_page() {
+        let mut reader = cache(2, 0, None);
+
+        reader.read_range(0, 10).await.unwrap();
+        reader.read_range(1024, 10).await.unwrap();
+        reader.read_range(0, 10).await.unwrap();
+        reader.read_range(2048, 10).await.unwrap();
+        reader.inner.requests.clear();
+
+        reader.read_range(0, 10).await.unwrap();
+        assert!(reader.inner.requests.is_empty());
+
+        reader.read_range(1024, 10).await.unwrap();
+        assert_eq!(reader.inner.requests, vec![(1024, 1024)]);
+
+        assert_eq!(reader.pages.len(), 2);
+        assert_eq!(reader.lru.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn clamps_to_the_end_of_a_file_of_unknown_size() {
+        let mut reader = cache(64, 4, None);
+
+        assert_eq!(
+            reader.read_range(9500, 500).await.unwrap(),
+            expected(9500, 500)
+        );
+        assert_eq!(reader.inner.requests, vec![(9216, 5 * 1024), (9216, 784)]);
+
+        assert!(matches!(
+            reader.read_range(9990, 20).await,
+            Err(DatError::OutOfBounds { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn batches_read_ranges_into_one_call() {
+        let mut reader = cache(64, 0, None);
+
+        reader.read_range(1024, 10).await.unwrap();
+        reader.inner.requests.clear();
+
+        let ranges = [(0, 10), (1029, 10), (3000, 2000), (0, 0), (2100, 10)];
+        let data = reader.read_ranges(&ranges).await.unwrap();
+
+        for (&(offset, length), data) in ranges.iter().zip(&data) {
+            assert_eq!(*data, expected(offset, length));
+        }
+
+        assert_eq!(reader.inner.batches, 1);
+        assert_eq!(reader.inner.requests, vec![(0, 1024), (2048, 3072)]);
+    }
+}
@@ -1,83 +1,108 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::{
-    error::Error,
-    io::{Cursor, Read, Seek, SeekFrom, Write},
-};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
-use crate::dat::reader::range_reader::RangeReader;
+use crate::dat::{
+    error::DatError,
+    reader::{block_chain_validator::BlockChainValidator, range_reader::RangeReader},
+};
 
 #[derive(Debug)]
 pub struct DatBlockReader {}
 
 impl DatBlockReader {
+    /// Read `size` bytes stored in the block chain starting at `offset`
+    ///
+    /// Every block is checked by the validator, which also records the
+    /// offsets of the blocks that made up the chain.
     pub fn read<R: Read + Seek>(
         reader: &mut R,
         offset: u32,
         size: u32,
-        block_size: u32,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        reader.seek(SeekFrom::Start(offset as u64))?;
+        validator: &mut BlockChainValidator,
+    ) -> Result<Vec<u8>, DatError> {
+        validator.check_size(offset, size)?;
+        let data_size = validator.block_size() - 4;
 
         let mut buffer = vec![0; size as usize];
         let mut writer = Cursor::new(&mut buffer);
         let mut left_to_read = size;
-        let mut next_address = reader.read_u32::<LittleEndian>()?;
+        let mut current_offset = offset;
 
         while left_to_read > 0 {
-            if left_to_read < block_size {
-                let mut data: Vec<u8> = vec![0; left_to_read as usize];
-                reader.read_exact(&mut data)?;
-                writer.write_all(&data)?;
-                break;
-            } else {
-                let mut data: Vec<u8> = vec![0; (block_size as usize) - 4];
-                reader.read_exact(&mut data)?;
-                writer.write_all(&data)?;
-                reader.seek(SeekFrom::Start(next_address as u64))?;
-                next_address = reader.read_u32::<LittleEndian>()?;
-                left_to_read -= block_size - 4;
-            }
+            validator.visit(current_offset)?;
+            reader.seek(SeekFrom::Start(current_offset as u64))?;
+            let next_address = reader.read_u32::<LittleEndian>()?;
+
+            let read_size = std::cmp::min(left_to_read, data_size);
+            let mut data: Vec<u8> = vec![0; read_size as usize];
+            reader.read_exact(&mut data)?;
+            writer.write_all(&data)?;
+
+            left_to_read -= read_size;
+            current_offset = next_address;
         }
+
         Ok(buffer)
     }
 
+    /// Follow the block chain starting at `offset` holding `size` bytes
+    /// without reading its data, leaving the blocks in the validator
+    ///
+    /// Only the pointer at the start of each block is read. An empty chain
+    /// still has a block, as that's what DatWriter gives empty files.
+    pub fn walk<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        size: u32,
+        validator: &mut BlockChainValidator,
+    ) -> Result<(), DatError> {
+        validator.check_size(offset, size)?;
+
+        let block_count = size.div_ceil(validator.block_size() - 4).max(1);
+        let mut current_offset = offset;
+
+        for _ in 0..block_count {
+            validator.visit(current_offset)?;
+            reader.seek(SeekFrom::Start(current_offset as u64))?;
+            current_offset = reader.read_u32::<LittleEndian>()?;
+        }
+
+        Ok(())
+    }
+
     pub async fn read_async<R: RangeReader>(
         reader: &mut R,
         offset: u32,
         size: u32,
-        block_size: u32,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        validator: &mut BlockChainValidator,
+    ) -> Result<Vec<u8>, DatError> {
+        validator.check_size(offset, size)?;
+        let data_size = validator.block_size() - 4;
+
         let mut buffer = vec![0; size as usize];
         let mut writer = Cursor::new(&mut buffer);
         let mut left_to_read = size;
-        
-        // Read the first 4 bytes to get the next address
-        let first_chunk = reader.read_range(offset, 4).await?;
-        let mut cursor = Cursor::new(first_chunk);
-        let mut next_address = cursor.read_u32::<LittleEndian>()?;
         let mut current_offset = offset;
 
         while left_to_read > 0 {
-            if left_to_read < block_size {
-                // Read the remaining data
-                let data = reader.read_range(current_offset + 4, left_to_read as usize).await?;
-                writer.write_all(&data)?;
-                break;
-            } else {
-                // Read the data part of this block (excluding the 4-byte pointer)
-                let data_size = (block_size as usize) - 4;
-                let data = reader.read_range(current_offset + 4, data_size).await?;
-                writer.write_all(&data)?;
-                
-                // Move to the next block and read its pointer
-                current_offset = next_address;
-                let next_chunk = reader.read_range(current_offset, 4).await?;
-                let mut cursor = Cursor::new(next_chunk);
-                next_address = cursor.read_u32::<LittleEndian>()?;
-                
-                left_to_read -= block_size - 4;
-            }
+            validator.visit(current_offset)?;
+
+            // Read the pointer to the next block along with this block's data
+            let read_size = std::cmp::min(left_to_read, data_size);
+            let block = reader
+                .read_range(current_offset, read_size as usize + 4)
+                .await?;
+            let mut cursor = Cursor::new(block);
+            let next_address = cursor.read_u32::<LittleEndian>()?;
+
+            let mut data: Vec<u8> = vec![0; read_size as usize];
+            cursor.read_exact(&mut data)?;
+            writer.write_all(&data)?;
+
+            left_to_read -= read_size;
+            current_offset = next_address;
         }
+
         Ok(buffer)
     }
 }
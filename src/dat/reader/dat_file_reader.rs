@@ -1,4 +1,20 @@
-use crate::dat::reader::{range_reader::RangeReader, types::dat_block::DatBlock};
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        range_reader::RangeReader,
+        types::{
+            dat_block::DatBlock, dat_database_header::DatDatabaseHeader,
+            dat_directory_entry::DatDirectoryEntry, dat_entry_flags::decompress,
+        },
+    },
+};
+
+/// Outcome of a speculative read of contiguous blocks
+struct DatBlockRun {
+    /// Where the chain continues when it didn't follow the predicted layout
+    next_block_offset: Option<u32>,
+}
 
 /// The main API entry point for reading block-based files
 #[derive(Debug)]
@@ -6,33 +22,95 @@ pub struct DatFileReader {
     pub size: usize,
     pub block_size: usize,
     pub left_to_read: usize,
+    /// Decompress what's read (see DatDirectoryEntry::decode)
+    pub compressed: bool,
+    validator: BlockChainValidator,
 }
 
 impl DatFileReader {
-    pub fn new(size: usize, block_size: usize) -> Result<Self, Box<dyn std::error::Error>> {
-        if block_size < 4 {
-            return Err("block_size must be at least 4 bytes (for the pointer)".into());
+    /// Create a reader for a file of `size` bytes in a DAT with the given
+    /// block size and total size (both from its DatDatabaseHeader)
+    pub fn new(size: usize, block_size: usize, file_size: u32) -> Result<Self, DatError> {
+        if block_size <= 4 {
+            return Err(DatError::InvalidData(
+                "block_size must be more than 4 bytes (for the pointer)".to_string(),
+            ));
         }
         Ok(Self {
             size,
             block_size,
             left_to_read: size,
+            compressed: false,
+            validator: BlockChainValidator::new(block_size as u32, file_size),
         })
     }
 
+    /// Create a reader for the file described by a directory entry, which
+    /// decompresses the file if the entry says it's compressed
+    pub fn from_entry(
+        header: &DatDatabaseHeader,
+        entry: &DatDirectoryEntry,
+    ) -> Result<Self, DatError> {
+        let mut reader = Self::new(
+            entry.file_size as usize,
+            header.block_size as usize,
+            header.file_size,
+        )?
+        .for_object(entry.object_id);
+        reader.compressed = entry.is_compressed();
+
+        Ok(reader)
+    }
+
+    /// Name the object being read in any corruption errors
+    pub fn for_object(mut self, object_id: u32) -> Self {
+        self.validator = self.validator.for_object(object_id);
+        self
+    }
+
+    /// The validator tracking the blocks read so far
+    pub fn validator(&self) -> &BlockChainValidator {
+        &self.validator
+    }
+
     /// Read a file starting at the given offset for the specified total size
+    ///
+    /// Blocks are usually laid out back to back, so rather than discovering
+    /// the chain one block at a time the reader first fetches the whole run it
+    /// predicts in a single range and then checks each block's pointer against
+    /// the prediction. Where the chain jumps elsewhere, it speculates again from
+    /// the new offset, and once a speculative read fails (e.g. by running past
+    /// the end of the file) it falls back to reading one block at a time.
     pub async fn read_file<R>(
         &mut self,
         reader: &mut R,
         start_offset: u32,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+    ) -> Result<Vec<u8>, DatError>
     where
         R: RangeReader,
     {
+        let size = u32::try_from(self.size).map_err(|_| {
+            DatError::InvalidData(format!("File size {} is too large for a DAT", self.size))
+        })?;
+        self.validator.check_size(start_offset, size)?;
+
         let mut buffer = Vec::with_capacity(self.size);
         let mut next_address = start_offset;
+        let mut speculate = true;
 
         while self.left_to_read > 0 {
+            if speculate {
+                match self.read_run(reader, next_address, &mut buffer).await? {
+                    Some(run) => {
+                        if let Some(next_block_offset) = run.next_block_offset {
+                            next_address = next_block_offset;
+                        }
+                        continue;
+                    }
+                    None => speculate = false,
+                }
+            }
+
             let block = self.read_block(reader, next_address).await?;
             buffer.extend_from_slice(&block.data);
 
@@ -41,42 +119,257 @@ impl DatFileReader {
             }
         }
 
+        if self.compressed {
+            return decompress(&buffer, self.validator.object_id().unwrap_or_default());
+        }
+
         Ok(buffer)
     }
 
-    /// Read a single block from the given offset
+    /// Speculatively read the rest of the file as if it were contiguous from
+    /// the given offset
     ///
-    /// A block is [ next_offset, data ]. For the last block, the next_offset
-    /// is 0
-    async fn read_block<R>(
+    /// Blocks are consumed for as long as their pointers match the predicted
+    /// layout. Nothing is consumed, and None is returned, if the run reaches
+    /// past the end of the file or comes back any other length than asked
+    /// for. Any other error (e.g. a
+    /// network error the reader has already retried) is returned as-is,
+    /// since reading block by block would only fail the same way.
+    async fn read_run<R>(
         &mut self,
         reader: &mut R,
         offset: u32,
-    ) -> Result<DatBlock, Box<dyn std::error::Error>>
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<DatBlockRun>, DatError>
+    where
+        R: RangeReader,
+    {
+        let data_size = self.block_size - 4;
+        let block_count = self.left_to_read.div_ceil(data_size);
+        let run_size = (block_count - 1) * self.block_size
+            + (self.left_to_read - (block_count - 1) * data_size)
+            + 4;
+
+        // Validate the first block up front so a bad pointer is reported as
+        // corruption rather than treated as a failed speculative read
+        let mut validator = self.validator.clone();
+        validator.visit(offset)?;
+
+        let run_data = match reader.read_range(offset, run_size).await {
+            Ok(run_data) if run_data.len() == run_size => run_data,
+            Ok(_) | Err(DatError::OutOfBounds { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        self.validator = validator;
+
+        for (i, block) in run_data.chunks(self.block_size).enumerate() {
+            if i > 0 {
+                self.validator
+                    .visit(offset + (i * self.block_size) as u32)?;
+            }
+
+            let next_block_offset = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+            let data = &block[4..];
+
+            buffer.extend_from_slice(data);
+            self.left_to_read -= data.len();
+
+            if self.left_to_read == 0 {
+                break;
+            }
+
+            let predicted = offset as usize + (i + 1) * self.block_size;
+
+            if next_block_offset as usize != predicted {
+                return Ok(Some(DatBlockRun {
+                    next_block_offset: Some(next_block_offset),
+                }));
+            }
+        }
+
+        Ok(Some(DatBlockRun {
+            next_block_offset: None,
+        }))
+    }
+
+    /// Read a single block from the given offset
+    ///
+    /// A block is [ next_offset, data ]. For the last block, the next_offset
+    /// is 0
+    async fn read_block<R>(&mut self, reader: &mut R, offset: u32) -> Result<DatBlock, DatError>
     where
         R: RangeReader,
     {
         // Determine the size of the next read. This is either an entire block
         // when we have more than a block worth of data to read or whatever is
         // left to read (+ 4 bytes for the pointer).
+        self.validator.visit(offset)?;
+
         let next_read_size = std::cmp::min(self.block_size, self.left_to_read + 4);
         self.left_to_read -= next_read_size - 4;
 
         // Dispatch the read to the underlying read implementation
         let block_data = reader.read_range(offset, next_read_size).await?;
 
-        if block_data.len() < 4 {
-            return Err("Block too small to contain pointer".into());
+        if block_data.len() != next_read_size {
+            return Err(self.validator.corrupt(
+                offset,
+                format!(
+                    "Read {} bytes of a {} byte block",
+                    block_data.len(),
+                    next_read_size
+                ),
+            ));
         }
 
         // Create and return the DatBlock
-        let next_offset_bytes: [u8; 4] = block_data[0..4].try_into()?;
-        let next_block_offset = u32::from_le_bytes(next_offset_bytes);
+        let next_block_offset =
+            u32::from_le_bytes([block_data[0], block_data[1], block_data[2], block_data[3]]);
         let data: Vec<u8> = block_data[4..].to_vec();
 
         Ok(DatBlock {
-            next_block_offset: next_block_offset,
+            next_block_offset,
             data,
         })
     }
 }
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::reader::sync_file_reader::SyncFileRangeReader;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// A 256 byte DAT holding one 180 byte file in blocks 192, 64 and 128
+    fn fragmented_dat() -> (Vec<u8>, Vec<u8>) {
+        let contents: Vec<u8> = (0..180).map(|i| i as u8).collect();
+        let mut dat = vec![0u8; 4 * BLOCK_SIZE];
+
+        for (i, (offset, next)) in [(192, 64u32), (64, 128), (128, 0)].into_iter().enumerate() {
+            dat[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+            dat[offset + 4..offset + BLOCK_SIZE].copy_from_slice(&contents[i * 60..(i + 1) * 60]);
+        }
+
+        (dat, contents)
+    }
+
+    /// A 384 byte DAT holding one 240 byte file in blocks 64, 192, 256 and
+    /// 320, so only the first block is out of place
+    fn jumping_dat() -> (Vec<u8>, Vec<u8>) {
+        let contents: Vec<u8> = (0..240).map(|i| i as u8).collect();
+        let mut dat = vec![0u8; 6 * BLOCK_SIZE];
+        let chain = [(64, 192u32), (192, 256), (256, 320), (320, 0)];
+
+        for (i, (offset, next)) in chain.into_iter().enumerate() {
+            dat[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+            dat[offset + 4..offset + BLOCK_SIZE].copy_from_slice(&contents[i * 60..(i + 1) * 60]);
+        }
+
+        (dat, contents)
+    }
+
+    /// Counts reads, optionally returning too much for anything longer than
+    /// a block
+    struct RecordingReader {
+        inner: SyncFileRangeReader<Cursor<Vec<u8>>>,
+        reads: usize,
+        pad_runs: bool,
+    }
+
+    impl RangeReader for RecordingReader {
+        async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+            self.reads += 1;
+            let mut data = RangeReader::read_range(&mut self.inner, offset, length).await?;
+
+            if self.pad_runs && length > BLOCK_SIZE {
+                data.extend_from_slice(&[0xAA; 8]);
+            }
+
+            Ok(data)
+        }
+    }
+
+    /// Fails every read with a network error, counting the attempts
+    struct FailingReader {
+        reads: usize,
+    }
+
+    impl RangeReader for FailingReader {
+        async fn read_range(&mut self, _offset: u32, _length: usize) -> Result<Vec<u8>, DatError> {
+            self.reads += 1;
+            Err(DatError::Http("connection reset".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_blocks_when_a_run_passes_the_end() {
+        let (dat, contents) = fragmented_dat();
+        let mut reader = SyncFileRangeReader::new(Cursor::new(dat));
+
+        let data = DatFileReader::new(contents.len(), BLOCK_SIZE, 256)
+            .unwrap()
+            .read_file(&mut reader, 192)
+            .await
+            .unwrap();
+
+        assert_eq!(data, contents);
+    }
+
+    #[tokio::test]
+    async fn other_read_errors_are_not_retried_block_by_block() {
+        let mut reader = FailingReader { reads: 0 };
+
+        let result = DatFileReader::new(180, BLOCK_SIZE, 256)
+            .unwrap()
+            .read_file(&mut reader, 192)
+            .await;
+
+        assert!(matches!(result, Err(DatError::Http(_))));
+        assert_eq!(reader.reads, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_speculating_after_a_one_block_run() {
+        let (dat, contents) = jumping_dat();
+        let mut reader = RecordingReader {
+            inner: SyncFileRangeReader::new(Cursor::new(dat)),
+            reads: 0,
+            pad_runs: false,
+        };
+
+        let data = DatFileReader::new(contents.len(), BLOCK_SIZE, 384)
+            .unwrap()
+            .read_file(&mut reader, 64)
+            .await
+            .unwrap();
+
+        assert_eq!(data, contents);
+        // The run from 64 stops after one block, and the one from 192 gets
+        // the rest
+        assert_eq!(reader.reads, 2);
+    }
+
+    #[tokio::test]
+    async fn over_long_runs_are_a_failed_speculation() {
+        let (dat, contents) = jumping_dat();
+        let mut reader = RecordingReader {
+            inner: SyncFileRangeReader::new(Cursor::new(dat)),
+            reads: 0,
+            pad_runs: true,
+        };
+
+        let data = DatFileReader::new(contents.len(), BLOCK_SIZE, 384)
+            .unwrap()
+            .read_file(&mut reader, 64)
+            .await
+            .unwrap();
+
+        assert_eq!(data, contents);
+        // The padded run, then each of the four blocks
+        assert_eq!(reader.reads, 5);
+    }
+}
@@ -0,0 +1,704 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
+use strum::Display;
+
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        range_reader::RangeReader,
+        types::{
+            dat_database_header::{DAT_HEADER_OFFSET, DAT_HEADER_SIZE, DatDatabaseHeader},
+            dat_directory::DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+            dat_directory_header::DatDirectoryHeader,
+        },
+    },
+};
+
+/// The kind of problem a VerifyIssue describes
+///
+/// These print in snake_case so they can be matched on in scripts.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[strum(serialize_all = "snake_case")]
+pub enum VerifyIssueKind {
+    /// The header itself is unusable (e.g. a bad block size)
+    Header,
+    /// The DAT is shorter than its header says it is
+    Truncated,
+    /// A directory node couldn't be read
+    Directory,
+    /// A directory node's entries are out of order or outside of the key
+    /// range its parent allows
+    EntryOrder,
+    /// A directory node's branches don't match its entry count
+    BranchCount,
+    /// Leaves of the B-tree are at different depths
+    TreeDepth,
+    /// A file's block chain couldn't be followed
+    BlockChain,
+    /// The free list couldn't be followed or doesn't end at free_tail
+    FreeList,
+    /// The free list's length doesn't match free_count
+    FreeCount,
+    /// A block doesn't start on a multiple of the block size
+    Misaligned,
+    /// A block is used by more than one chain (or is both used and free)
+    DoubleAllocated,
+    /// A run of blocks isn't used by anything and isn't on the free list
+    Orphaned,
+}
+
+/// A single problem found while verifying a DAT
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerifyIssue {
+    pub kind: VerifyIssueKind,
+    /// Offset of the block (or directory node) the issue was found at
+    pub offset: u32,
+    /// The file involved, if any
+    pub object_id: Option<u32>,
+    pub detail: String,
+}
+
+/// What a block in the DAT is used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum BlockOwner {
+    Directory(u32),
+    File(u32),
+    Free,
+}
+
+impl std::fmt::Display for BlockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockOwner::Directory(offset) => write!(f, "directory node at {}", offset),
+            BlockOwner::File(object_id) => write!(f, "file {:08X}", object_id),
+            BlockOwner::Free => write!(f, "the free list"),
+        }
+    }
+}
+
+/// The outcome of verifying a DAT
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerifyReport {
+    pub files: usize,
+    pub directories: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Full consistency check of a DAT
+///
+/// Walks the B-tree, every file's block chain and the free list, recording
+/// which blocks each of them uses, and then looks for blocks used twice or
+/// not at all. Problems are collected into a VerifyReport rather than
+/// stopping at the first one; only failing to read the header is an error.
+///
+/// Chains are followed by reading just the pointer at the start of each
+/// block, so wrapping the reader in a CachingRangeReader helps a lot.
+pub struct DatVerifier<'a, R> {
+    reader: &'a mut R,
+    header: DatDatabaseHeader,
+    owners: HashMap<u32, BlockOwner>,
+    report: VerifyReport,
+}
+
+impl<'a, R: RangeReader> DatVerifier<'a, R> {
+    pub async fn verify(reader: &'a mut R) -> Result<VerifyReport, DatError> {
+        let header = DatDatabaseHeader::read_async(reader).await?;
+
+        let mut verifier = DatVerifier {
+            reader,
+            header,
+            owners: HashMap::new(),
+            report: VerifyReport::default(),
+        };
+
+        if verifier.check_header().await {
+            verifier.check_tree().await;
+            verifier.check_free_list().await;
+            verifier.check_orphans();
+        }
+
+        verifier.report.used_blocks = verifier
+            .owners
+            .values()
+            .filter(|owner| **owner != BlockOwner::Free)
+            .count();
+
+        Ok(verifier.report)
+    }
+
+    fn issue(
+        &mut self,
+        kind: VerifyIssueKind,
+        offset: u32,
+        object_id: Option<u32>,
+        detail: String,
+    ) {
+        self.report.issues.push(VerifyIssue {
+            kind,
+            offset,
+            object_id,
+            detail,
+        });
+    }
+
+    /// Turn an error from following the chain starting at `offset` into an
+    /// issue, using the block the error names when there is one
+    fn chain_issue(
+        &mut self,
+        kind: VerifyIssueKind,
+        offset: u32,
+        object_id: Option<u32>,
+        error: DatError,
+    ) {
+        match error {
+            DatError::CorruptBlockChain { offset, reason, .. } => {
+                self.issue(kind, offset, object_id, reason)
+            }
+            e => self.issue(kind, offset, object_id, e.to_string()),
+        }
+    }
+
+    /// Check the header is usable at all, returning false if nothing else can
+    /// be checked
+    async fn check_header(&mut self) -> bool {
+        let block_size = self.header.block_size;
+        let file_size = self.header.file_size;
+
+        if block_size <= 4 {
+            self.issue(
+                VerifyIssueKind::Header,
+                DAT_HEADER_OFFSET as u32,
+                None,
+                format!("Block size {} is too small to hold any data", block_size),
+            );
+            return false;
+        }
+
+        if (file_size as u64) < DAT_HEADER_OFFSET + DAT_HEADER_SIZE as u64 {
+            self.issue(
+                VerifyIssueKind::Header,
+                DAT_HEADER_OFFSET as u32,
+                None,
+                format!("File size {} is smaller than the header", file_size),
+            );
+            return false;
+        }
+
+        // A half-downloaded DAT still has the header of the full one, so make
+        // sure its last byte can actually be read
+        if let Err(e) = self.reader.read_range(file_size - 1, 1).await {
+            self.issue(
+                VerifyIssueKind::Truncated,
+                file_size - 1,
+                None,
+                format!(
+                    "Header says the DAT is {} bytes but its last byte can't be read: {}",
+                    file_size, e
+                ),
+            );
+        }
+
+        true
+    }
+
+    /// Record that a chain uses the given blocks
+    fn claim(&mut self, blocks: &[u32], owner: BlockOwner) {
+        let object_id = match owner {
+            BlockOwner::File(object_id) => Some(object_id),
+            _ => None,
+        };
+
+        for &offset in blocks {
+            if offset % self.header.block_size != 0 {
+                self.issue(
+                    VerifyIssueKind::Misaligned,
+                    offset,
+                    object_id,
+                    format!(
+                        "Block used by {} isn't a multiple of the block size ({})",
+                        owner, self.header.block_size
+                    ),
+                );
+            }
+
+            // The first owner is kept so each block is only counted once
+            match self.owners.get(&offset).copied() {
+                Some(previous) => self.issue(
+                    VerifyIssueKind::DoubleAllocated,
+                    offset,
+                    object_id,
+                    format!("Block used by {} is also used by {}", owner, previous),
+                ),
+                None => {
+                    self.owners.insert(offset, owner);
+                }
+            }
+        }
+    }
+
+    /// Walk a chain of `size` bytes reading only the pointer of each block
+    ///
+    /// The blocks are recorded by the validator, so on failure whatever part
+    /// of the chain could be followed is still known and those blocks aren't
+    /// reported as orphaned.
+    async fn walk_chain(
+        &mut self,
+        validator: &mut BlockChainValidator,
+        offset: u32,
+        size: u32,
+    ) -> Result<(), DatError> {
+        validator.check_size(offset, size)?;
+
+        let block_count = size.div_ceil(self.header.block_size - 4).max(1);
+        let mut current_offset = offset;
+
+        for _ in 0..block_count {
+            validator.visit(current_offset)?;
+            let pointer = self.reader.read_range(current_offset, 4).await?;
+            current_offset = u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]]);
+        }
+
+        Ok(())
+    }
+
+    async fn check_tree(&mut self) {
+        let mut seen = HashSet::new();
+        let mut leaf_depth = None;
+        // Each pending node carries the exclusive bounds its keys must lie in
+        let mut pending = vec![(self.header.btree, None, None, 0)];
+
+        while let Some((offset, low, high, depth)) = pending.pop() {
+            if !seen.insert(offset) {
+                self.issue(
+                    VerifyIssueKind::Directory,
+                    offset,
+                    None,
+                    "Directory node is referenced more than once".to_string(),
+                );
+                continue;
+            }
+
+            let mut validator =
+                BlockChainValidator::new(self.header.block_size, self.header.file_size);
+            let node = DatBlockReader::read_async(
+                self.reader,
+                offset,
+                DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+                &mut validator,
+            )
+            .await
+            .and_then(|buffer| DatDirectoryHeader::read(&mut Cursor::new(buffer)));
+            self.claim(validator.blocks(), BlockOwner::Directory(offset));
+
+            let node = match node {
+                Ok(node) => node,
+                Err(e) => {
+                    self.chain_issue(VerifyIssueKind::Directory, offset, None, e);
+                    continue;
+                }
+            };
+
+            self.report.directories += 1;
+            let entry_count = node.entry_count as usize;
+            let is_leaf = node.branches[0] == 0;
+
+            // Entries must be strictly increasing and within the parent's bounds
+            let mut previous = low;
+
+            for entry in &node.entries {
+                if previous.is_some_and(|previous| entry.object_id <= previous)
+                    || high.is_some_and(|high| entry.object_id >= high)
+                {
+                    self.issue(
+                        VerifyIssueKind::EntryOrder,
+                        offset,
+                        Some(entry.object_id),
+                        format!(
+                            "Entry is out of order (previous key {}, upper bound {})",
+                            previous.map_or("-".to_string(), |id| format!("{:08X}", id)),
+                            high.map_or("-".to_string(), |id| format!("{:08X}", id)),
+                        ),
+                    );
+                }
+
+                previous = Some(entry.object_id);
+            }
+
+            // Leaves have no branches, other nodes have one more than entries
+            let expected_branches = if is_leaf { 0 } else { entry_count + 1 };
+            let branch_count = node.branches.iter().filter(|branch| **branch != 0).count();
+
+            if node.branches[..expected_branches].contains(&0) || branch_count != expected_branches
+            {
+                self.issue(
+                    VerifyIssueKind::BranchCount,
+                    offset,
+                    None,
+                    format!(
+                        "Node with {} entries has {} branches, expected {}",
+                        entry_count, branch_count, expected_branches
+                    ),
+                );
+            }
+
+            if is_leaf {
+                match leaf_depth {
+                    None => leaf_depth = Some(depth),
+                    Some(expected) if expected != depth => self.issue(
+                        VerifyIssueKind::TreeDepth,
+                        offset,
+                        None,
+                        format!("Leaf is at depth {}, expected {}", depth, expected),
+                    ),
+                    _ => {}
+                }
+            } else {
+                for i in (0..=entry_count).rev() {
+                    let branch = node.branches[i];
+
+                    if branch == 0 {
+                        continue;
+                    }
+
+                    let branch_low = if i == 0 {
+                        low
+                    } else {
+                        Some(node.entries[i - 1].object_id)
+                    };
+                    let branch_high = if i == entry_count {
+                        high
+                    } else {
+                        Some(node.entries[i].object_id)
+                    };
+                    pending.push((branch, branch_low, branch_high, depth + 1));
+                }
+            }
+
+            for entry in &node.entries {
+                self.report.files += 1;
+
+                let mut validator =
+                    BlockChainValidator::new(self.header.block_size, self.header.file_size)
+                        .for_object(entry.object_id);
+                let result = self
+                    .walk_chain(&mut validator, entry.file_offset, entry.file_size)
+                    .await;
+                self.claim(validator.blocks(), BlockOwner::File(entry.object_id));
+
+                if let Err(e) = result {
+                    self.chain_issue(
+                        VerifyIssueKind::BlockChain,
+                        entry.file_offset,
+                        Some(entry.object_id),
+                        e,
+                    );
+                }
+            }
+        }
+    }
+
+    async fn check_free_list(&mut self) {
+        let free_head = self.header.free_head;
+        let free_tail = self.header.free_tail;
+        let free_count = self.header.free_count;
+
+        let mut validator = BlockChainValidator::new(self.header.block_size, self.header.file_size);
+        let mut current_offset = free_head;
+        let mut error = None;
+
+        // The free list is a chain like any other, ending in a zero pointer
+        while current_offset != 0 {
+            if let Err(e) = validator.visit(current_offset) {
+                error = Some(e);
+                break;
+            }
+
+            match self.reader.read_range(current_offset, 4).await {
+                Ok(pointer) => {
+                    current_offset =
+                        u32::from_le_bytes([pointer[0], pointer[1], pointer[2], pointer[3]])
+                }
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let blocks = validator.blocks().to_vec();
+        self.report.free_blocks = blocks.len();
+        self.claim(&blocks, BlockOwner::Free);
+
+        if let Some(e) = error {
+            self.chain_issue(VerifyIssueKind::FreeList, free_head, None, e);
+        } else {
+            let last = blocks.last().copied().unwrap_or(0);
+
+            if last != free_tail {
+                self.issue(
+                    VerifyIssueKind::FreeList,
+                    last,
+                    None,
+                    format!(
+                        "Free list ends at {} instead of free_tail {}",
+                        last, free_tail
+                    ),
+                );
+            }
+        }
+
+        if blocks.len() != free_count as usize {
+            self.issue(
+                VerifyIssueKind::FreeCount,
+                free_head,
+                None,
+                format!(
+                    "Free list has {} blocks but free_count is {}",
+                    blocks.len(),
+                    free_count
+                ),
+            );
+        }
+    }
+
+    /// Report every run of blocks that nothing claimed
+    ///
+    /// Blocks are assumed to be laid out on multiples of the block size, with
+    /// the first one after the header.
+    fn check_orphans(&mut self) {
+        let block_size = self.header.block_size as u64;
+        let header_end = DAT_HEADER_OFFSET + DAT_HEADER_SIZE as u64;
+        let first_block = header_end.div_ceil(block_size) * block_size;
+        let file_size = self.header.file_size as u64;
+
+        let mut run_start = None;
+        let mut offset = first_block;
+
+        while offset + block_size <= file_size {
+            let claimed = self.owners.contains_key(&(offset as u32));
+
+            match (claimed, run_start) {
+                (false, None) => run_start = Some(offset),
+                (true, Some(start)) => {
+                    self.orphaned_run(start, offset);
+                    run_start = None;
+                }
+                _ => {}
+            }
+
+            offset += block_size;
+        }
+
+        if let Some(start) = run_start {
+            self.orphaned_run(start, offset);
+        }
+    }
+
+    fn orphaned_run(&mut self, start: u64, end: u64) {
+        let blocks = (end - start) / self.header.block_size as u64;
+
+        self.issue(
+            VerifyIssueKind::Orphaned,
+            start as u32,
+            None,
+            format!(
+                "{} block(s) ending at {} aren't used and aren't on the free list",
+                blocks, end
+            ),
+        );
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{
+        enums::dat_database_type::DatDatabaseType,
+        reader::{sync_file_reader::SyncFileRangeReader, types::dat_database::DatDatabase},
+        writer::dat_writer::DatWriter,
+    };
+
+    const BLOCK_SIZE: u32 = 256;
+
+    /// A DAT with three files of three blocks each and a non-empty free list
+    fn dat() -> Vec<u8> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, BLOCK_SIZE)
+                .unwrap();
+
+        for object_id in 0x06000001..=0x06000003 {
+            writer
+                .write_file(object_id, &[object_id as u8; 600])
+                .unwrap();
+        }
+
+        // Big enough that blocks are left over after the directory node
+        // takes what it needs from the free list
+        writer.write_file(0x06000004, &[4; 3000]).unwrap();
+        writer.delete_file(0x06000004).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn header(dat: &[u8]) -> DatDatabaseHeader {
+        DatDatabase::read(&mut Cursor::new(dat)).unwrap().header
+    }
+
+    fn read_u32(dat: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(dat[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write_u32(dat: &mut [u8], offset: usize, value: u32) {
+        dat[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Where byte `position` of the chain starting at `start` is in the DAT
+    fn locate(dat: &[u8], start: u32, mut position: usize) -> usize {
+        let data_size = BLOCK_SIZE as usize - 4;
+        let mut block = start as usize;
+
+        while position >= data_size {
+            block = read_u32(dat, block) as usize;
+            position -= data_size;
+        }
+
+        block + 4 + position
+    }
+
+    /// Where the root node's `field`th u32 of entry `index` is
+    fn root_entry(dat: &[u8], index: usize, field: usize) -> usize {
+        locate(dat, header(dat).btree, 62 * 4 + 4 + index * 24 + field * 4)
+    }
+
+    fn file_offset(dat: &[u8], index: usize) -> u32 {
+        read_u32(dat, root_entry(dat, index, 2))
+    }
+
+    async fn kinds(dat: Vec<u8>) -> Vec<VerifyIssueKind> {
+        let report = DatVerifier::verify(&mut SyncFileRangeReader::new(Cursor::new(dat)))
+            .await
+            .unwrap();
+
+        report.issues.iter().map(|issue| issue.kind).collect()
+    }
+
+    #[tokio::test]
+    async fn written_dats_have_no_issues() {
+        let dat = dat();
+        let report = DatVerifier::verify(&mut SyncFileRangeReader::new(Cursor::new(dat)))
+            .await
+            .unwrap();
+
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.files, 3);
+        assert!(report.free_blocks > 0);
+    }
+
+    #[tokio::test]
+    async fn detects_cross_linked_blocks() {
+        let mut dat = dat();
+        let first = file_offset(&dat, 0) as usize;
+        let second = file_offset(&dat, 1);
+
+        // The first file now carries on into the second one's blocks
+        write_u32(&mut dat, first, second);
+
+        let kinds = kinds(dat).await;
+        assert!(
+            kinds.contains(&VerifyIssueKind::DoubleAllocated),
+            "{:?}",
+            kinds
+        );
+        assert!(kinds.contains(&VerifyIssueKind::Orphaned), "{:?}", kinds);
+    }
+
+    #[tokio::test]
+    async fn detects_pointers_past_the_end() {
+        let mut dat = dat();
+        let first = file_offset(&dat, 0) as usize;
+        let past_the_end = header(&dat).file_size + BLOCK_SIZE * 4;
+        write_u32(&mut dat, first, past_the_end);
+
+        let kinds = kinds(dat).await;
+        assert!(kinds.contains(&VerifyIssueKind::BlockChain), "{:?}", kinds);
+    }
+
+    #[tokio::test]
+    async fn detects_free_list_cycles() {
+        let mut dat = dat();
+        let header = header(&dat);
+        write_u32(&mut dat, header.free_tail as usize, header.free_head);
+
+        let kinds = kinds(dat).await;
+        assert!(kinds.contains(&VerifyIssueKind::FreeList), "{:?}", kinds);
+    }
+
+    #[tokio::test]
+    async fn detects_a_wrong_free_count() {
+        let mut dat = dat();
+        let header = header(&dat);
+        let free_count = DAT_HEADER_OFFSET as usize + 7 * 4;
+        write_u32(&mut dat, free_count, header.free_count + 1);
+
+        assert_eq!(kinds(dat).await, vec![VerifyIssueKind::FreeCount]);
+    }
+
+    #[tokio::test]
+    async fn detects_unsorted_entries() {
+        let mut dat = dat();
+        let first = root_entry(&dat, 0, 1);
+        let second = root_entry(&dat, 1, 1);
+        let first_id = read_u32(&dat, first);
+        let second_id = read_u32(&dat, second);
+        write_u32(&mut dat, first, second_id);
+        write_u32(&mut dat, second, first_id);
+
+        assert_eq!(kinds(dat).await, vec![VerifyIssueKind::EntryOrder]);
+    }
+
+    #[tokio::test]
+    async fn detects_overfull_nodes() {
+        let mut dat = dat();
+        let entry_count = locate(&dat, header(&dat).btree, 62 * 4);
+        write_u32(&mut dat, entry_count, 62);
+
+        let kinds = kinds(dat).await;
+        assert_eq!(
+            kinds.first(),
+            Some(&VerifyIssueKind::Directory),
+            "{:?}",
+            kinds
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_branch_counts_that_dont_match() {
+        let mut dat = dat();
+        let btree = header(&dat).btree;
+        // A leaf with one branch, which points back at the root
+        let first_branch = locate(&dat, btree, 0);
+        write_u32(&mut dat, first_branch, btree);
+
+        let kinds = kinds(dat).await;
+        assert!(kinds.contains(&VerifyIssueKind::BranchCount), "{:?}", kinds);
+    }
+}
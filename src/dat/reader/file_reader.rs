@@ -1,6 +1,6 @@
 use futures::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, SeekFrom};
 
-use crate::dat::reader::range_reader::RangeReader;
+use crate::dat::{error::DatError, reader::range_reader::RangeReader};
 
 /// File-based implementation of RangeReader using seek
 pub struct FileRangeReader<R> {
@@ -20,21 +20,26 @@ impl<R> RangeReader for FileRangeReader<R>
 where
     R: AsyncRead + AsyncSeek + Unpin + Send,
 {
-    fn read_range(
+    async fn read_range(
         &mut self,
         offset: u32,
         length: usize,
-    ) -> impl std::future::Future<Output = Result<Vec<u8>, Box<dyn std::error::Error>>> {
-        async move {
+    ) -> Result<Vec<u8>, DatError> {
+        // Seek to the position
+        self.reader.seek(SeekFrom::Start(offset.into())).await?;
 
-            // Seek to the position
-            self.reader.seek(SeekFrom::Start(offset.into())).await?;
+        // Read exactly the requested bytes, reporting a read past the end
+        // the same way the other readers do
+        let mut buffer = vec![0u8; length];
 
-            // Read exactly the requested bytes
-            let mut buffer = vec![0u8; length];
-            self.reader.read_exact(&mut buffer).await?;
-
-            Ok(buffer)
+        match self.reader.read_exact(&mut buffer).await {
+            Ok(()) => Ok(buffer),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(DatError::OutOfBounds {
+                offset: offset as u64,
+                length: length as u64,
+                size: self.reader.seek(SeekFrom::End(0)).await?,
+            }),
+            Err(e) => Err(e.into()),
         }
     }
 }
@@ -1,20 +1,106 @@
-use crate::dat::reader::range_reader::RangeReader;
+use std::time::Duration;
+
+use reqwest::{
+    Response, StatusCode,
+    header::{
+        CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderMap, IF_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED,
+        RANGE,
+    },
+};
+
+use crate::dat::{error::DatError, reader::range_reader::RangeReader};
 
 pub struct HttpRangeReaderOptions {
-    allow_fallback_full_read: bool,
+    /// Accept a 200 response with the whole resource from servers that don't
+    /// support range requests, and serve this and every later range out of
+    /// it (it's kept in memory, so this needs as much memory as the DAT is
+    /// large)
+    pub allow_fallback_full_read: bool,
+    /// How many times to retry a request that failed in a way that might not
+    /// happen again (a network error, timeout, 5xx or 429 status, or a body
+    /// that ended early)
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling for each one after
+    pub retry_backoff: Duration,
+    /// Give up on a request (and maybe retry it) after this long
+    pub timeout: Option<Duration>,
+    /// Send the ETag (or Last-Modified date) of the first response with every
+    /// later request, so a DAT that changes on the server between requests
+    /// is reported rather than read as a mix of two versions
+    pub pin_version: bool,
+    /// Most ranges to ask for in one request from read_ranges, since servers
+    /// limit how long headers (and so the Range header) can be
+    pub max_ranges_per_request: usize,
 }
 
 impl Default for HttpRangeReaderOptions {
     fn default() -> Self {
         HttpRangeReaderOptions {
             allow_fallback_full_read: false,
+            retries: 3,
+            retry_backoff: Duration::from_millis(250),
+            timeout: Some(Duration::from_secs(30)),
+            pin_version: true,
+            max_ranges_per_request: 32,
+        }
+    }
+}
+
+/// The version of the remote DAT a reader has pinned its requests to
+#[derive(Clone, Debug, PartialEq)]
+pub enum PinnedVersion {
+    /// A strong ETag, sent as If-Match
+    ETag(String),
+    /// A Last-Modified date, sent as If-Unmodified-Since when the server
+    /// doesn't give a strong ETag
+    LastModified(String),
+}
+
+impl std::fmt::Display for PinnedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PinnedVersion::ETag(etag) => write!(f, "ETag {}", etag),
+            PinnedVersion::LastModified(date) => write!(f, "Last-Modified {}", date),
+        }
+    }
+}
+
+/// Why a single request failed, and whether it's worth trying again
+enum AttemptError {
+    Retryable(DatError),
+    Fatal(DatError),
+    /// The server sent the whole resource for a request for several ranges
+    MultipleRangesUnsupported,
+}
+
+impl AttemptError {
+    fn into_error(self) -> DatError {
+        match self {
+            AttemptError::Retryable(e) | AttemptError::Fatal(e) => e,
+            AttemptError::MultipleRangesUnsupported => DatError::Http(
+                "HTTP server doesn't support multiple ranges per request".to_string(),
+            ),
         }
     }
 }
+
+impl From<reqwest::Error> for AttemptError {
+    fn from(e: reqwest::Error) -> Self {
+        AttemptError::Retryable(e.into())
+    }
+}
+
 pub struct HttpRangeReader {
     url: String,
     client: reqwest::Client,
     options: HttpRangeReaderOptions,
+    pinned_version: Option<PinnedVersion>,
+    /// The whole resource, once a server that doesn't support range requests
+    /// has sent it
+    full_body: Option<Vec<u8>>,
+    /// Set once the server answers a request for several ranges with the
+    /// whole resource, so read_ranges only asks for one at a time
+    multiple_ranges_unsupported: bool,
 }
 
 impl HttpRangeReader {
@@ -23,6 +109,18 @@ impl HttpRangeReader {
             url,
             client,
             options,
+            pinned_version: None,
+            full_body: None,
+            multiple_ranges_unsupported: false,
+        }
+    }
+
+    /// Start building a reader with options other than the defaults
+    pub fn builder(url: String) -> HttpRangeReaderBuilder {
+        HttpRangeReaderBuilder {
+            url,
+            client: None,
+            options: HttpRangeReaderOptions::default(),
         }
     }
 
@@ -34,51 +132,1132 @@ impl HttpRangeReader {
             HttpRangeReaderOptions::default(),
         )
     }
-}
 
-impl RangeReader for HttpRangeReader {
-    fn read_range(
+    /// The version of the DAT every request after the first must match, if
+    /// one has been pinned yet
+    pub fn pinned_version(&self) -> Option<&PinnedVersion> {
+        self.pinned_version.as_ref()
+    }
+
+    /// Whether the whole resource has been downloaded (see
+    /// HttpRangeReaderOptions::allow_fallback_full_read), so no more requests
+    /// will be made
+    pub fn has_full_body(&self) -> bool {
+        self.full_body.is_some()
+    }
+
+    /// Make a single request for one or more ranges, none of them empty
+    async fn try_read_ranges(
         &mut self,
+        ranges: &[(u32, usize)],
+    ) -> Result<Vec<Vec<u8>>, AttemptError> {
+        let range_header = ranges
+            .iter()
+            .map(|&(offset, length)| format!("{}-{}", offset, offset as u64 + length as u64 - 1))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut request = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}", range_header));
+
+        if let Some(timeout) = self.options.timeout {
+            request = request.timeout(timeout);
+        }
+
+        request = match &self.pinned_version {
+            Some(PinnedVersion::ETag(etag)) => request.header(IF_MATCH, etag),
+            Some(PinnedVersion::LastModified(date)) => request.header(IF_UNMODIFIED_SINCE, date),
+            None => request,
+        };
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        if status == StatusCode::PRECONDITION_FAILED {
+            return Err(AttemptError::Fatal(self.changed_error()));
+        }
+
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            let size = content_range(response.headers())
+                .and_then(|range| range.size)
+                .unwrap_or(0);
+            // Report the range that's past the end
+            let &(offset, length) = ranges
+                .iter()
+                .find(|&&(offset, _)| offset as u64 >= size)
+                .unwrap_or(&ranges[0]);
+
+            return Err(AttemptError::Fatal(DatError::OutOfBounds {
+                offset: offset as u64,
+                length: length as u64,
+                size,
+            }));
+        }
+
+        if !status.is_success() {
+            let error = DatError::Http(format!("HTTP request failed with status: {}", status));
+
+            return Err(
+                if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                    AttemptError::Retryable(error)
+                } else {
+                    AttemptError::Fatal(error)
+                },
+            );
+        }
+
+        self.check_version(response.headers())?;
+
+        if status != StatusCode::PARTIAL_CONTENT {
+            return self.read_full(response, ranges).await;
+        }
+
+        let parts = self.read_parts(response).await?;
+
+        ranges
+            .iter()
+            .map(|&(offset, length)| self.take_range(&parts, offset, length))
+            .collect()
+    }
+
+    /// Read the parts of a 206 response: the one part of a single-range
+    /// response, or every part of a multipart/byteranges one
+    async fn read_parts(&self, response: Response) -> Result<Vec<ResponsePart>, AttemptError> {
+        let boundary = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(multipart_boundary);
+
+        let parts = match boundary {
+            Some(boundary) => {
+                let body = response.bytes().await?;
+
+                parse_multipart(&body, &boundary).ok_or_else(|| {
+                    // Most likely cut short, so worth another try
+                    AttemptError::Retryable(DatError::Http(format!(
+                        "Malformed or incomplete multipart/byteranges response from {}",
+                        self.url
+                    )))
+                })?
+            }
+            None => {
+                let range = content_range(response.headers()).ok_or_else(|| {
+                    AttemptError::Fatal(DatError::Http(format!(
+                        "Partial response from {} has a missing or invalid Content-Range",
+                        self.url
+                    )))
+                })?;
+
+                vec![ResponsePart {
+                    range,
+                    data: response.bytes().await?.to_vec(),
+                }]
+            }
+        };
+
+        for part in &parts {
+            let expected = part.range.length().ok_or_else(|| {
+                AttemptError::Fatal(DatError::Http(format!(
+                    "Partial response from {} has an invalid Content-Range",
+                    self.url
+                )))
+            })?;
+
+            if part.data.len() as u64 != expected {
+                return Err(AttemptError::Retryable(DatError::Http(format!(
+                    "Short read of bytes {}-{} of {}: got {} of {} bytes",
+                    part.range.start,
+                    part.range.end,
+                    self.url,
+                    part.data.len(),
+                    expected
+                ))));
+            }
+        }
+
+        Ok(parts)
+    }
+
+    /// Take a requested range out of the parts of a response
+    ///
+    /// Servers may merge adjacent or overlapping ranges and send the parts
+    /// in any order, so the range only has to fall inside one of them.
+    fn take_range(
+        &self,
+        parts: &[ResponsePart],
         offset: u32,
         length: usize,
-    ) -> impl std::future::Future<Output = Result<Vec<u8>, Box<dyn std::error::Error>>> {
-        let end_byte = offset + length as u32 - 1;
-        let range_header = format!("bytes={}-{}", offset, end_byte);
-        let url = self.url.clone();
-        let client = self.client.clone();
-
-        async move {
-            let response = client
-                .get(&url)
-                .header("Range", range_header)
-                .send()
-                .await?;
-
-            // Check if the server supports range requests
-            if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
-                let bytes = response.bytes().await?;
-                Ok(bytes.to_vec())
-            } else if response.status().is_success() {
-                if !self.options.allow_fallback_full_read {
-                    return Err(
-                        format!("HTTP server doesn't support range requests and falling back to reading the entire resource (allow_fallback_full_read) was disabled using options.").into(),
-                    );
+    ) -> Result<Vec<u8>, AttemptError> {
+        let start = offset as u64;
+        let end = start + length as u64 - 1;
+
+        if let Some(part) = parts
+            .iter()
+            .find(|part| part.range.start <= start && end <= part.range.end)
+        {
+            let from = (start - part.range.start) as usize;
+
+            return Ok(part.data[from..from + length].to_vec());
+        }
+
+        // Servers return what they have of a range that runs past the end of
+        // the file
+        if let Some(size) = parts.iter().find_map(|part| part.range.size)
+            && end >= size
+        {
+            return Err(AttemptError::Fatal(DatError::OutOfBounds {
+                offset: start,
+                length: length as u64,
+                size,
+            }));
+        }
+
+        let received: Vec<String> = parts
+            .iter()
+            .map(|part| format!("{}-{}", part.range.start, part.range.end))
+            .collect();
+
+        Err(AttemptError::Fatal(DatError::Http(format!(
+            "Requested bytes {}-{} of {} but got bytes {}",
+            start,
+            end,
+            self.url,
+            received.join(",")
+        ))))
+    }
+
+    /// Keep the whole resource from a 200 response, if that's allowed, and
+    /// read the ranges out of it
+    async fn read_full(
+        &mut self,
+        response: Response,
+        ranges: &[(u32, usize)],
+    ) -> Result<Vec<Vec<u8>>, AttemptError> {
+        if !self.options.allow_fallback_full_read {
+            // The server may only be refusing multiple ranges at once
+            if ranges.len() > 1 {
+                return Err(AttemptError::MultipleRangesUnsupported);
+            }
+
+            return Err(AttemptError::Fatal(DatError::Http(
+                "HTTP server doesn't support range requests and falling back to reading the entire resource (allow_fallback_full_read) was disabled using options.".to_string(),
+            )));
+        }
+
+        // Server doesn't support ranges, but returned full content, so keep
+        // it to take these and every later range from
+        let full_body = Vec::from(response.bytes().await?);
+        let data = ranges
+            .iter()
+            .map(|&(offset, length)| slice_full_body(&full_body, offset, length))
+            .collect::<Result<_, _>>()
+            .map_err(AttemptError::Fatal);
+        self.full_body = Some(full_body);
+
+        data
+    }
+
+    /// try_read_ranges, retried with backoff as the options allow
+    async fn read_ranges_with_retries(
+        &mut self,
+        ranges: &[(u32, usize)],
+    ) -> Result<Vec<Vec<u8>>, AttemptError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.try_read_ranges(ranges).await {
+                Err(AttemptError::Retryable(_)) if attempt < self.options.retries => {
+                    let backoff = self
+                        .options
+                        .retry_backoff
+                        .saturating_mul(1 << attempt.min(16));
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(AttemptError::Retryable(DatError::Http(message))) => {
+                    return Err(AttemptError::Fatal(DatError::Http(format!(
+                        "{} (gave up after {} attempts)",
+                        message,
+                        attempt + 1
+                    ))));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Pin the DAT's version from the first successful response, and make
+    /// sure later ones are for the same version
+    ///
+    /// Servers that ignore If-Match or If-Unmodified-Since are caught here
+    /// too, as long as they send an ETag or Last-Modified date.
+    fn check_version(&mut self, headers: &HeaderMap) -> Result<(), AttemptError> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        // Weak ETags never match If-Match, so they're no use for pinning
+        let etag = header(ETAG).filter(|etag| !etag.starts_with("W/"));
+        let last_modified = header(LAST_MODIFIED);
+
+        match &self.pinned_version {
+            Some(PinnedVersion::ETag(pinned))
+                if etag.as_ref().is_some_and(|etag| etag != pinned) =>
+            {
+                return Err(AttemptError::Fatal(self.changed_error()));
+            }
+            Some(PinnedVersion::LastModified(pinned))
+                if last_modified.as_ref().is_some_and(|date| date != pinned) =>
+            {
+                return Err(AttemptError::Fatal(self.changed_error()));
+            }
+            Some(_) => {}
+            None if self.options.pin_version => {
+                self.pinned_version = match (etag, last_modified) {
+                    (Some(etag), _) => Some(PinnedVersion::ETag(etag)),
+                    (None, Some(date)) => Some(PinnedVersion::LastModified(date)),
+                    (None, None) => None,
+                };
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn changed_error(&self) -> DatError {
+        match &self.pinned_version {
+            Some(pinned_version) => DatError::Http(format!(
+                "{} changed on the server since it was first read (expected {})",
+                self.url, pinned_version
+            )),
+            None => DatError::Http(format!("{} changed on the server", self.url)),
+        }
+    }
+}
+
+fn slice_full_body(full_body: &[u8], offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+    let start = offset as usize;
+    let end = start + length;
+
+    if end > full_body.len() {
+        return Err(DatError::OutOfBounds {
+            offset: start as u64,
+            length: length as u64,
+            size: full_body.len() as u64,
+        });
+    }
+
+    Ok(full_body[start..end].to_vec())
+}
+
+/// A parsed `Content-Range: bytes start-end/size` header
+#[derive(Clone, Copy)]
+struct ContentRange {
+    start: u64,
+    end: u64,
+    /// None when the server sent `*` for the size
+    size: Option<u64>,
+}
+
+impl ContentRange {
+    /// Number of bytes in the range, or None if there are too many to count
+    fn length(&self) -> Option<u64> {
+        self.end.checked_sub(self.start)?.checked_add(1)
+    }
+}
+
+/// A range of the resource from a 206 response
+struct ResponsePart {
+    range: ContentRange,
+    data: Vec<u8>,
+}
+
+/// Parse the Content-Range header, if there's a valid one
+fn content_range(headers: &HeaderMap) -> Option<ContentRange> {
+    parse_content_range(headers.get(CONTENT_RANGE)?.to_str().ok()?)
+}
+
+/// Parse a Content-Range value
+///
+/// For a 416 response it's `bytes */size`, which is returned with a start
+/// and end of 0.
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, size) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let size = match size.trim() {
+        "*" => None,
+        size => Some(size.parse().ok()?),
+    };
+
+    if range.trim() == "*" {
+        return Some(ContentRange {
+            start: 0,
+            end: 0,
+            size,
+        });
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = end.trim().parse().ok()?;
+
+    (start <= end).then_some(ContentRange { start, end, size })
+}
+
+/// The boundary of a `multipart/byteranges; boundary=...` Content-Type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/byteranges")
+    {
+        return None;
+    }
+
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Split a multipart/byteranges body into its parts
+///
+/// Each part's data is taken to be as long as its Content-Range says, rather
+/// than running up to the next boundary, since DAT data can contain anything.
+/// Returns None if the body is malformed or cut short.
+fn parse_multipart(body: &[u8], boundary: &str) -> Option<Vec<ResponsePart>> {
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+    let find = |from: usize, needle: &[u8]| {
+        body.get(from..)?
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|position| from + position)
+    };
+
+    let mut parts = Vec::new();
+    let mut position = find(0, delimiter)? + delimiter.len();
+
+    // The last delimiter has "--" straight after it
+    while !body.get(position..)?.starts_with(b"--") {
+        let headers_end = find(position, b"\r\n\r\n")?;
+        let headers = std::str::from_utf8(&body[position..headers_end]).ok()?;
+        let range = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+
+            name.trim()
+                .eq_ignore_ascii_case("content-range")
+                .then(|| parse_content_range(value.trim()))?
+        })?;
+
+        let data_start = headers_end + 4;
+        let data_end = data_start.checked_add(usize::try_from(range.length()?).ok()?)?;
+        let data = body.get(data_start..data_end)?.to_vec();
+        parts.push(ResponsePart { range, data });
+
+        position = find(data_end, delimiter)? + delimiter.len();
+    }
+
+    Some(parts)
+}
+
+impl RangeReader for HttpRangeReader {
+    /// Request a range, retrying with backoff as the options allow
+    ///
+    /// The data is only returned if it's exactly the range asked for, of the
+    /// version of the DAT that was pinned on the first request.
+    async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        if let Some(full_body) = &self.full_body {
+            return slice_full_body(full_body, offset, length);
+        }
+
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        match self.read_ranges_with_retries(&[(offset, length)]).await {
+            Ok(mut data) => Ok(data.remove(0)),
+            Err(e) => Err(e.into_error()),
+        }
+    }
+
+    /// Request the ranges in as few requests as the options allow, as one
+    /// multipart/byteranges response each
+    ///
+    /// Servers that answer with the whole resource instead (e.g. because
+    /// they only support single ranges) are asked for one range at a time
+    /// from then on, unless allow_fallback_full_read is set.
+    async fn read_ranges(&mut self, ranges: &[(u32, usize)]) -> Result<Vec<Vec<u8>>, DatError> {
+        if let Some(full_body) = &self.full_body {
+            return ranges
+                .iter()
+                .map(|&(offset, length)| slice_full_body(full_body, offset, length))
+                .collect();
+        }
+
+        let batch_size = match self.multiple_ranges_unsupported {
+            true => 1,
+            false => self.options.max_ranges_per_request.max(1),
+        };
+        let wanted: Vec<(u32, usize)> = ranges
+            .iter()
+            .copied()
+            .filter(|&(_, length)| length > 0)
+            .collect();
+        let mut fetched = Vec::with_capacity(wanted.len());
+        let mut batches = wanted.chunks(batch_size);
+
+        while let Some(batch) = batches.next() {
+            match self.read_ranges_with_retries(batch).await {
+                Ok(data) => fetched.extend(data),
+                Err(AttemptError::MultipleRangesUnsupported) => {
+                    self.multiple_ranges_unsupported = true;
+
+                    for &(offset, length) in batch.iter().chain(batches.by_ref().flatten()) {
+                        fetched.push(self.read_range(offset, length).await?);
+                    }
+                }
+                Err(e) => return Err(e.into_error()),
+            }
+        }
+
+        // Put the empty ranges back in among the fetched ones
+        let mut fetched = fetched.into_iter();
+
+        Ok(ranges
+            .iter()
+            .map(|&(_, length)| match length {
+                0 => Vec::new(),
+                _ => fetched.next().unwrap_or_default(),
+            })
+            .collect())
+    }
+}
+
+/// Builds an HttpRangeReader, starting from the default options
+pub struct HttpRangeReaderBuilder {
+    url: String,
+    client: Option<reqwest::Client>,
+    options: HttpRangeReaderOptions,
+}
+
+impl HttpRangeReaderBuilder {
+    /// Use this client rather than a new one
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Replace every option at once
+    pub fn options(mut self, options: HttpRangeReaderOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn allow_fallback_full_read(mut self, allow_fallback_full_read: bool) -> Self {
+        self.options.allow_fallback_full_read = allow_fallback_full_read;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.options.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Give up on requests after this long, or never with None
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    pub fn pin_version(mut self, pin_version: bool) -> Self {
+        self.options.pin_version = pin_version;
+        self
+    }
+
+    pub fn max_ranges_per_request(mut self, max_ranges_per_request: usize) -> Self {
+        self.options.max_ranges_per_request = max_ranges_per_request;
+        self
+    }
+
+    pub fn build(self) -> HttpRangeReader {
+        HttpRangeReader::new(self.client.unwrap_or_default(), self.url, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// What the test server sends back for a request
+    struct Reply {
+        status: &'static str,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn partial(start: u64, end: u64, size: u64, body: Vec<u8>) -> Self {
+            Reply {
+                status: "206 Partial Content",
+                headers: vec![("Content-Range", format!("bytes {}-{}/{}", start, end, size))],
+                body,
+            }
+        }
+
+        fn status(status: &'static str) -> Self {
+            Reply {
+                status,
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        fn header(mut self, name: &'static str, value: &str) -> Self {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+    }
+
+    /// Serve every request on a local port with `reply`, which is given the
+    /// request's number (from 0) and its headers with lowercase names
+    ///
+    /// Returns the URL to request and a count of the requests made.
+    async fn serve<F>(reply: F) -> (String, Arc<AtomicUsize>)
+    where
+        F: Fn(usize, &HashMap<String, String>) -> Reply + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/client_portal.dat",
+            listener.local_addr().unwrap()
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+
+                    if socket.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+
+                    request.push(byte[0]);
                 }
 
-                // Server doesn't support ranges, but returned full content
-                // We'll take just the part we need
-                let bytes = response.bytes().await?;
-                let start = offset as usize;
-                let end = std::cmp::min(start + length, bytes.len());
+                let headers = String::from_utf8_lossy(&request)
+                    .lines()
+                    .skip(1)
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+                    .collect();
+                let reply = reply(count.fetch_add(1, Ordering::SeqCst), &headers);
 
-                if start >= bytes.len() {
-                    return Err("Offset beyond file size".into());
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    reply.status,
+                    reply.body.len()
+                );
+
+                for (name, value) in &reply.headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
                 }
 
-                Ok(bytes[start..end].to_vec())
-            } else {
-                Err(format!("HTTP request failed with status: {}", response.status()).into())
+                response.push_str("\r\n");
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.write_all(&reply.body).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn reader(url: String) -> HttpRangeReader {
+        HttpRangeReader::builder(url)
+            .retries(2)
+            .retry_backoff(Duration::from_millis(1))
+            .build()
+    }
+
+    fn data(start: u64, end: u64) -> Vec<u8> {
+        (start..=end).map(|i| i as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn retries_then_succeeds() {
+        let (url, requests) = serve(|n, _| match n {
+            0 => Reply::status("503 Service Unavailable"),
+            _ => Reply::partial(10, 19, 100, data(10, 19)),
+        })
+        .await;
+
+        let result = reader(url).read_range(10, 10).await.unwrap();
+
+        assert_eq!(result, data(10, 19));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retry() {
+        let (url, requests) = serve(|_, _| Reply::status("503 Service Unavailable")).await;
+
+        let result = reader(url).read_range(10, 10).await;
+
+        assert!(
+            matches!(&result, Err(DatError::Http(message)) if message.contains("gave up after 3 attempts"))
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn sends_if_match_and_reports_412() {
+        let (url, requests) = serve(|n, headers| match n {
+            0 => Reply::partial(0, 9, 100, data(0, 9)).header("ETag", "\"v1\""),
+            _ if headers.get("if-match").map(String::as_str) == Some("\"v1\"") => {
+                Reply::status("412 Precondition Failed")
+            }
+            _ => Reply::partial(10, 19, 100, data(10, 19)).header("ETag", "\"v2\""),
+        })
+        .await;
+        let mut reader = reader(url);
+
+        reader.read_range(0, 10).await.unwrap();
+        let result = reader.read_range(10, 10).await;
+
+        assert_eq!(
+            reader.pinned_version(),
+            Some(&PinnedVersion::ETag("\"v1\"".to_string()))
+        );
+        assert!(matches!(&result, Err(DatError::Http(message)) if message.contains("changed")));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_a_changed_etag_when_if_match_is_ignored() {
+        let (url, _) = serve(|n, _| {
+            let etag = if n == 0 { "\"v1\"" } else { "\"v2\"" };
+
+            Reply::partial(0, 9, 100, data(0, 9)).header("ETag", etag)
+        })
+        .await;
+        let mut reader = reader(url);
+
+        reader.read_range(0, 10).await.unwrap();
+        let result = reader.read_range(0, 10).await;
+
+        assert!(matches!(&result, Err(DatError::Http(message)) if message.contains("changed")));
+    }
+
+    #[tokio::test]
+    async fn reports_a_changed_last_modified_date() {
+        let (url, _) = serve(|n, headers| {
+            if n > 0 {
+                assert_eq!(
+                    headers.get("if-unmodified-since").map(String::as_str),
+                    Some("Mon, 01 Jan 2024 00:00:00 GMT")
+                );
             }
+
+            let date = match n {
+                0 | 1 => "Mon, 01 Jan 2024 00:00:00 GMT",
+                _ => "Tue, 02 Jan 2024 00:00:00 GMT",
+            };
+
+            Reply::partial(0, 9, 100, data(0, 9)).header("Last-Modified", date)
+        })
+        .await;
+        let mut reader = reader(url);
+
+        reader.read_range(0, 10).await.unwrap();
+        reader.read_range(0, 10).await.unwrap();
+        let result = reader.read_range(0, 10).await;
+
+        assert!(matches!(&result, Err(DatError::Http(message)) if message.contains("changed")));
+    }
+
+    #[tokio::test]
+    async fn retries_a_short_body() {
+        let (url, requests) = serve(|_, _| Reply::partial(0, 99, 1000, data(0, 49))).await;
+
+        let result = reader(url).read_range(0, 100).await;
+
+        assert!(matches!(&result, Err(DatError::Http(message)) if message.contains("Short read")));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_mismatched_content_range() {
+        let (url, requests) = serve(|_, _| Reply::partial(100, 199, 1000, data(100, 199))).await;
+
+        let result = reader(url).read_range(0, 100).await;
+
+        assert!(
+            matches!(&result, Err(DatError::Http(message)) if message.contains("but got bytes 100-199"))
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_content_range_too_long_to_count() {
+        let (url, _) = serve(|_, _| Reply {
+            status: "206 Partial Content",
+            headers: vec![("Content-Range", format!("bytes 0-{}/*", u64::MAX))],
+            body: data(0, 9),
+        })
+        .await;
+
+        let result = reader(url).read_range(0, 10).await;
+
+        assert!(
+            matches!(&result, Err(DatError::Http(message)) if message.contains("invalid Content-Range"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_416_as_out_of_bounds() {
+        let (url, requests) = serve(|_, _| {
+            Reply::status("416 Range Not Satisfiable").header("Content-Range", "bytes */50")
+        })
+        .await;
+
+        let result = reader(url).read_range(60, 10).await;
+
+        assert!(matches!(
+            result,
+            Err(DatError::OutOfBounds {
+                offset: 60,
+                length: 10,
+                size: 50
+            })
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_full_body_to_read_later_ranges_from() {
+        let (url, requests) = serve(|_, _| Reply {
+            status: "200 OK",
+            headers: Vec::new(),
+            body: data(0, 99),
+        })
+        .await;
+        let mut reader = HttpRangeReader::builder(url)
+            .allow_fallback_full_read(true)
+            .build();
+
+        assert_eq!(reader.read_range(10, 10).await.unwrap(), data(10, 19));
+        assert!(reader.has_full_body());
+        assert_eq!(reader.read_range(50, 20).await.unwrap(), data(50, 69));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_full_body_unless_allowed() {
+        let (url, requests) = serve(|_, _| Reply {
+            status: "200 OK",
+            headers: Vec::new(),
+            body: data(0, 99),
+        })
+        .await;
+        let mut reader = reader(url);
+
+        let result = reader.read_range(10, 10).await;
+
+        assert!(
+            matches!(&result, Err(DatError::Http(message)) if message.contains("allow_fallback_full_read"))
+        );
+        assert!(!reader.has_full_body());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    /// A multipart/byteranges body with one part per (start, end), with the
+    /// CRLF before each delimiter that servers send
+    fn multipart_body(boundary: &str, preamble: &str, parts: &[(u64, u64)]) -> Vec<u8> {
+        let mut body = preamble.as_bytes().to_vec();
+
+        for &(start, end) in parts {
+            body.extend_from_slice(
+                format!(
+                    "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/1000\r\n\r\n",
+                    boundary, start, end
+                )
+                .as_bytes(),
+            );
+            body.extend(data(start, end));
         }
+
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        body
+    }
+
+    fn ranges(parts: &[ResponsePart]) -> Vec<(u64, u64)> {
+        parts
+            .iter()
+            .map(|part| (part.range.start, part.range.end))
+            .collect()
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        let range = parse_content_range("bytes 10-19/100").unwrap();
+        assert_eq!((range.start, range.end, range.size), (10, 19, Some(100)));
+
+        let range = parse_content_range("bytes 10-19/*").unwrap();
+        assert_eq!((range.start, range.end, range.size), (10, 19, None));
+
+        let range = parse_content_range("bytes */100").unwrap();
+        assert_eq!((range.start, range.end, range.size), (0, 0, Some(100)));
+
+        for value in [
+            "bytes 19-10/100",
+            "bytes 10-19",
+            "bytes 10/100",
+            "items 10-19/100",
+            "bytes a-19/100",
+            "bytes 10-19/x",
+        ] {
+            assert!(parse_content_range(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn finds_multipart_boundaries() {
+        assert_eq!(
+            multipart_boundary("multipart/byteranges; boundary=3d6b6a416f9b5"),
+            Some("3d6b6a416f9b5".to_string())
+        );
+        assert_eq!(
+            multipart_boundary("Multipart/ByteRanges; charset=x; Boundary=\"a b:c\""),
+            Some("a b:c".to_string())
+        );
+        assert_eq!(multipart_boundary("multipart/byteranges"), None);
+        assert_eq!(multipart_boundary("multipart/mixed; boundary=x"), None);
+        assert_eq!(multipart_boundary("application/octet-stream"), None);
+    }
+
+    #[test]
+    fn parses_parts_in_any_order() {
+        let body = multipart_body("XYZ", "", &[(200, 209), (0, 9), (100, 149)]);
+        let parts = parse_multipart(&body, "XYZ").unwrap();
+
+        assert_eq!(ranges(&parts), [(200, 209), (0, 9), (100, 149)]);
+        assert_eq!(parts[1].data, data(0, 9));
+
+        let reader = HttpRangeReader::with_default_client(String::new());
+
+        for (offset, length) in [(0, 10), (100, 50), (200, 10)] {
+            let taken = reader.take_range(&parts, offset, length).ok().unwrap();
+            assert_eq!(
+                taken,
+                data(offset as u64, offset as u64 + length as u64 - 1)
+            );
+        }
+    }
+
+    #[test]
+    fn takes_requested_ranges_out_of_merged_parts() {
+        // Asked for 0-9 and 10-19 and 30-39, sent as one part
+        let body = multipart_body("XYZ", "", &[(0, 39)]);
+        let parts = parse_multipart(&body, "XYZ").unwrap();
+        let reader = HttpRangeReader::with_default_client(String::new());
+
+        for (offset, length) in [(0, 10), (10, 10), (30, 10)] {
+            let taken = reader.take_range(&parts, offset, length).ok().unwrap();
+            assert_eq!(
+                taken,
+                data(offset as u64, offset as u64 + length as u64 - 1)
+            );
+        }
+
+        assert!(reader.take_range(&parts, 35, 10).is_err());
+    }
+
+    #[test]
+    fn skips_the_preamble() {
+        let body = multipart_body("XYZ", "This is a preamble\r\n\r\n", &[(0, 9), (20, 29)]);
+        let parts = parse_multipart(&body, "XYZ").unwrap();
+
+        assert_eq!(ranges(&parts), [(0, 9), (20, 29)]);
+        assert_eq!(parts[1].data, data(20, 29));
+    }
+
+    #[test]
+    fn reads_data_containing_the_boundary() {
+        // Part data is taken by length, so a delimiter inside it is just data
+        let mut body = b"\r\n--XYZ\r\nContent-Range: bytes 0-7/8\r\n\r\n".to_vec();
+        body.extend_from_slice(b"--XYZ--!");
+        body.extend_from_slice(b"\r\n--XYZ--\r\n");
+        let parts = parse_multipart(&body, "XYZ").unwrap();
+
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].data, b"--XYZ--!");
+    }
+
+    #[test]
+    fn rejects_truncated_multipart_bodies() {
+        let body = multipart_body("XYZ", "", &[(0, 9), (20, 29)]);
+
+        // Cut in the last part's data, in its headers and in the final
+        // delimiter
+        for cut in [body.len() - 15, body.len() - 60, body.len() - 4] {
+            assert!(parse_multipart(&body[..cut], "XYZ").is_none(), "{}", cut);
+        }
+
+        assert!(parse_multipart(&body, "ABC").is_none());
+    }
+
+    #[test]
+    fn rejects_parts_without_a_content_range() {
+        let body = b"--XYZ\r\nContent-Type: application/octet-stream\r\n\r\n0123456789\r\n--XYZ--";
+
+        assert!(parse_multipart(body, "XYZ").is_none());
+    }
+
+    /// The (start, end) ranges in a request's Range header
+    fn requested(headers: &HashMap<String, String>) -> Vec<(u64, u64)> {
+        headers["range"]
+            .trim_start_matches("bytes=")
+            .split(',')
+            .map(|range| {
+                let (start, end) = range.split_once('-').unwrap();
+                (start.parse().unwrap(), end.parse().unwrap())
+            })
+            .collect()
+    }
+
+    /// Answer with the ranges asked for, as one multipart/byteranges part
+    /// each if there's more than one
+    fn answer(headers: &HashMap<String, String>) -> Reply {
+        match requested(headers).as_slice() {
+            &[(start, end)] => Reply::partial(start, end, 1000, data(start, end)),
+            parts => Reply {
+                status: "206 Partial Content",
+                headers: vec![(
+                    "Content-Type",
+                    "multipart/byteranges; boundary=XYZ".to_string(),
+                )],
+                body: multipart_body("XYZ", "", parts),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_in_the_order_asked_for() {
+        let (url, requests) = serve(|_, headers| {
+            assert_eq!(headers["range"], "bytes=100-109,0-9,200-209");
+
+            Reply {
+                status: "206 Partial Content",
+                headers: vec![(
+                    "Content-Type",
+                    "multipart/byteranges; boundary=XYZ".to_string(),
+                )],
+                body: multipart_body("XYZ", "", &[(200, 209), (0, 9), (100, 109)]),
+            }
+        })
+        .await;
+
+        let result = reader(url)
+            .read_ranges(&[(100, 10), (50, 0), (0, 10), (200, 10), (0, 0)])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result,
+            [
+                data(100, 109),
+                Vec::new(),
+                data(0, 9),
+                data(200, 209),
+                Vec::new()
+            ]
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_single_ranges_when_multiple_get_the_full_body() {
+        let (url, requests) = serve(|_, headers| match requested(headers).len() {
+            1 => answer(headers),
+            _ => Reply {
+                status: "200 OK",
+                headers: Vec::new(),
+                body: data(0, 255),
+            },
+        })
+        .await;
+        let mut reader = reader(url);
+
+        let result = reader.read_ranges(&[(0, 10), (100, 10)]).await.unwrap();
+
+        assert_eq!(result, [data(0, 9), data(100, 109)]);
+        assert!(reader.multiple_ranges_unsupported);
+        assert!(!reader.has_full_body());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Straight to single ranges from then on
+        let result = reader.read_ranges(&[(20, 10), (30, 10)]).await.unwrap();
+
+        assert_eq!(result, [data(20, 29), data(30, 39)]);
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn batches_ranges_by_max_ranges_per_request() {
+        let (url, requests) = serve(|n, headers| {
+            assert_eq!(requested(headers).len(), if n < 2 { 2 } else { 1 });
+
+            answer(headers)
+        })
+        .await;
+        let mut reader = HttpRangeReader::builder(url)
+            .max_ranges_per_request(2)
+            .build();
+        let wanted = [(0, 10), (20, 10), (40, 10), (60, 10), (80, 10)];
+
+        let result = reader.read_ranges(&wanted).await.unwrap();
+
+        assert_eq!(
+            result,
+            wanted
+                .iter()
+                .map(|&(offset, length)| data(offset as u64, offset as u64 + length as u64 - 1))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
     }
 }
@@ -0,0 +1,223 @@
+use std::{borrow::Cow, fs::File, path::Path};
+
+use memmap2::Mmap;
+
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        range_reader::{RangeReader, RangeReaderSync},
+        types::{dat_database_header::DatDatabaseHeader, dat_directory_entry::DatDirectoryEntry},
+    },
+};
+
+/// Memory-mapped implementation of RangeReader and RangeReaderSync
+///
+/// Intended for batch jobs over local DATs. Ranges can be borrowed straight
+/// from the map with `slice` and `read_file` rather than being copied into a
+/// fresh Vec for every read.
+pub struct MmapRangeReader {
+    mmap: Mmap,
+}
+
+impl MmapRangeReader {
+    /// Map a DAT into memory
+    ///
+    /// # Safety
+    ///
+    /// The file mustn't be truncated or written to, by this process or any
+    /// other, for as long as the reader (or anything borrowed from it) is
+    /// alive. The map would change underneath the slices handed out, and a
+    /// truncated file makes reading the end of the map fault, which is
+    /// undefined behaviour.
+    pub unsafe fn new(file: &File) -> Result<Self, DatError> {
+        // SAFETY: The map is only ever read from, and the caller guarantees
+        // the file isn't changed while it's mapped
+        let mmap = unsafe { Mmap::map(file)? };
+
+        Ok(Self { mmap })
+    }
+
+    /// Open and map a DAT by path
+    ///
+    /// # Safety
+    ///
+    /// As for `new`: the file mustn't be truncated or written to while the
+    /// reader is alive.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<Self, DatError> {
+        // SAFETY: Passed on to the caller
+        unsafe { Self::new(&File::open(path)?) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+
+    /// Borrow a range of the file without copying it
+    pub fn slice(&self, offset: u32, length: usize) -> Result<&[u8], DatError> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(length)
+            .filter(|end| *end <= self.mmap.len())
+            .ok_or(DatError::OutOfBounds {
+                offset: start as u64,
+                length: length as u64,
+                size: self.mmap.len() as u64,
+            })?;
+
+        Ok(&self.mmap[start..end])
+    }
+
+    /// Read a file stored as a block chain starting at the given offset
+    ///
+    /// Every block starts with a pointer to the next one, so a file's data is
+    /// only contiguous in the map when it fits in a single block. Those files
+    /// are borrowed directly; anything longer is assembled into a new buffer.
+    ///
+    /// This returns the data as stored, so files whose entries are flagged
+    /// as compressed come back still compressed. Use `read_entry` to have
+    /// them decompressed.
+    pub fn read_file(
+        &self,
+        offset: u32,
+        size: u32,
+        validator: &mut BlockChainValidator,
+    ) -> Result<Cow<'_, [u8]>, DatError> {
+        validator.check_size(offset, size)?;
+        let data_size = validator.block_size() - 4;
+
+        if size <= data_size {
+            validator.visit(offset)?;
+            return Ok(Cow::Borrowed(self.slice(offset + 4, size as usize)?));
+        }
+
+        let mut buffer = Vec::with_capacity(size as usize);
+        let mut left_to_read = size;
+        let mut next_address = offset;
+
+        while left_to_read > 0 {
+            validator.visit(next_address)?;
+            let read_size = left_to_read.min(data_size);
+            let block = self.slice(next_address, read_size as usize + 4)?;
+            buffer.extend_from_slice(&block[4..]);
+            left_to_read -= read_size;
+            next_address = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        }
+
+        Ok(Cow::Owned(buffer))
+    }
+
+    /// Read (and decompress, if needed) the file a directory entry points
+    /// to, as DatFileReader::from_entry does
+    ///
+    /// Only uncompressed files that fit in a single block are borrowed.
+    pub fn read_entry(
+        &self,
+        header: &DatDatabaseHeader,
+        entry: &DatDirectoryEntry,
+    ) -> Result<Cow<'_, [u8]>, DatError> {
+        let mut validator = BlockChainValidator::new(header.block_size, header.file_size)
+            .for_object(entry.object_id);
+        let stored = self.read_file(entry.file_offset, entry.file_size, &mut validator)?;
+
+        if entry.is_compressed() {
+            return Ok(Cow::Owned(entry.decode(stored.into_owned())?));
+        }
+
+        Ok(stored)
+    }
+}
+
+impl RangeReader for MmapRangeReader {
+    async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        Ok(self.slice(offset, length)?.to_vec())
+    }
+}
+
+impl RangeReaderSync for MmapRangeReader {
+    fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        Ok(self.slice(offset, length)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{
+        enums::dat_database_type::DatDatabaseType,
+        reader::{sync_dat_file_reader::SyncDatFileReader, types::dat_database::DatDatabase},
+        writer::dat_writer::DatWriter,
+    };
+
+    /// Write a DAT to a temporary file and map it
+    fn mapped_dat(name: &str) -> (MmapRangeReader, Vec<u8>) {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+        writer.write_file(0x06000001, &[7; 10]).unwrap();
+        writer.write_file(0x06000002, &[9; 1000]).unwrap();
+        let dat = writer.finish().unwrap().into_inner();
+
+        let path =
+            std::env::temp_dir().join(format!("libac-rs-mmap-{}-{}.dat", name, std::process::id()));
+        std::fs::write(&path, &dat).unwrap();
+        // SAFETY: Nothing else knows about the file, and it's deleted (which
+        // leaves the map intact) rather than changed
+        let reader = unsafe { MmapRangeReader::open(&path) }.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        (reader, dat)
+    }
+
+    #[test]
+    fn reads_ranges_and_files() {
+        let (mut reader, dat) = mapped_dat("ranges");
+        let db = DatDatabase::read(&mut Cursor::new(&dat)).unwrap();
+
+        assert_eq!(reader.len(), dat.len());
+        assert_eq!(reader.slice(0x140, 16).unwrap(), &dat[0x140..0x150]);
+        assert_eq!(
+            RangeReaderSync::read_range(&mut reader, 0, dat.len()).unwrap(),
+            dat
+        );
+
+        let small = db.find_file(0x06000001).unwrap();
+        let data = reader.read_entry(&db.header, small).unwrap();
+        assert!(matches!(data, Cow::Borrowed(_)));
+        assert_eq!(data.as_ref(), [7; 10]);
+
+        // Spread over several blocks, and read the same through
+        // SyncDatFileReader
+        let large = db.find_file(0x06000002).unwrap();
+        assert_eq!(
+            reader.read_entry(&db.header, large).unwrap().as_ref(),
+            [9; 1000]
+        );
+        let data = SyncDatFileReader::from_entry(&db.header, large)
+            .unwrap()
+            .read_file(&mut reader, large.file_offset)
+            .unwrap();
+        assert_eq!(data, [9; 1000]);
+    }
+
+    #[test]
+    fn reads_past_the_end_are_out_of_bounds() {
+        let (mut reader, dat) = mapped_dat("bounds");
+        let size = dat.len() as u64;
+
+        assert!(matches!(
+            reader.slice(dat.len() as u32 - 4, 8),
+            Err(DatError::OutOfBounds { offset, length: 8, size: s }) if offset == size - 4 && s == size
+        ));
+        assert!(matches!(
+            RangeReaderSync::read_range(&mut reader, u32::MAX, usize::MAX),
+            Err(DatError::OutOfBounds { .. })
+        ));
+        assert!(reader.slice(dat.len() as u32, 0).unwrap().is_empty());
+    }
+}
@@ -1,8 +1,14 @@
+#[cfg(feature = "core")]
+pub mod any_range_reader;
+pub mod block_chain_validator;
+pub mod caching_reader;
 pub mod dat_block_reader;
 pub mod dat_file_reader;
+pub mod dat_verifier;
 #[cfg(feature = "core")]
 pub mod file_reader;
 pub mod range_reader;
+pub mod shared_database;
 pub mod sync_dat_file_reader;
 pub mod sync_file_reader;
 pub mod types;
@@ -12,3 +18,6 @@ pub mod http_reader;
 
 #[cfg(feature = "cloudflare")]
 pub mod worker_r2_reader;
+
+#[cfg(feature = "mmap")]
+pub mod mmap_reader;
@@ -1,15 +1,148 @@
-pub trait RangeReader {
+use std::{future::Future, pin::Pin};
+
+use crate::dat::error::DatError;
+
+/// Reads byte ranges from a DAT, wherever it's stored
+///
+/// Readers and their futures are Send so reads can run on any tokio task or
+/// thread (see SharedDatDatabase).
+pub trait RangeReader: Send {
     fn read_range(
         &mut self,
         offset: u32,
         length: usize,
-    ) -> impl std::future::Future<Output = Result<Vec<u8>, Box<dyn std::error::Error>>>;
+    ) -> impl std::future::Future<Output = Result<Vec<u8>, DatError>> + Send;
+
+    /// Read several ranges, returning their data in the same order
+    ///
+    /// This reads them one after another. Readers that can fetch several
+    /// ranges at once (e.g. HttpRangeReader, with a multi-range request)
+    /// override it.
+    fn read_ranges(
+        &mut self,
+        ranges: &[(u32, usize)],
+    ) -> impl std::future::Future<Output = Result<Vec<Vec<u8>>, DatError>> + Send {
+        async move {
+            let mut results = Vec::with_capacity(ranges.len());
+
+            for &(offset, length) in ranges {
+                results.push(self.read_range(offset, length).await?);
+            }
+
+            Ok(results)
+        }
+    }
 }
 
-pub trait RangeReaderSync {
+/// The future DynRangeReader::read_range_dyn returns
+pub type RangeReadFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, DatError>> + Send + 'a>>;
+
+/// The future DynRangeReader::read_ranges_dyn returns
+pub type RangeReadsFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<Vec<u8>>, DatError>> + Send + 'a>>;
+
+/// An object-safe RangeReader, for backends chosen at runtime
+///
+/// Every RangeReader is a DynRangeReader, and `Box<dyn DynRangeReader>` is
+/// a RangeReader again, so a boxed reader can be used anywhere a RangeReader
+/// can (e.g. DatDatabase::read_async) at the cost of boxing each read's
+/// future.
+pub trait DynRangeReader: Send {
+    fn read_range_dyn(&mut self, offset: u32, length: usize) -> RangeReadFuture<'_>;
+
+    fn read_ranges_dyn<'a>(&'a mut self, ranges: &'a [(u32, usize)]) -> RangeReadsFuture<'a>;
+}
+
+impl<R: RangeReader> DynRangeReader for R {
+    fn read_range_dyn(&mut self, offset: u32, length: usize) -> RangeReadFuture<'_> {
+        Box::pin(self.read_range(offset, length))
+    }
+
+    fn read_ranges_dyn<'a>(&'a mut self, ranges: &'a [(u32, usize)]) -> RangeReadsFuture<'a> {
+        Box::pin(self.read_ranges(ranges))
+    }
+}
+
+impl RangeReader for Box<dyn DynRangeReader + '_> {
     fn read_range(
         &mut self,
         offset: u32,
         length: usize,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    ) -> impl std::future::Future<Output = Result<Vec<u8>, DatError>> + Send {
+        // Through the box, so this doesn't go back through the blanket
+        // DynRangeReader impl the box itself has
+        (**self).read_range_dyn(offset, length)
+    }
+
+    // An async fn, since the boxed future can only borrow self and the
+    // ranges for the shorter of their lifetimes
+    async fn read_ranges(&mut self, ranges: &[(u32, usize)]) -> Result<Vec<Vec<u8>>, DatError> {
+        (**self).read_ranges_dyn(ranges).await
+    }
+}
+
+pub trait RangeReaderSync {
+    fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError>;
+}
+
+#[cfg(all(test, feature = "core", feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{
+        enums::dat_database_type::DatDatabaseType,
+        reader::{
+            any_range_reader::AnyRangeReader, dat_file_reader::DatFileReader,
+            types::dat_database::DatDatabase,
+        },
+        writer::dat_writer::DatWriter,
+    };
+
+    fn contents(object_id: u32) -> Vec<u8> {
+        (0..object_id % 0x1000 * 3)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn boxed_readers_read_dats() {
+        let ids = [0x06000001, 0x06000200, 0x06000FFF];
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+
+        for object_id in ids {
+            writer.write_file(object_id, &contents(object_id)).unwrap();
+        }
+
+        let dat = writer.finish().unwrap().into_inner();
+        let path = std::env::temp_dir().join(format!("libac-rs-dyn-{}.dat", std::process::id()));
+        std::fs::write(&path, &dat).unwrap();
+
+        let mut reader = AnyRangeReader::open_dyn(path.to_str().unwrap())
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let db = DatDatabase::read_async(&mut reader).await.unwrap();
+        assert_eq!(db.list_files(true).unwrap().len(), ids.len());
+
+        for object_id in ids {
+            let entry = db.find_file(object_id).unwrap();
+            let data = DatFileReader::from_entry(&db.header, entry)
+                .unwrap()
+                .read_file(&mut reader, entry.file_offset)
+                .await
+                .unwrap();
+
+            assert_eq!(data, contents(object_id));
+        }
+
+        let ranges = [(0, 4), (dat.len() as u32 - 8, 8), (0x140, 0)];
+        let read = reader.read_ranges(&ranges).await.unwrap();
+
+        assert_eq!(read[0], dat[..4]);
+        assert_eq!(read[1], dat[dat.len() - 8..]);
+        assert!(read[2].is_empty());
+    }
 }
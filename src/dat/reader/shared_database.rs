@@ -0,0 +1,428 @@
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Poll, Waker},
+};
+
+use crate::dat::{
+    error::DatError,
+    reader::{
+        dat_file_reader::DatFileReader,
+        range_reader::RangeReader,
+        types::{dat_database::DatDatabase, dat_directory_entry::DatDirectoryEntry},
+    },
+};
+
+#[cfg(feature = "core")]
+use crate::dat::reader::any_range_reader::{AnyRangeReader, DatUri};
+
+type ReaderFuture<R> = Pin<Box<dyn Future<Output = Result<R, DatError>> + Send>>;
+type ReaderFactory<R> = Box<dyn Fn() -> ReaderFuture<R> + Send + Sync>;
+
+/// How many idle readers a SharedDatDatabase keeps by default
+pub const DEFAULT_MAX_IDLE_READERS: usize = 16;
+
+/// How many readers a SharedDatDatabase has open at once by default
+pub const DEFAULT_MAX_OPEN_READERS: usize = 64;
+
+struct ReaderPool<R> {
+    idle: Vec<R>,
+    /// Readers open, whether idle or in use, counting ones being opened
+    open: usize,
+    /// Reads waiting for a reader to be returned or closed
+    waiting: Vec<Waker>,
+}
+
+struct SharedDatDatabaseInner<R: RangeReader> {
+    database: DatDatabase,
+    open_reader: ReaderFactory<R>,
+    pool: Mutex<ReaderPool<R>>,
+    max_idle_readers: AtomicUsize,
+    max_open_readers: AtomicUsize,
+}
+
+/// A DatDatabase that any number of tasks can read from at once
+///
+/// RangeReader and DatFileReader need `&mut` access while reading, so each
+/// read takes a reader from a pool (opening a new one when they're all in
+/// use, or waiting for one once max_open_readers are open) and hands it back
+/// when it's done. Clones share the database and the pool, and every read
+/// future is Send, so the handle can be given to each request in a web
+/// server.
+pub struct SharedDatDatabase<R: RangeReader> {
+    inner: Arc<SharedDatDatabaseInner<R>>,
+}
+
+impl<R: RangeReader> Clone for SharedDatDatabase<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<R: RangeReader + 'static> SharedDatDatabase<R> {
+    /// Share an already read database, opening readers for it with
+    /// `open_reader`
+    ///
+    /// `reader` is the reader the database was read through, if it should be
+    /// reused.
+    pub fn new<F, Fut>(database: DatDatabase, reader: Option<R>, open_reader: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, DatError>> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(SharedDatDatabaseInner {
+                database,
+                open_reader: Box::new(move || Box::pin(open_reader())),
+                pool: Mutex::new(ReaderPool {
+                    open: usize::from(reader.is_some()),
+                    idle: reader.into_iter().collect(),
+                    waiting: Vec::new(),
+                }),
+                max_idle_readers: AtomicUsize::new(DEFAULT_MAX_IDLE_READERS),
+                max_open_readers: AtomicUsize::new(DEFAULT_MAX_OPEN_READERS),
+            }),
+        }
+    }
+
+    /// Open a reader with `open_reader`, read the database through it and
+    /// share it
+    pub async fn open<F, Fut>(open_reader: F) -> Result<Self, DatError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, DatError>> + Send + 'static,
+    {
+        let mut reader = open_reader().await?;
+        let database = DatDatabase::read_async(&mut reader).await?;
+
+        Ok(Self::new(database, Some(reader), open_reader))
+    }
+}
+
+impl<R: RangeReader> SharedDatDatabase<R> {
+    pub fn database(&self) -> &DatDatabase {
+        &self.inner.database
+    }
+
+    pub fn find_file(&self, object_id: u32) -> Option<DatDirectoryEntry> {
+        self.inner.database.find_file(object_id).copied()
+    }
+
+    /// Keep at most this many readers around between reads, for every clone
+    /// of this handle
+    pub fn set_max_idle_readers(&self, max_idle_readers: usize) {
+        self.inner
+            .max_idle_readers
+            .store(max_idle_readers, Ordering::Relaxed);
+
+        let mut pool = self.lock_pool();
+        let keep = pool.idle.len().min(max_idle_readers);
+        let closed = pool.idle.split_off(keep);
+        pool.open -= closed.len();
+        let waiting = std::mem::take(&mut pool.waiting);
+        drop(pool);
+        drop(closed);
+
+        waiting.into_iter().for_each(Waker::wake);
+    }
+
+    /// Have at most this many readers open at once, for every clone of this
+    /// handle, with reads past that waiting for one to be returned
+    ///
+    /// Lowering it doesn't close readers that are already open; they're
+    /// closed as they're returned until there are few enough.
+    pub fn set_max_open_readers(&self, max_open_readers: usize) {
+        self.inner
+            .max_open_readers
+            .store(max_open_readers.max(1), Ordering::Relaxed);
+
+        let waiting = std::mem::take(&mut self.lock_pool().waiting);
+        waiting.into_iter().for_each(Waker::wake);
+    }
+
+    /// Number of readers waiting in the pool
+    pub fn idle_readers(&self) -> usize {
+        self.lock_pool().idle.len()
+    }
+
+    /// Number of readers open, whether idle or in use
+    pub fn open_readers(&self) -> usize {
+        self.lock_pool().open
+    }
+
+    /// Read (and decompress, if needed) an object by ID
+    pub async fn read_file(&self, object_id: u32) -> Result<Vec<u8>, DatError> {
+        let entry = self
+            .find_file(object_id)
+            .ok_or_else(|| DatError::NotFound(format!("Object {:08X}", object_id)))?;
+
+        self.read_entry(&entry).await
+    }
+
+    /// Read (and decompress, if needed) the file a directory entry points to
+    pub async fn read_entry(&self, entry: &DatDirectoryEntry) -> Result<Vec<u8>, DatError> {
+        let mut reader = self.take_reader().await?;
+
+        let data = DatFileReader::from_entry(&self.inner.database.header, entry)?
+            .read_file(&mut *reader, entry.file_offset)
+            .await?;
+
+        // Readers whose reads fail are closed rather than reused, in case
+        // they're what's broken
+        reader.reuse = true;
+
+        Ok(data)
+    }
+
+    async fn take_reader(&self) -> Result<PooledReader<'_, R>, DatError> {
+        let idle = std::future::poll_fn(|cx| {
+            let mut pool = self.lock_pool();
+
+            if let Some(reader) = pool.idle.pop() {
+                return Poll::Ready(Some(reader));
+            }
+
+            if pool.open < self.inner.max_open_readers.load(Ordering::Relaxed) {
+                pool.open += 1;
+                return Poll::Ready(None);
+            }
+
+            pool.waiting.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await;
+
+        // From here on the reader's place in the pool is given back when
+        // this is dropped, even if opening the reader fails
+        let mut reader = PooledReader {
+            shared: self,
+            reader: idle,
+            reuse: false,
+        };
+
+        if reader.reader.is_none() {
+            reader.reader = Some((self.inner.open_reader)().await?);
+        }
+
+        Ok(reader)
+    }
+
+    fn lock_pool(&self) -> std::sync::MutexGuard<'_, ReaderPool<R>> {
+        // The pool's count is only changed along with the reader it counts,
+        // so it's still usable if a thread panicked while holding the lock
+        self.inner
+            .pool
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A reader taken from a SharedDatDatabase's pool, returned to it (if
+/// `reuse` is set and there's room) or closed when dropped
+struct PooledReader<'a, R: RangeReader> {
+    shared: &'a SharedDatDatabase<R>,
+    /// None only while the reader is being opened
+    reader: Option<R>,
+    reuse: bool,
+}
+
+impl<R: RangeReader> Deref for PooledReader<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.reader.as_ref().expect("reader is open")
+    }
+}
+
+impl<R: RangeReader> DerefMut for PooledReader<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.reader.as_mut().expect("reader is open")
+    }
+}
+
+impl<R: RangeReader> Drop for PooledReader<'_, R> {
+    fn drop(&mut self) {
+        let inner = &self.shared.inner;
+        let mut pool = self.shared.lock_pool();
+        let keep = self.reuse
+            && pool.idle.len() < inner.max_idle_readers.load(Ordering::Relaxed)
+            && pool.open <= inner.max_open_readers.load(Ordering::Relaxed);
+
+        // Closed readers are dropped after the lock is released
+        let closed = match (keep, self.reader.take()) {
+            (true, Some(reader)) => {
+                pool.idle.push(reader);
+                None
+            }
+            (_, reader) => {
+                pool.open -= 1;
+                reader
+            }
+        };
+
+        let waiting = std::mem::take(&mut pool.waiting);
+        drop(pool);
+        drop(closed);
+
+        waiting.into_iter().for_each(Waker::wake);
+    }
+}
+
+#[cfg(feature = "core")]
+impl SharedDatDatabase<AnyRangeReader> {
+    /// Open a DAT by path or URI, with a new file handle or HTTP reader for
+    /// each concurrent read
+    pub async fn open_uri(uri: &str) -> Result<Self, DatError> {
+        let uri = DatUri::parse(uri)?;
+
+        Self::open(move || {
+            let uri = uri.clone();
+            async move { AnyRangeReader::open_uri(&uri).await }
+        })
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{enums::dat_database_type::DatDatabaseType, writer::dat_writer::DatWriter};
+
+    /// Reads from a DAT in memory, keeping count of how many are open
+    struct MemoryReader {
+        dat: Arc<Vec<u8>>,
+        open: Arc<AtomicUsize>,
+    }
+
+    impl MemoryReader {
+        fn new(dat: Arc<Vec<u8>>, open: Arc<AtomicUsize>, most_open: Arc<AtomicUsize>) -> Self {
+            let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+            most_open.fetch_max(now_open, Ordering::SeqCst);
+
+            Self { dat, open }
+        }
+    }
+
+    impl Drop for MemoryReader {
+        fn drop(&mut self) {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl RangeReader for MemoryReader {
+        async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+            // Let other tasks in between reads, so they need readers at once
+            tokio::task::yield_now().await;
+
+            let start = offset as usize;
+
+            self.dat
+                .get(start..start + length)
+                .map(|data| data.to_vec())
+                .ok_or(DatError::OutOfBounds {
+                    offset: offset as u64,
+                    length: length as u64,
+                    size: self.dat.len() as u64,
+                })
+        }
+    }
+
+    fn contents(object_id: u32) -> Vec<u8> {
+        vec![object_id as u8; 100 + object_id as usize % 7 * 300]
+    }
+
+    fn dat() -> Arc<Vec<u8>> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+
+        for object_id in 0..50 {
+            writer.write_file(object_id, &contents(object_id)).unwrap();
+        }
+
+        Arc::new(writer.finish().unwrap().into_inner())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_from_many_tasks_with_a_bounded_number_of_readers() {
+        let dat = dat();
+        let open = Arc::new(AtomicUsize::new(0));
+        let most_open = Arc::new(AtomicUsize::new(0));
+        let shared = SharedDatDatabase::open({
+            let open = open.clone();
+            let most_open = most_open.clone();
+
+            move || {
+                let reader = MemoryReader::new(dat.clone(), open.clone(), most_open.clone());
+                async move { Ok(reader) }
+            }
+        })
+        .await
+        .unwrap();
+        shared.set_max_open_readers(3);
+
+        let tasks: Vec<_> = (0..20)
+            .map(|task| {
+                let shared = shared.clone();
+
+                tokio::spawn(async move {
+                    for i in 0..50 {
+                        let object_id = (i * 7 + task) % 50;
+                        let data = shared.read_file(object_id).await.unwrap();
+
+                        assert_eq!(data, contents(object_id));
+                    }
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(most_open.load(Ordering::SeqCst), 3);
+        assert!(shared.open_readers() <= 3);
+        assert_eq!(shared.open_readers(), open.load(Ordering::SeqCst));
+        assert_eq!(shared.idle_readers(), shared.open_readers());
+    }
+
+    #[tokio::test]
+    async fn gives_back_the_place_of_a_reader_that_failed_to_open() {
+        let dat = dat();
+        let database = DatDatabase::read(&mut Cursor::new(dat.as_slice())).unwrap();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let open = Arc::new(AtomicUsize::new(0));
+        let most_open = Arc::new(AtomicUsize::new(0));
+        let shared = SharedDatDatabase::new(database, None, {
+            let opened = opened.clone();
+
+            move || {
+                let result = match opened.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(DatError::Http("unavailable".to_string())),
+                    _ => Ok(MemoryReader::new(
+                        dat.clone(),
+                        open.clone(),
+                        most_open.clone(),
+                    )),
+                };
+                async move { result }
+            }
+        });
+        shared.set_max_open_readers(1);
+
+        assert!(shared.read_file(1).await.is_err());
+        assert_eq!(shared.open_readers(), 0);
+
+        assert_eq!(shared.read_file(1).await.unwrap(), contents(1));
+        assert_eq!(shared.open_readers(), 1);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+}
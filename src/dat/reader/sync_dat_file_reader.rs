@@ -1,4 +1,14 @@
-use crate::dat::reader::{range_reader::RangeReaderSync, types::dat_block::DatBlock};
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        range_reader::RangeReaderSync,
+        types::{
+            dat_block::DatBlock, dat_database_header::DatDatabaseHeader,
+            dat_directory_entry::DatDirectoryEntry, dat_entry_flags::decompress,
+        },
+    },
+};
 
 /// A synchronous entry point for reading block-based files
 #[derive(Debug)]
@@ -6,29 +16,67 @@ pub struct SyncDatFileReader {
     pub size: usize,
     pub block_size: usize,
     pub left_to_read: usize,
+    /// Decompress what's read (see DatDirectoryEntry::decode)
+    pub compressed: bool,
+    validator: BlockChainValidator,
 }
 
 impl SyncDatFileReader {
-    pub fn new(size: usize, block_size: usize) -> Result<Self, Box<dyn std::error::Error>> {
-        if block_size < 4 {
-            return Err("block_size must be at least 4 bytes (for the pointer)".into());
+    /// Create a reader for a file of `size` bytes in a DAT with the given
+    /// block size and total size (both from its DatDatabaseHeader)
+    pub fn new(size: usize, block_size: usize, file_size: u32) -> Result<Self, DatError> {
+        if block_size <= 4 {
+            return Err(DatError::InvalidData(
+                "block_size must be more than 4 bytes (for the pointer)".to_string(),
+            ));
         }
         Ok(Self {
             size,
             block_size,
             left_to_read: size,
+            compressed: false,
+            validator: BlockChainValidator::new(block_size as u32, file_size),
         })
     }
 
+    /// Create a reader for the file described by a directory entry, which
+    /// decompresses the file if the entry says it's compressed
+    pub fn from_entry(
+        header: &DatDatabaseHeader,
+        entry: &DatDirectoryEntry,
+    ) -> Result<Self, DatError> {
+        let mut reader = Self::new(
+            entry.file_size as usize,
+            header.block_size as usize,
+            header.file_size,
+        )?
+        .for_object(entry.object_id);
+        reader.compressed = entry.is_compressed();
+
+        Ok(reader)
+    }
+
+    /// Name the object being read in any corruption errors
+    pub fn for_object(mut self, object_id: u32) -> Self {
+        self.validator = self.validator.for_object(object_id);
+        self
+    }
+
+    /// The validator tracking the blocks read so far
+    pub fn validator(&self) -> &BlockChainValidator {
+        &self.validator
+    }
+
     /// Read a file starting at the given offset for the specified total size
-    pub fn read_file<R>(
-        &mut self,
-        reader: &mut R,
-        start_offset: u32,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+    pub fn read_file<R>(&mut self, reader: &mut R, start_offset: u32) -> Result<Vec<u8>, DatError>
     where
         R: RangeReaderSync,
     {
+        let size = u32::try_from(self.size).map_err(|_| {
+            DatError::InvalidData(format!("File size {} is too large for a DAT", self.size))
+        })?;
+        self.validator.check_size(start_offset, size)?;
+
         let mut buffer = Vec::with_capacity(self.size);
         let mut next_address = start_offset;
 
@@ -41,6 +89,10 @@ impl SyncDatFileReader {
             }
         }
 
+        if self.compressed {
+            return decompress(&buffer, self.validator.object_id().unwrap_or_default());
+        }
+
         Ok(buffer)
     }
 
@@ -48,17 +100,15 @@ impl SyncDatFileReader {
     ///
     /// A block is [ next_offset, data ]. For the last block, the next_offset
     /// is 0
-    fn read_block<R>(
-        &mut self,
-        reader: &mut R,
-        offset: u32,
-    ) -> Result<DatBlock, Box<dyn std::error::Error>>
+    fn read_block<R>(&mut self, reader: &mut R, offset: u32) -> Result<DatBlock, DatError>
     where
         R: RangeReaderSync,
     {
         // Determine the size of the next read. This is either an entire block
         // when we have more than a block worth of data to read or whatever is
         // left to read (+ 4 bytes for the pointer).
+        self.validator.visit(offset)?;
+
         let next_read_size = std::cmp::min(self.block_size, self.left_to_read + 4);
         self.left_to_read -= next_read_size - 4;
 
@@ -66,16 +116,18 @@ impl SyncDatFileReader {
         let block_data = reader.read_range(offset, next_read_size)?;
 
         if block_data.len() < 4 {
-            return Err("Block too small to contain pointer".into());
+            return Err(self
+                .validator
+                .corrupt(offset, "Block too small to contain pointer".to_string()));
         }
 
         // Create and return the DatBlock
-        let next_offset_bytes: [u8; 4] = block_data[0..4].try_into()?;
-        let next_block_offset = u32::from_le_bytes(next_offset_bytes);
+        let next_block_offset =
+            u32::from_le_bytes([block_data[0], block_data[1], block_data[2], block_data[3]]);
         let data: Vec<u8> = block_data[4..].to_vec();
 
         Ok(DatBlock {
-            next_block_offset: next_block_offset,
+            next_block_offset,
             data,
         })
     }
@@ -1,6 +1,9 @@
 use std::io::{Read, Seek, SeekFrom};
 
-use crate::dat::reader::range_reader::RangeReaderSync;
+use crate::dat::{
+    error::DatError,
+    reader::range_reader::{RangeReader, RangeReaderSync},
+};
 
 pub struct SyncFileRangeReader<R> {
     reader: R,
@@ -23,14 +26,33 @@ where
         &mut self,
         offset: u32,
         length: usize,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    ) -> Result<Vec<u8>, DatError> {
         // Seek to the position
         self.reader.seek(SeekFrom::Start(offset.into()))?;
 
-        // Read exactly the requested bytes
+        // Read exactly the requested bytes, reporting a read past the end
+        // the same way the other readers do
         let mut buffer = vec![0u8; length];
-        self.reader.read_exact(&mut buffer)?;
 
-        Ok(buffer)
+        match self.reader.read_exact(&mut buffer) {
+            Ok(()) => Ok(buffer),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(DatError::OutOfBounds {
+                offset: offset as u64,
+                length: length as u64,
+                size: self.reader.seek(SeekFrom::End(0))?,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Reads block the calling thread, so this is only suited to local files and
+/// to executors where that's acceptable (e.g. futures::executor::block_on)
+impl<R> RangeReader for SyncFileRangeReader<R>
+where
+    R: Read + Seek + Send,
+{
+    async fn read_range(&mut self, offset: u32, length: usize) -> Result<Vec<u8>, DatError> {
+        RangeReaderSync::read_range(self, offset, length)
     }
 }
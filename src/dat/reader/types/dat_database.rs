@@ -1,39 +1,195 @@
-use std::{
-    error::Error,
-    io::{Read, Seek},
-};
+use std::io::{Read, Seek};
 
 use super::{
-    dat_database_header::DatDatabaseHeader, dat_directory::DatDirectory,
+    dat_database_header::DatDatabaseHeader,
+    dat_directory::DatDirectory,
     dat_directory_entry::DatDirectoryEntry,
+    dat_index::{DatIndex, DatIndexKey},
+};
+use crate::dat::{
+    enums::{dat_database_type::DatDatabaseType, dat_file_type::DatFileType},
+    error::DatError,
+    info::DAT_ITERATION_FILE_ID,
+    reader::range_reader::RangeReader,
 };
-use crate::dat::reader::range_reader::RangeReader;
 
 #[derive(Debug)]
 pub struct DatDatabase {
     pub header: DatDatabaseHeader,
     pub root_dir: DatDirectory,
+    /// Detected when the database is read (see detect_type), and can be
+    /// overridden with with_database_type
+    pub database_type: DatDatabaseType,
+    /// What a DatIndex of this database is keyed on
+    pub index_key: DatIndexKey,
 }
 
 impl DatDatabase {
-    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDatabase, Box<dyn Error>> {
+    /// Wrap a directory tree read from the DAT
+    fn new(header: DatDatabaseHeader, root_dir: DatDirectory) -> Result<DatDatabase, DatError> {
+        let index_key = DatIndexKey::new(&header, root_dir.node())?;
+
+        Self::with_key(header, root_dir, index_key)
+    }
+
+    fn with_key(
+        header: DatDatabaseHeader,
+        root_dir: DatDirectory,
+        index_key: DatIndexKey,
+    ) -> Result<DatDatabase, DatError> {
+        // Only look through the entries when the header doesn't say
+        let database_type = match header.database_type() {
+            Some(database_type) => database_type,
+            None => {
+                let mut entries = Vec::new();
+                root_dir.list_files(&mut entries, true)?;
+                Self::detect_type(&header, &entries)
+            }
+        };
+
+        Ok(DatDatabase {
+            header,
+            root_dir,
+            database_type,
+            index_key,
+        })
+    }
+
+    /// Work out what kind of database a DAT is
+    ///
+    /// The header's data_set is used when it's one we know. Otherwise the DAT
+    /// is taken to be a cell DAT if it has at least one landblock (xxyyFFFF)
+    /// and nothing with a cell number below 0x100, which every portal DAT
+    /// has plenty of (e.g. 0x06000001). Anything else is assumed to be a
+    /// portal DAT.
+    pub fn detect_type(
+        header: &DatDatabaseHeader,
+        entries: &[DatDirectoryEntry],
+    ) -> DatDatabaseType {
+        if let Some(database_type) = header.database_type() {
+            return database_type;
+        }
+
+        // Every kind of DAT has an iteration file
+        let ids = entries
+            .iter()
+            .map(|entry| entry.object_id)
+            .filter(|&object_id| object_id != DAT_ITERATION_FILE_ID);
+
+        let mut has_landblock = false;
+
+        for object_id in ids {
+            match object_id & 0xFFFF {
+                0xFFFF => has_landblock = true,
+                0x0000..=0x00FF => return DatDatabaseType::Portal,
+                _ => {}
+            }
+        }
+
+        if has_landblock {
+            DatDatabaseType::Cell
+        } else {
+            DatDatabaseType::Portal
+        }
+    }
+
+    /// Treat the database as a different kind, for when detect_type gets it
+    /// wrong
+    pub fn with_database_type(mut self, database_type: DatDatabaseType) -> Self {
+        self.database_type = database_type;
+        self
+    }
+
+    /// The type of a file in this database
+    pub fn file_type(&self, entry: &DatDirectoryEntry) -> DatFileType {
+        entry.file_type_in(&self.database_type)
+    }
+
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDatabase, DatError> {
         let header: DatDatabaseHeader = DatDatabaseHeader::read(reader)?;
-        let root_dir = DatDirectory::read(reader, header.btree, header.block_size)?;
+        let root_dir =
+            DatDirectory::read(reader, header.btree, header.block_size, header.file_size)?;
 
-        Ok(DatDatabase { header, root_dir })
+        Self::new(header, root_dir)
     }
 
-    pub async fn read_async<R: RangeReader>(reader: &mut R) -> Result<DatDatabase, Box<dyn Error>> {
+    pub async fn read_async<R: RangeReader>(reader: &mut R) -> Result<DatDatabase, DatError> {
         let header: DatDatabaseHeader = DatDatabaseHeader::read_async(reader).await?;
-        let root_dir = DatDirectory::read_async(reader, header.btree, header.block_size).await?;
+        let root_dir =
+            DatDirectory::read_async(reader, header.btree, header.block_size, header.file_size)
+                .await?;
 
-        Ok(DatDatabase { header, root_dir })
+        Self::new(header, root_dir)
     }
 
-    pub fn list_files(&self, recursive: bool) -> Result<Vec<DatDirectoryEntry>, Box<dyn Error>> {
+    /// Read a database, using the given index instead of walking the directory
+    /// tree when the index is still valid for the DAT's header and root node
+    pub fn read_with_index<R: Read + Seek>(
+        reader: &mut R,
+        index: Option<&DatIndex>,
+    ) -> Result<DatDatabase, DatError> {
+        let header: DatDatabaseHeader = DatDatabaseHeader::read(reader)?;
+
+        if let Some(index) = index {
+            let root = DatDirectory::read_node_header(
+                reader,
+                header.btree,
+                header.block_size,
+                header.file_size,
+            )?;
+            let index_key = DatIndexKey::new(&header, &root)?;
+
+            if index.is_valid_for(&index_key) {
+                let root_dir = DatDirectory::from_entries(index.entries.clone());
+
+                return Self::with_key(header, root_dir, index_key);
+            }
+        }
+
+        let root_dir =
+            DatDirectory::read(reader, header.btree, header.block_size, header.file_size)?;
+
+        Self::new(header, root_dir)
+    }
+
+    pub async fn read_async_with_index<R: RangeReader>(
+        reader: &mut R,
+        index: Option<&DatIndex>,
+    ) -> Result<DatDatabase, DatError> {
+        let header: DatDatabaseHeader = DatDatabaseHeader::read_async(reader).await?;
+
+        if let Some(index) = index {
+            let root = DatDirectory::read_node_header_async(
+                reader,
+                header.btree,
+                header.block_size,
+                header.file_size,
+            )
+            .await?;
+            let index_key = DatIndexKey::new(&header, &root)?;
+
+            if index.is_valid_for(&index_key) {
+                let root_dir = DatDirectory::from_entries(index.entries.clone());
+
+                return Self::with_key(header, root_dir, index_key);
+            }
+        }
+
+        let root_dir =
+            DatDirectory::read_async(reader, header.btree, header.block_size, header.file_size)
+                .await?;
+
+        Self::new(header, root_dir)
+    }
+
+    pub fn list_files(&self, recursive: bool) -> Result<Vec<DatDirectoryEntry>, DatError> {
         let mut files_list: Vec<DatDirectoryEntry> = Vec::new();
         self.root_dir.list_files(&mut files_list, recursive)?;
 
         Ok(files_list)
     }
+
+    pub fn find_file(&self, object_id: u32) -> Option<&DatDirectoryEntry> {
+        self.root_dir.find_file(object_id)
+    }
 }
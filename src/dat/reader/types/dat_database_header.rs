@@ -1,14 +1,19 @@
-use std::{
-    error::Error,
-    io::{Read, Seek, SeekFrom},
-};
+use std::io::{Read, Seek, SeekFrom, Write};
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use crate::dat::reader::range_reader::RangeReader;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::dat::{
+    enums::dat_database_type::DatDatabaseType, error::DatError,
+    reader::range_reader::RangeReader,
+};
 
 pub const DAT_HEADER_OFFSET: u64 = 0x140;
+/// Size of the header data: 16 u32s + 16 bytes for version_major
+pub const DAT_HEADER_SIZE: usize = 16 * 4 + 16;
+/// Value of DatDatabaseHeader::file_type in every DAT ("BT")
+pub const DAT_FILE_TYPE: u32 = 0x5442;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DatDatabaseHeader {
     pub file_type: u32,
     pub block_size: u32,
@@ -31,7 +36,7 @@ pub struct DatDatabaseHeader {
 
 impl DatDatabaseHeader {
     /// Common function to parse header data from a buffer
-    fn from_buffer<R: Read>(reader: &mut R) -> Result<DatDatabaseHeader, Box<dyn Error>> {
+    fn from_buffer<R: Read>(reader: &mut R) -> Result<DatDatabaseHeader, DatError> {
         let file_type = reader.read_u32::<LittleEndian>()?;
         let block_size = reader.read_u32::<LittleEndian>()?;
         let file_size = reader.read_u32::<LittleEndian>()?;
@@ -72,17 +77,141 @@ impl DatDatabaseHeader {
         })
     }
 
-    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDatabaseHeader, Box<dyn Error>> {
+    /// The kind of DAT this is, if the header is a DAT header with a
+    /// data_set we know
+    pub fn database_type(&self) -> Option<DatDatabaseType> {
+        if self.file_type != DAT_FILE_TYPE {
+            return None;
+        }
+
+        DatDatabaseType::from_u32(self.data_set)
+    }
+
+    /// Offset of the first block, right after the header
+    pub fn first_block_offset(&self) -> u64 {
+        let header_end = DAT_HEADER_OFFSET + DAT_HEADER_SIZE as u64;
+
+        header_end.div_ceil(self.block_size.max(1) as u64) * self.block_size.max(1) as u64
+    }
+
+    /// Everything about the header that doesn't look like a valid DAT
+    ///
+    /// An empty list doesn't mean the rest of the DAT is fine, only that the
+    /// header is self-consistent; see DatVerifier for that.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.file_type != DAT_FILE_TYPE {
+            problems.push(format!(
+                "Magic is 0x{:08X}, expected 0x{:08X} (\"BT\")",
+                self.file_type, DAT_FILE_TYPE
+            ));
+        }
+
+        if DatDatabaseType::from_u32(self.data_set).is_none() {
+            problems.push(format!("Unknown data set {}", self.data_set));
+        }
+
+        if self.block_size <= 4 {
+            problems.push(format!(
+                "Block size {} is too small to hold a pointer and data",
+                self.block_size
+            ));
+
+            // Everything below is measured in blocks
+            return problems;
+        }
+
+        if !self.block_size.is_power_of_two() {
+            problems.push(format!(
+                "Block size {} isn't a power of two",
+                self.block_size
+            ));
+        }
+
+        let first_block = self.first_block_offset();
+        let file_size = self.file_size as u64;
+
+        if file_size < first_block {
+            problems.push(format!(
+                "File size {} is smaller than the header ({} bytes)",
+                file_size, first_block
+            ));
+        } else if !(file_size - first_block).is_multiple_of(self.block_size as u64) {
+            problems.push(format!(
+                "File size {} isn't a whole number of {} byte blocks",
+                file_size, self.block_size
+            ));
+        }
+
+        let in_blocks = |offset: u32| {
+            offset as u64 >= first_block
+                && offset as u64 + self.block_size as u64 <= file_size
+                && offset.is_multiple_of(self.block_size)
+        };
+
+        if !in_blocks(self.btree) {
+            problems.push(format!(
+                "Directory root {} isn't a block in the file",
+                self.btree
+            ));
+        }
+
+        if self.free_count == 0 {
+            if self.free_head != 0 {
+                problems.push(format!(
+                    "Free list is empty but starts at {}",
+                    self.free_head
+                ));
+            }
+        } else {
+            for (name, offset) in [("head", self.free_head), ("tail", self.free_tail)] {
+                if !in_blocks(offset) {
+                    problems.push(format!(
+                        "Free list {} {} isn't a block in the file",
+                        name, offset
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDatabaseHeader, DatError> {
         reader.seek(SeekFrom::Start(DAT_HEADER_OFFSET))?;
         Self::from_buffer(reader)
     }
 
-    pub async fn read_async<R: RangeReader>(reader: &mut R) -> Result<DatDatabaseHeader, Box<dyn Error>> {
-        // Calculate the total size of the header data: 16 u32s + 16 bytes for version_major
-        const HEADER_SIZE: usize = 16 * 4 + 16;
-        
+    /// Write the header data (but not the bytes before DAT_HEADER_OFFSET)
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_u32::<LittleEndian>(self.file_type)?;
+        writer.write_u32::<LittleEndian>(self.block_size)?;
+        writer.write_u32::<LittleEndian>(self.file_size)?;
+        writer.write_u32::<LittleEndian>(self.data_set)?;
+        writer.write_u32::<LittleEndian>(self.data_subset)?;
+        writer.write_u32::<LittleEndian>(self.free_head)?;
+        writer.write_u32::<LittleEndian>(self.free_tail)?;
+        writer.write_u32::<LittleEndian>(self.free_count)?;
+        writer.write_u32::<LittleEndian>(self.btree)?;
+        writer.write_u32::<LittleEndian>(self.new_lru)?;
+        writer.write_u32::<LittleEndian>(self.old_lru)?;
+        writer.write_u32::<LittleEndian>(self.use_lru as u32)?;
+        writer.write_u32::<LittleEndian>(self.master_map_id)?;
+        writer.write_u32::<LittleEndian>(self.engine_pack_version)?;
+        writer.write_u32::<LittleEndian>(self.game_pack_version)?;
+
+        let mut version_major = self.version_major.clone();
+        version_major.resize(16, 0);
+        writer.write_all(&version_major)?;
+        writer.write_u32::<LittleEndian>(self.version_minor)?;
+
+        Ok(())
+    }
+
+    pub async fn read_async<R: RangeReader>(reader: &mut R) -> Result<DatDatabaseHeader, DatError> {
         // Read the header data in one range read operation
-        let data = reader.read_range(DAT_HEADER_OFFSET as u32, HEADER_SIZE).await?;
+        let data = reader.read_range(DAT_HEADER_OFFSET as u32, DAT_HEADER_SIZE).await?;
         let mut cursor = std::io::Cursor::new(data);
         
         Self::from_buffer(&mut cursor)
@@ -1,16 +1,24 @@
 use std::{
-    error::Error,
+    collections::HashSet,
     io::{Cursor, Read, Seek},
 };
 
-use crate::dat::reader::{
-    dat_block_reader::DatBlockReader,
-    range_reader::RangeReader,
-    types::{dat_directory_entry::DatDirectoryEntry, dat_directory_header::DatDirectoryHeader},
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        range_reader::RangeReader,
+        types::{dat_directory_entry::DatDirectoryEntry, dat_directory_header::DatDirectoryHeader},
+    },
+    writer::dat_btree::{DatBTree, DatBTreeNode},
 };
 
 pub const DAT_DIRECTORY_HEADER_OBJECT_SIZE: u32 = 0x6B4;
 
+type DatDirectoryFuture<'a> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<DatDirectory, DatError>> + Send + 'a>>;
+
 #[derive(Debug)]
 pub struct DatDirectory {
     header: DatDirectoryHeader,
@@ -18,23 +26,70 @@ pub struct DatDirectory {
 }
 
 impl DatDirectory {
+    /// Build a directory tree holding the given entries
+    ///
+    /// This is what a DatDatabase opened from a DatIndex uses in place of the
+    /// tree in the DAT, since only the entries are needed for listing files
+    /// and looking them up. They're put into a B-tree the way DatWriter
+    /// would, so no node holds more than 61 entries. The nodes aren't stored
+    /// anywhere, so their branch offsets are all 0.
+    pub fn from_entries(entries: Vec<DatDirectoryEntry>) -> DatDirectory {
+        let mut tree = DatBTree::default();
+
+        for entry in entries {
+            tree.insert(entry);
+        }
+
+        Self::from_btree_node(tree.root)
+    }
+
+    fn from_btree_node(node: DatBTreeNode) -> DatDirectory {
+        DatDirectory {
+            header: DatDirectoryHeader {
+                branches: vec![0; 62],
+                entry_count: node.entries.len() as u32,
+                entries: node.entries,
+            },
+            directories: node
+                .children
+                .into_iter()
+                .map(Self::from_btree_node)
+                .collect(),
+        }
+    }
+
     pub fn read<R: Read + Seek>(
         reader: &mut R,
         offset: u32,
         block_size: u32,
-    ) -> Result<DatDirectory, Box<dyn Error>> {
-        // Read DatDirectoryHeader
-        let header_buf =
-            DatBlockReader::read(reader, offset, DAT_DIRECTORY_HEADER_OBJECT_SIZE, block_size)?;
-        let mut header_reader = Cursor::new(header_buf);
-        let header = DatDirectoryHeader::read(&mut header_reader)?;
+        file_size: u32,
+    ) -> Result<DatDirectory, DatError> {
+        Self::read_node(reader, offset, block_size, file_size, &mut HashSet::new())
+    }
+
+    fn read_node<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        block_size: u32,
+        file_size: u32,
+        seen: &mut HashSet<u32>,
+    ) -> Result<DatDirectory, DatError> {
+        Self::check_node(offset, seen)?;
+
+        let header = Self::read_node_header(reader, offset, block_size, file_size)?;
 
         let mut directories: Vec<DatDirectory> = Vec::new();
 
         // Recurse only if we're not a leaf
         if header.branches[0] != 0 {
             for i in 0..header.entry_count + 1 {
-                let dir = DatDirectory::read(reader, header.branches[i as usize], block_size)?;
+                let dir = DatDirectory::read_node(
+                    reader,
+                    header.branches[i as usize],
+                    block_size,
+                    file_size,
+                    seen,
+                )?;
                 directories.push(dir);
             }
         }
@@ -45,23 +100,41 @@ impl DatDirectory {
         })
     }
 
-    pub fn read_async<R: RangeReader>(
+    pub async fn read_async<R: RangeReader>(
         reader: &mut R,
         offset: u32,
         block_size: u32,
-    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<DatDirectory, Box<dyn Error>>> + '_>> {
+        file_size: u32,
+    ) -> Result<DatDirectory, DatError> {
+        Self::read_node_async(reader, offset, block_size, file_size, &mut HashSet::new()).await
+    }
+
+    fn read_node_async<'a, R: RangeReader>(
+        reader: &'a mut R,
+        offset: u32,
+        block_size: u32,
+        file_size: u32,
+        seen: &'a mut HashSet<u32>,
+    ) -> DatDirectoryFuture<'a> {
         Box::pin(async move {
-            // Read DatDirectoryHeader using async block reader
-            let header_buf = DatBlockReader::read_async(reader, offset, DAT_DIRECTORY_HEADER_OBJECT_SIZE, block_size).await?;
-            let mut header_reader = Cursor::new(header_buf);
-            let header = DatDirectoryHeader::read(&mut header_reader)?;
+            Self::check_node(offset, seen)?;
+
+            let header =
+                Self::read_node_header_async(reader, offset, block_size, file_size).await?;
 
             let mut directories: Vec<DatDirectory> = Vec::new();
 
             // Recurse only if we're not a leaf
             if header.branches[0] != 0 {
                 for i in 0..header.entry_count + 1 {
-                    let dir = DatDirectory::read_async(reader, header.branches[i as usize], block_size).await?;
+                    let dir = DatDirectory::read_node_async(
+                        reader,
+                        header.branches[i as usize],
+                        block_size,
+                        file_size,
+                        seen,
+                    )
+                    .await?;
                     directories.push(dir);
                 }
             }
@@ -73,11 +146,66 @@ impl DatDirectory {
         })
     }
 
+    /// Read a single directory node, without its children
+    pub fn read_node_header<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        block_size: u32,
+        file_size: u32,
+    ) -> Result<DatDirectoryHeader, DatError> {
+        let mut validator = BlockChainValidator::new(block_size, file_size);
+        let header_buf = DatBlockReader::read(
+            reader,
+            offset,
+            DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+            &mut validator,
+        )?;
+
+        DatDirectoryHeader::read(&mut Cursor::new(header_buf))
+    }
+
+    pub async fn read_node_header_async<R: RangeReader>(
+        reader: &mut R,
+        offset: u32,
+        block_size: u32,
+        file_size: u32,
+    ) -> Result<DatDirectoryHeader, DatError> {
+        let mut validator = BlockChainValidator::new(block_size, file_size);
+        let header_buf = DatBlockReader::read_async(
+            reader,
+            offset,
+            DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+            &mut validator,
+        )
+        .await?;
+
+        DatDirectoryHeader::read(&mut Cursor::new(header_buf))
+    }
+
+    /// This node's branches and entries
+    pub fn node(&self) -> &DatDirectoryHeader {
+        &self.header
+    }
+
+    /// Make sure a directory node isn't reached twice, which would otherwise
+    /// recurse forever on a corrupt tree
+    fn check_node(offset: u32, seen: &mut HashSet<u32>) -> Result<(), DatError> {
+        if !seen.insert(offset) {
+            return Err(DatError::CorruptBlockChain {
+                object_id: None,
+                offset,
+                reason: "Directory node is referenced more than once".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
     pub fn list_files(
         &self,
         files_list: &mut Vec<DatDirectoryEntry>,
         recursive: bool,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), DatError> {
         if recursive {
             for i in 0..self.directories.len() {
                 self.directories[i].list_files(files_list, recursive)?;
@@ -85,9 +213,71 @@ impl DatDirectory {
         }
 
         for i in 0..self.header.entries.len() {
-            files_list.push(self.header.entries[i as usize]);
+            files_list.push(self.header.entries[i]);
         }
 
         Ok(())
     }
+
+    /// Look up a file by object ID, following the B-tree down from this node
+    /// rather than listing every file
+    pub fn find_file(&self, object_id: u32) -> Option<&DatDirectoryEntry> {
+        match self
+            .header
+            .entries
+            .binary_search_by_key(&object_id, |entry| entry.object_id)
+        {
+            Ok(i) => Some(&self.header.entries[i]),
+            // Branch i holds everything between entries i - 1 and i
+            Err(i) => self.directories.get(i)?.find_file(object_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(object_id: u32) -> DatDirectoryEntry {
+        DatDirectoryEntry {
+            bit_flags: 0,
+            object_id,
+            file_offset: object_id.wrapping_mul(1024),
+            file_size: 0,
+            date: 0,
+            iteration: 0,
+        }
+    }
+
+    fn check_nodes(directory: &DatDirectory) {
+        let mut bytes = Vec::new();
+
+        assert!(directory.header.entries.len() <= 61);
+        assert!(directory.header.write(&mut bytes).is_ok());
+
+        for child in &directory.directories {
+            check_nodes(child);
+        }
+    }
+
+    #[test]
+    fn from_entries_builds_nodes_that_fit() {
+        // Out of order, so the tree has to sort them
+        let ids: Vec<u32> = (0..5000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let directory = DatDirectory::from_entries(ids.iter().copied().map(entry).collect());
+
+        check_nodes(&directory);
+        assert!(!directory.directories.is_empty());
+
+        let mut listed = Vec::new();
+        directory.list_files(&mut listed, true).unwrap();
+        assert_eq!(listed.len(), ids.len());
+
+        for &object_id in &ids {
+            let found = directory.find_file(object_id).unwrap();
+            assert_eq!(found.file_offset, entry(object_id).file_offset);
+        }
+
+        assert!(directory.find_file(1).is_none());
+    }
 }
@@ -1,13 +1,21 @@
 use std::{
-    error::Error,
-    io::{Read, Seek},
+    io::{Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::dat::enums::dat_file_type::{DatFileSubtype, DatFileType};
+use crate::dat::{
+    enums::{
+        dat_database_type::DatDatabaseType,
+        dat_file_type::{DatFileSubtype, DatFileType},
+    },
+    error::DatError,
+    reader::types::dat_entry_flags::{DatEntryFlags, decompress},
+};
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DatDirectoryEntry {
     pub bit_flags: u32,
     pub object_id: u32,
@@ -18,7 +26,7 @@ pub struct DatDirectoryEntry {
 }
 
 impl DatDirectoryEntry {
-    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDirectoryEntry, Box<dyn Error>> {
+    pub fn read<R: Read>(reader: &mut R) -> Result<DatDirectoryEntry, DatError> {
         Ok(DatDirectoryEntry {
             bit_flags: reader.read_u32::<LittleEndian>()?,
             object_id: reader.read_u32::<LittleEndian>()?,
@@ -29,13 +37,53 @@ impl DatDirectoryEntry {
         })
     }
 
-    pub fn file_type(&self) -> DatFileType {
-        match self.object_id {
-            0x06000000..=0x07FFFFFF => DatFileType::Texture,
-            _ => DatFileType::Unknown,
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_u32::<LittleEndian>(self.bit_flags)?;
+        writer.write_u32::<LittleEndian>(self.object_id)?;
+        writer.write_u32::<LittleEndian>(self.file_offset)?;
+        writer.write_u32::<LittleEndian>(self.file_size)?;
+        writer.write_u32::<LittleEndian>(self.date)?;
+        writer.write_u32::<LittleEndian>(self.iteration)?;
+
+        Ok(())
+    }
+
+    pub fn flags(&self) -> DatEntryFlags {
+        DatEntryFlags::from_bits(self.bit_flags)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags().compressed
+    }
+
+    /// When the file was last changed (`date` is seconds since the Unix
+    /// epoch)
+    pub fn date_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.date as u64)
+    }
+
+    /// Turn the file's contents as stored in the DAT into its actual
+    /// contents, decompressing them if the entry is flagged as compressed
+    pub fn decode(&self, stored: Vec<u8>) -> Result<Vec<u8>, DatError> {
+        if self.is_compressed() {
+            decompress(&stored, self.object_id)
+        } else {
+            Ok(stored)
         }
     }
 
+    /// The type of file this is, assuming it's in a portal DAT
+    ///
+    /// Use file_type_in (or DatDatabase::file_type) for entries that could
+    /// be from other kinds of DAT.
+    pub fn file_type(&self) -> DatFileType {
+        self.file_type_in(&DatDatabaseType::Portal)
+    }
+
+    pub fn file_type_in(&self, database_type: &DatDatabaseType) -> DatFileType {
+        DatFileType::from_object_id(self.object_id, database_type)
+    }
+
     // WIP: Use this to let datfiles be specialized things like icons
     pub fn file_subtype(&self) -> DatFileSubtype {
         // TODO
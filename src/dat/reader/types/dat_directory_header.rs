@@ -1,9 +1,8 @@
-use std::{
-    error::Error,
-    io::{Read, Seek},
-};
+use std::io::{Read, Seek, Write};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dat::error::DatError;
 
 use super::dat_directory_entry::DatDirectoryEntry;
 
@@ -15,15 +14,23 @@ pub struct DatDirectoryHeader {
 }
 
 impl DatDirectoryHeader {
-    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDirectoryHeader, Box<dyn Error>> {
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<DatDirectoryHeader, DatError> {
         let mut branches = vec![0; 62];
 
-        for i in 0..branches.len() {
-            branches[i] = reader.read_u32::<LittleEndian>()?;
+        for branch in branches.iter_mut() {
+            *branch = reader.read_u32::<LittleEndian>()?;
         }
 
         let entry_count = reader.read_u32::<LittleEndian>()?;
 
+        if entry_count as usize >= branches.len() {
+            return Err(DatError::InvalidData(format!(
+                "Directory node has {} entries but can hold at most {}",
+                entry_count,
+                branches.len() - 1
+            )));
+        }
+
         let mut entries = vec![];
 
         for _ in 0..entry_count {
@@ -36,4 +43,28 @@ impl DatDirectoryHeader {
             entries,
         })
     }
+
+    /// Write the node as read by `read`, without padding it out to
+    /// DAT_DIRECTORY_HEADER_OBJECT_SIZE
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        if self.branches.len() != 62 || self.entries.len() >= self.branches.len() {
+            return Err(DatError::InvalidData(format!(
+                "Directory node has {} branches and {} entries, expected 62 and at most 61",
+                self.branches.len(),
+                self.entries.len()
+            )));
+        }
+
+        for branch in &self.branches {
+            writer.write_u32::<LittleEndian>(*branch)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.entries.len() as u32)?;
+
+        for entry in &self.entries {
+            entry.write(writer)?;
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,187 @@
+use flate2::{Decompress, FlushDecompress, Status};
+
+use crate::dat::error::DatError;
+
+/// DatDirectoryEntry::bit_flags, split into its parts
+///
+/// The low 16 bits describe how the file is stored (bit 0 is set for
+/// compressed files; the others are kept as is) and the high 16 bits hold the
+/// version of the file's format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatEntryFlags {
+    pub compressed: bool,
+    /// The rest of the low 16 bits, besides the compressed bit
+    pub type_bits: u16,
+    pub version: u16,
+}
+
+impl DatEntryFlags {
+    pub const COMPRESSED: u32 = 0x0001;
+    pub const VERSION_SHIFT: u32 = 16;
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            compressed: bits & Self::COMPRESSED != 0,
+            type_bits: (bits & 0xFFFF & !Self::COMPRESSED) as u16,
+            version: (bits >> Self::VERSION_SHIFT) as u16,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        ((self.version as u32) << Self::VERSION_SHIFT)
+            | (self.type_bits as u32 & !Self::COMPRESSED)
+            | if self.compressed { Self::COMPRESSED } else { 0 }
+    }
+}
+
+/// How much decompress grows its output by at a time
+const DECOMPRESS_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Unpack the contents of a compressed file
+///
+/// Compressed files are stored as the uncompressed size (a u32) followed by
+/// a zlib stream.
+pub fn decompress(data: &[u8], object_id: u32) -> Result<Vec<u8>, DatError> {
+    let invalid = |reason: String| {
+        DatError::InvalidData(format!("Compressed file {:08X} {}", object_id, reason))
+    };
+
+    let Some((size, stream)) = data.split_first_chunk::<4>() else {
+        return Err(invalid("is too short to hold its size".to_string()));
+    };
+    let size = u32::from_le_bytes(*size) as u64;
+
+    // The output grows a chunk at a time rather than trusting the size, up
+    // to one byte past it so a stream that's too long is caught without
+    // decompressing all of it
+    let mut decoder = Decompress::new(true);
+    let mut decompressed = Vec::new();
+
+    while decompressed.len() as u64 <= size {
+        let (total_in, total_out) = (decoder.total_in(), decoder.total_out());
+        let room = (size + 1 - decompressed.len() as u64).min(DECOMPRESS_CHUNK_SIZE);
+        decompressed.reserve(room as usize);
+
+        let status = decoder
+            .decompress_vec(
+                &stream[total_in as usize..],
+                &mut decompressed,
+                FlushDecompress::None,
+            )
+            .map_err(|e| invalid(format!("couldn't be decompressed: {}", e)))?;
+
+        if status == Status::StreamEnd {
+            break;
+        }
+
+        // The stream (or its checksum) stops short of its end
+        if decoder.total_in() == total_in && decoder.total_out() == total_out {
+            return Err(invalid("is truncated".to_string()));
+        }
+    }
+
+    if decompressed.len() as u64 != size {
+        return Err(invalid(format!(
+            "decompressed to {} bytes but should be {}",
+            decompressed.len(),
+            size
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::ZlibEncoder};
+
+    use super::*;
+    use crate::dat::reader::types::dat_directory_entry::DatDirectoryEntry;
+
+    /// Store `data` the way a compressed file is, claiming it's `size` bytes
+    fn compress(data: &[u8], size: u32) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(size.to_le_bytes().to_vec(), Compression::default());
+        encoder.write_all(data).unwrap();
+
+        encoder.finish().unwrap()
+    }
+
+    fn entry(bit_flags: u32) -> DatDirectoryEntry {
+        DatDirectoryEntry {
+            bit_flags,
+            object_id: 0x06000001,
+            file_offset: 0,
+            file_size: 0,
+            date: 0,
+            iteration: 1,
+        }
+    }
+
+    fn is_invalid(result: Result<Vec<u8>, DatError>) -> bool {
+        matches!(result, Err(DatError::InvalidData(_)))
+    }
+
+    #[test]
+    fn splits_known_bit_patterns() {
+        for (bits, compressed, type_bits, version) in [
+            (0x00000000, false, 0x0000, 0x0000),
+            (0x00000001, true, 0x0000, 0x0000),
+            (0x00020001, true, 0x0000, 0x0002),
+            (0x00030006, false, 0x0006, 0x0003),
+            (0xFFFFFFFF, true, 0xFFFE, 0xFFFF),
+        ] {
+            let flags = entry(bits).flags();
+
+            assert_eq!(
+                flags,
+                DatEntryFlags {
+                    compressed,
+                    type_bits,
+                    version,
+                },
+                "{:08X}",
+                bits
+            );
+            assert_eq!(flags.bits(), bits);
+            assert_eq!(entry(bits).is_compressed(), compressed);
+        }
+    }
+
+    #[test]
+    fn decodes_compressed_entries() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 7) as u8).collect();
+        let stored = compress(&data, data.len() as u32);
+
+        assert!(stored.len() < data.len());
+        assert_eq!(entry(0x00010001).decode(stored.clone()).unwrap(), data);
+
+        // Without the flag the stored bytes are the contents
+        assert_eq!(entry(0x00010000).decode(stored.clone()).unwrap(), stored);
+    }
+
+    #[test]
+    fn rejects_sizes_that_dont_match() {
+        let data = [1u8; 100];
+
+        assert!(is_invalid(decompress(&compress(&data, 101), 0x06000001)));
+        assert!(is_invalid(decompress(&compress(&data, 99), 0x06000001)));
+        assert!(is_invalid(decompress(&compress(&data, 0), 0x06000001)));
+    }
+
+    #[test]
+    fn rejects_truncated_streams() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 31 % 251) as u8).collect();
+        let stored = compress(&data, data.len() as u32);
+
+        for length in [0, 3, 4, 6, stored.len() / 2, stored.len() - 1] {
+            assert!(
+                is_invalid(decompress(&stored[..length], 0x06000001)),
+                "{} bytes",
+                length
+            );
+        }
+    }
+}
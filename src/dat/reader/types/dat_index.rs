@@ -0,0 +1,357 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dat::{
+    diff::{ContentHash, content_hash},
+    error::DatError,
+};
+
+use super::{
+    dat_database::DatDatabase, dat_database_header::DatDatabaseHeader,
+    dat_directory_entry::DatDirectoryEntry, dat_directory_header::DatDirectoryHeader,
+};
+
+pub const DAT_INDEX_MAGIC: &[u8; 4] = b"ACDI";
+pub const DAT_INDEX_VERSION: u32 = 3;
+
+/// The header fields and root directory node an index was built against
+///
+/// The header alone doesn't change when a file is rewritten in place, so the
+/// root node is hashed too. DatWriter writes every directory node it changes
+/// to new blocks, which means any edit it makes moves the root and changes
+/// both `btree` and `root_hash`. Other tools may rewrite a node below the
+/// root in place, which the key can't see without reading the whole
+/// directory; sidecar indexes catch that with a DatFileStamp, and indexes
+/// kept anywhere else have to be replaced along with their DAT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatIndexKey {
+    pub engine_pack_version: u32,
+    pub game_pack_version: u32,
+    pub file_size: u32,
+    pub block_size: u32,
+    pub btree: u32,
+    pub free_head: u32,
+    pub free_count: u32,
+    pub root_hash: ContentHash,
+}
+
+impl DatIndexKey {
+    /// The key for a DAT with this header and root directory node
+    pub fn new(header: &DatDatabaseHeader, root: &DatDirectoryHeader) -> Result<Self, DatError> {
+        let mut root_bytes = Vec::new();
+        root.write(&mut root_bytes)?;
+
+        Ok(Self {
+            engine_pack_version: header.engine_pack_version,
+            game_pack_version: header.game_pack_version,
+            file_size: header.file_size,
+            block_size: header.block_size,
+            btree: header.btree,
+            free_head: header.free_head,
+            free_count: header.free_count,
+            root_hash: content_hash(&root_bytes),
+        })
+    }
+}
+
+/// The size and modification time of a DAT on disk
+///
+/// Every write to the file changes these, however it's made, so a sidecar
+/// index is stamped with them and only used while they still match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatFileStamp {
+    pub len: u64,
+    /// Nanoseconds since the Unix epoch
+    pub modified: u64,
+}
+
+impl DatFileStamp {
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<DatFileStamp, DatError> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+
+        Ok(DatFileStamp {
+            len: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// A flat, serialisable snapshot of every DatDirectoryEntry in a DAT
+///
+/// Walking the directory tree means reading every node of the B-tree, which
+/// is slow for large DATs and painful over HTTP. An index can be saved next
+/// to the DAT (or anywhere else as raw bytes) and loaded on the next open
+/// instead, as long as it's still valid for the DAT's header.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DatIndex {
+    pub key: DatIndexKey,
+    /// The DAT file this is the sidecar index of, when it is one
+    pub stamp: Option<DatFileStamp>,
+    pub entries: Vec<DatDirectoryEntry>,
+}
+
+impl DatIndex {
+    pub fn from_database(db: &DatDatabase) -> Result<DatIndex, DatError> {
+        Ok(DatIndex {
+            key: db.index_key,
+            stamp: None,
+            entries: db.list_files(true)?,
+        })
+    }
+
+    /// Whether this index still describes the DAT the key was made from
+    pub fn is_valid_for(&self, key: &DatIndexKey) -> bool {
+        self.key == *key
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<DatIndex, DatError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != DAT_INDEX_MAGIC {
+            return Err(DatError::InvalidData(
+                "Not a DAT index file (bad magic)".to_string(),
+            ));
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+
+        if version != DAT_INDEX_VERSION {
+            return Err(DatError::InvalidData(format!(
+                "Unsupported DAT index version: {}",
+                version
+            )));
+        }
+
+        let key = DatIndexKey {
+            engine_pack_version: reader.read_u32::<LittleEndian>()?,
+            game_pack_version: reader.read_u32::<LittleEndian>()?,
+            file_size: reader.read_u32::<LittleEndian>()?,
+            block_size: reader.read_u32::<LittleEndian>()?,
+            btree: reader.read_u32::<LittleEndian>()?,
+            free_head: reader.read_u32::<LittleEndian>()?,
+            free_count: reader.read_u32::<LittleEndian>()?,
+            root_hash: {
+                let mut root_hash = ContentHash::default();
+                reader.read_exact(&mut root_hash)?;
+                root_hash
+            },
+        };
+        let stamp = DatFileStamp {
+            len: reader.read_u64::<LittleEndian>()?,
+            modified: reader.read_u64::<LittleEndian>()?,
+        };
+        // An empty file can't be a DAT, so a zero length means no stamp
+        let stamp = (stamp.len != 0).then_some(stamp);
+
+        // Not preallocated, since a corrupt count shouldn't be able to ask
+        // for more memory than the index has entries for
+        let entry_count = reader.read_u32::<LittleEndian>()?;
+        let mut entries = Vec::new();
+
+        for _ in 0..entry_count {
+            entries.push(DatDirectoryEntry::read(reader)?);
+        }
+
+        Ok(DatIndex {
+            key,
+            stamp,
+            entries,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), DatError> {
+        writer.write_all(DAT_INDEX_MAGIC)?;
+        writer.write_u32::<LittleEndian>(DAT_INDEX_VERSION)?;
+        writer.write_u32::<LittleEndian>(self.key.engine_pack_version)?;
+        writer.write_u32::<LittleEndian>(self.key.game_pack_version)?;
+        writer.write_u32::<LittleEndian>(self.key.file_size)?;
+        writer.write_u32::<LittleEndian>(self.key.block_size)?;
+        writer.write_u32::<LittleEndian>(self.key.btree)?;
+        writer.write_u32::<LittleEndian>(self.key.free_head)?;
+        writer.write_u32::<LittleEndian>(self.key.free_count)?;
+        writer.write_all(&self.key.root_hash)?;
+        let stamp = self.stamp.unwrap_or(DatFileStamp {
+            len: 0,
+            modified: 0,
+        });
+        writer.write_u64::<LittleEndian>(stamp.len)?;
+        writer.write_u64::<LittleEndian>(stamp.modified)?;
+        writer.write_u32::<LittleEndian>(self.entries.len() as u32)?;
+
+        for entry in &self.entries {
+            entry.write(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse an index from raw bytes, e.g. an object fetched from R2
+    pub fn from_bytes(bytes: &[u8]) -> Result<DatIndex, DatError> {
+        Self::read(&mut Cursor::new(bytes))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DatError> {
+        let mut buffer = Vec::new();
+        self.write(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DatIndex, DatError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        Self::read(&mut reader)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DatError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Conventional location of the sidecar index for a DAT on disk
+    pub fn sidecar_path(dat_path: &str) -> String {
+        format!("{}.idx", dat_path)
+    }
+
+    /// Load the sidecar index of a DAT on disk, refusing it if the DAT has
+    /// been written to since it was saved
+    pub fn load_sidecar(dat_path: &str) -> Result<DatIndex, DatError> {
+        let index = Self::load(Self::sidecar_path(dat_path))?;
+
+        if index.stamp.is_none() || index.stamp != Some(DatFileStamp::of(dat_path)?) {
+            return Err(DatError::InvalidData(format!(
+                "{} has been written to since its index was saved",
+                dat_path
+            )));
+        }
+
+        Ok(index)
+    }
+
+    /// Save as the sidecar index of a DAT on disk
+    ///
+    /// `stamp` should be taken before the DAT was read, so a write made
+    /// while it was being read makes the index stale rather than hiding.
+    pub fn save_sidecar(mut self, dat_path: &str, stamp: DatFileStamp) -> Result<(), DatError> {
+        self.stamp = Some(stamp);
+        self.save(Self::sidecar_path(dat_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{enums::dat_database_type::DatDatabaseType, writer::dat_writer::DatWriter};
+
+    fn build_dat() -> Cursor<Vec<u8>> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, 256).unwrap();
+
+        for object_id in 0x06000001..=0x06000100 {
+            writer
+                .write_file(object_id, &object_id.to_le_bytes())
+                .unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn index_is_stale_after_an_in_place_rewrite() {
+        let mut dat = build_dat();
+        let index = DatIndex::from_database(&DatDatabase::read(&mut dat).unwrap()).unwrap();
+
+        // Same size, so no blocks are allocated or freed for the data
+        let mut writer = DatWriter::open(dat).unwrap();
+        let rewritten = writer.write_file(0x06000080, &[1, 2, 3, 4]).unwrap();
+        let mut dat = writer.finish().unwrap();
+
+        let db = DatDatabase::read_with_index(&mut dat, Some(&index)).unwrap();
+
+        assert!(!index.is_valid_for(&db.index_key));
+        let found = db.find_file(0x06000080).unwrap();
+        assert_eq!(found.iteration, rewritten.iteration);
+        assert_eq!(found.file_offset, rewritten.file_offset);
+    }
+
+    #[test]
+    fn index_is_used_while_valid() {
+        let mut dat = build_dat();
+        let db = DatDatabase::read(&mut dat).unwrap();
+        let index =
+            DatIndex::from_bytes(&DatIndex::from_database(&db).unwrap().to_bytes().unwrap())
+                .unwrap();
+
+        let reopened = DatDatabase::read_with_index(&mut dat, Some(&index)).unwrap();
+
+        assert!(index.is_valid_for(&reopened.index_key));
+        assert_eq!(reopened.list_files(true).unwrap().len(), 256);
+    }
+
+    #[test]
+    fn corrupt_entry_count_is_an_error() {
+        let mut dat = build_dat();
+        let db = DatDatabase::read(&mut dat).unwrap();
+        let mut bytes = DatIndex::from_database(&db).unwrap().to_bytes().unwrap();
+
+        // Magic, version, seven key fields, the root hash and the stamp
+        let count_offset = 4 + 4 + 7 * 4 + 32 + 16;
+        bytes[count_offset..count_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(DatIndex::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn sidecar_is_stale_once_the_dat_is_written_to() {
+        let dat_path = std::env::temp_dir()
+            .join(format!("libac-rs-sidecar-{}.dat", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let dat = build_dat().into_inner();
+        std::fs::write(&dat_path, &dat).unwrap();
+
+        let stamp = DatFileStamp::of(&dat_path).unwrap();
+        let db = DatDatabase::read(&mut Cursor::new(&dat)).unwrap();
+        DatIndex::from_database(&db)
+            .unwrap()
+            .save_sidecar(&dat_path, stamp)
+            .unwrap();
+        let loaded = DatIndex::load_sidecar(&dat_path).unwrap();
+
+        // An in-place write leaving the header and root node as they were
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&dat_path)
+            .unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(2))
+            .unwrap();
+        drop(file);
+        let reloaded = DatIndex::load_sidecar(&dat_path);
+
+        std::fs::remove_file(&dat_path).unwrap();
+        std::fs::remove_file(DatIndex::sidecar_path(&dat_path)).unwrap();
+
+        assert_eq!(loaded.stamp, Some(stamp));
+        assert_eq!(loaded.entries.len(), 256);
+        assert!(reloaded.is_err());
+    }
+}
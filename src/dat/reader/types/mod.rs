@@ -4,3 +4,5 @@ pub mod dat_database_header;
 pub mod dat_directory;
 pub mod dat_directory_entry;
 pub mod dat_directory_header;
+pub mod dat_entry_flags;
+pub mod dat_index;
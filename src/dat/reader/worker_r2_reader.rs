@@ -1,18 +1,26 @@
-use std::error::Error;
-use worker::{Bucket, Range};
+use worker::{
+    Bucket, Range,
+    send::{SendFuture, SendWrapper},
+};
 
-use crate::dat::reader::range_reader::RangeReader;
+use crate::dat::{error::DatError, reader::range_reader::RangeReader};
 
 /// Cloudflare Worker R2 implementation of RangeReader
 /// Uses the Worker runtime's R2 API through environment bindings
+///
+/// Workers are single-threaded, so the bucket and the futures reading from it
+/// are wrapped to be Send as RangeReader requires.
 pub struct WorkerR2RangeReader {
-    bucket: Bucket,
+    bucket: SendWrapper<Bucket>,
     key: String,
 }
 
 impl WorkerR2RangeReader {
     pub fn new(bucket: Bucket, key: String) -> Self {
-        Self { bucket, key }
+        Self {
+            bucket: SendWrapper::new(bucket),
+            key,
+        }
     }
 }
 
@@ -21,11 +29,11 @@ impl RangeReader for WorkerR2RangeReader {
         &mut self,
         offset: u32,
         length: usize,
-    ) -> impl std::future::Future<Output = Result<Vec<u8>, Box<dyn Error>>> {
-        let bucket = self.bucket.clone();
+    ) -> impl std::future::Future<Output = Result<Vec<u8>, DatError>> + Send {
+        let bucket = self.bucket.0.clone();
         let key = self.key.clone();
 
-        async move {
+        SendFuture::new(async move {
             let range = Range::OffsetWithLength {
                 offset: offset as u64,
                 length: length as u64,
@@ -36,21 +44,25 @@ impl RangeReader for WorkerR2RangeReader {
                 .range(range)
                 .execute()
                 .await
-                .map_err(|e| -> Box<dyn Error> { format!("R2 get failed: {:?}", e).into() })?;
+                .map_err(|e| DatError::Http(format!("R2 get failed: {:?}", e)))?;
 
             match object {
                 Some(obj) => {
                     let stream = obj
                         .body()
-                        .ok_or_else(|| -> Box<dyn Error> { "No body in R2 object".into() })?;
+                        .ok_or_else(|| DatError::Http("No body in R2 object".to_string()))?;
 
-                    let bytes = stream.bytes().await.map_err(|e| -> Box<dyn Error> {
-                        format!("Failed to read bytes: {:?}", e).into()
-                    })?;
+                    let bytes = stream
+                        .bytes()
+                        .await
+                        .map_err(|e| DatError::Http(format!("Failed to read bytes: {:?}", e)))?;
                     Ok(bytes.to_vec())
                 }
-                None => Err("Object not found in R2 bucket".into()),
+                None => Err(DatError::NotFound(format!(
+                    "Object {} not found in R2 bucket",
+                    key
+                ))),
             }
-        }
+        })
     }
 }
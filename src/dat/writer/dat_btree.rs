@@ -0,0 +1,424 @@
+use crate::dat::reader::types::dat_directory_entry::DatDirectoryEntry;
+
+/// Minimum degree of the B-tree
+///
+/// A node holds at most 2t - 1 = 61 entries and 2t = 62 branches, which is
+/// exactly what DatDirectoryHeader has room for.
+pub const DAT_BTREE_MIN_DEGREE: usize = 31;
+pub const DAT_BTREE_MAX_ENTRIES: usize = 2 * DAT_BTREE_MIN_DEGREE - 1;
+
+/// A directory node held in memory while a DAT is being edited
+#[derive(Debug, Clone, Default)]
+pub struct DatBTreeNode {
+    /// Where the node is stored in the DAT, if it's been written yet
+    pub offset: Option<u32>,
+    /// Whether the node has changed since it was read or last written
+    pub dirty: bool,
+    pub entries: Vec<DatDirectoryEntry>,
+    /// Child nodes, empty for a leaf and one more than `entries` otherwise
+    pub children: Vec<DatBTreeNode>,
+}
+
+impl DatBTreeNode {
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    fn position(&self, object_id: u32) -> (usize, bool) {
+        let pos = self
+            .entries
+            .partition_point(|entry| entry.object_id < object_id);
+        let found = self
+            .entries
+            .get(pos)
+            .is_some_and(|entry| entry.object_id == object_id);
+
+        (pos, found)
+    }
+}
+
+/// An in-memory copy of a DAT's directory B-tree, keyed on object ID
+///
+/// Nodes remember the offset they were read from so DatWriter can free their
+/// old blocks when it writes them somewhere new. Nodes that disappear
+/// (through merges or the root shrinking) have their offsets collected in
+/// `freed` so their blocks can be returned to the free list.
+#[derive(Debug, Clone, Default)]
+pub struct DatBTree {
+    pub root: DatBTreeNode,
+    pub freed: Vec<u32>,
+}
+
+impl DatBTree {
+    pub fn new(root: DatBTreeNode) -> Self {
+        Self {
+            root,
+            freed: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, object_id: u32) -> Option<&DatDirectoryEntry> {
+        let mut node = &self.root;
+
+        loop {
+            let (pos, found) = node.position(object_id);
+
+            if found {
+                return Some(&node.entries[pos]);
+            }
+
+            if node.is_leaf() {
+                return None;
+            }
+
+            node = &node.children[pos];
+        }
+    }
+
+    /// Every entry in object ID order
+    pub fn entries(&self) -> Vec<DatDirectoryEntry> {
+        fn collect(node: &DatBTreeNode, entries: &mut Vec<DatDirectoryEntry>) {
+            for (i, entry) in node.entries.iter().enumerate() {
+                if let Some(child) = node.children.get(i) {
+                    collect(child, entries);
+                }
+
+                entries.push(*entry);
+            }
+
+            if let Some(child) = node.children.last() {
+                collect(child, entries);
+            }
+        }
+
+        let mut entries = Vec::new();
+        collect(&self.root, &mut entries);
+
+        entries
+    }
+
+    /// Insert an entry, replacing (and returning) any entry with the same
+    /// object ID
+    pub fn insert(&mut self, entry: DatDirectoryEntry) -> Option<DatDirectoryEntry> {
+        if let Some(previous) = Self::replace(&mut self.root, entry) {
+            return Some(previous);
+        }
+
+        if self.root.entries.len() == DAT_BTREE_MAX_ENTRIES {
+            let old_root = std::mem::take(&mut self.root);
+            self.root.children.push(old_root);
+            self.root.dirty = true;
+            Self::split_child(&mut self.root, 0);
+        }
+
+        Self::insert_non_full(&mut self.root, entry);
+
+        None
+    }
+
+    /// Remove the entry with the given object ID, returning it if it existed
+    pub fn remove(&mut self, object_id: u32) -> Option<DatDirectoryEntry> {
+        let removed = Self::remove_from(&mut self.root, object_id, &mut self.freed);
+
+        // An internal root left without entries is replaced by its only child
+        if self.root.entries.is_empty() && !self.root.is_leaf() {
+            let child = self.root.children.remove(0);
+            let old_root = std::mem::replace(&mut self.root, child);
+
+            if let Some(offset) = old_root.offset {
+                self.freed.push(offset);
+            }
+        }
+
+        removed
+    }
+
+    fn replace(node: &mut DatBTreeNode, entry: DatDirectoryEntry) -> Option<DatDirectoryEntry> {
+        let (pos, found) = node.position(entry.object_id);
+
+        if found {
+            node.dirty = true;
+            return Some(std::mem::replace(&mut node.entries[pos], entry));
+        }
+
+        if node.is_leaf() {
+            return None;
+        }
+
+        Self::replace(&mut node.children[pos], entry)
+    }
+
+    /// Split the full child at `index`, moving its median entry up into
+    /// `parent`
+    fn split_child(parent: &mut DatBTreeNode, index: usize) {
+        let child = &mut parent.children[index];
+
+        let mut right = DatBTreeNode {
+            offset: None,
+            dirty: true,
+            entries: child.entries.split_off(DAT_BTREE_MIN_DEGREE),
+            children: Vec::new(),
+        };
+
+        if !child.is_leaf() {
+            right.children = child.children.split_off(DAT_BTREE_MIN_DEGREE);
+        }
+
+        let median = child.entries.pop().expect("a full node has a median entry");
+        child.dirty = true;
+
+        parent.entries.insert(index, median);
+        parent.children.insert(index + 1, right);
+        parent.dirty = true;
+    }
+
+    fn insert_non_full(node: &mut DatBTreeNode, entry: DatDirectoryEntry) {
+        let (mut pos, _) = node.position(entry.object_id);
+
+        if node.is_leaf() {
+            node.entries.insert(pos, entry);
+            node.dirty = true;
+            return;
+        }
+
+        if node.children[pos].entries.len() == DAT_BTREE_MAX_ENTRIES {
+            Self::split_child(node, pos);
+
+            if entry.object_id > node.entries[pos].object_id {
+                pos += 1;
+            }
+        }
+
+        Self::insert_non_full(&mut node.children[pos], entry);
+    }
+
+    fn remove_from(
+        node: &mut DatBTreeNode,
+        object_id: u32,
+        freed: &mut Vec<u32>,
+    ) -> Option<DatDirectoryEntry> {
+        let (pos, found) = node.position(object_id);
+
+        if node.is_leaf() {
+            if !found {
+                return None;
+            }
+
+            node.dirty = true;
+            return Some(node.entries.remove(pos));
+        }
+
+        if found {
+            node.dirty = true;
+
+            // Replace the entry with its predecessor or successor when either
+            // side can spare one, otherwise merge both sides around it
+            if node.children[pos].entries.len() >= DAT_BTREE_MIN_DEGREE {
+                let predecessor = Self::last_entry(&node.children[pos]);
+                Self::remove_from(&mut node.children[pos], predecessor.object_id, freed);
+                return Some(std::mem::replace(&mut node.entries[pos], predecessor));
+            }
+
+            if node.children[pos + 1].entries.len() >= DAT_BTREE_MIN_DEGREE {
+                let successor = Self::first_entry(&node.children[pos + 1]);
+                Self::remove_from(&mut node.children[pos + 1], successor.object_id, freed);
+                return Some(std::mem::replace(&mut node.entries[pos], successor));
+            }
+
+            Self::merge_children(node, pos, freed);
+            return Self::remove_from(&mut node.children[pos], object_id, freed);
+        }
+
+        // Make sure the child we descend into can lose an entry
+        let mut pos = pos;
+
+        if node.children[pos].entries.len() < DAT_BTREE_MIN_DEGREE {
+            pos = Self::fill_child(node, pos, freed);
+        }
+
+        Self::remove_from(&mut node.children[pos], object_id, freed)
+    }
+
+    /// Give the child at `index` at least DAT_BTREE_MIN_DEGREE entries by
+    /// borrowing from a sibling or merging with one, returning the index the
+    /// child ends up at
+    fn fill_child(node: &mut DatBTreeNode, index: usize, freed: &mut Vec<u32>) -> usize {
+        node.dirty = true;
+
+        if index > 0 && node.children[index - 1].entries.len() >= DAT_BTREE_MIN_DEGREE {
+            let (left, right) = node.children.split_at_mut(index);
+            let left = &mut left[index - 1];
+            let child = &mut right[0];
+
+            let borrowed = left.entries.pop().expect("sibling has entries to spare");
+            let separator = std::mem::replace(&mut node.entries[index - 1], borrowed);
+            child.entries.insert(0, separator);
+
+            if let Some(branch) = left.children.pop() {
+                child.children.insert(0, branch);
+            }
+
+            left.dirty = true;
+            child.dirty = true;
+
+            return index;
+        }
+
+        if index < node.entries.len()
+            && node.children[index + 1].entries.len() >= DAT_BTREE_MIN_DEGREE
+        {
+            let (left, right) = node.children.split_at_mut(index + 1);
+            let child = &mut left[index];
+            let right = &mut right[0];
+
+            let borrowed = right.entries.remove(0);
+            let separator = std::mem::replace(&mut node.entries[index], borrowed);
+            child.entries.push(separator);
+
+            if !right.is_leaf() {
+                child.children.push(right.children.remove(0));
+            }
+
+            right.dirty = true;
+            child.dirty = true;
+
+            return index;
+        }
+
+        if index < node.entries.len() {
+            Self::merge_children(node, index, freed);
+            index
+        } else {
+            Self::merge_children(node, index - 1, freed);
+            index - 1
+        }
+    }
+
+    /// Merge the child at `index + 1` and the separating entry into the child
+    /// at `index`
+    fn merge_children(node: &mut DatBTreeNode, index: usize, freed: &mut Vec<u32>) {
+        let separator = node.entries.remove(index);
+        let right = node.children.remove(index + 1);
+        let left = &mut node.children[index];
+
+        left.entries.push(separator);
+        left.entries.extend(right.entries);
+        left.children.extend(right.children);
+        left.dirty = true;
+        node.dirty = true;
+
+        if let Some(offset) = right.offset {
+            freed.push(offset);
+        }
+    }
+
+    fn first_entry(node: &DatBTreeNode) -> DatDirectoryEntry {
+        match node.children.first() {
+            Some(child) => Self::first_entry(child),
+            None => node.entries[0],
+        }
+    }
+
+    fn last_entry(node: &DatBTreeNode) -> DatDirectoryEntry {
+        match node.children.last() {
+            Some(child) => Self::last_entry(child),
+            None => node.entries[node.entries.len() - 1],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn entry(object_id: u32) -> DatDirectoryEntry {
+        DatDirectoryEntry {
+            bit_flags: 0,
+            object_id,
+            file_offset: object_id,
+            file_size: 0,
+            date: 0,
+            iteration: 1,
+        }
+    }
+
+    /// Check the B-tree properties below `node`, returning the depth of its
+    /// leaves
+    fn check_node(node: &DatBTreeNode, is_root: bool) -> usize {
+        assert!(node.entries.len() <= DAT_BTREE_MAX_ENTRIES);
+
+        if !is_root {
+            assert!(node.entries.len() >= DAT_BTREE_MIN_DEGREE - 1);
+        }
+
+        assert!(
+            node.entries
+                .windows(2)
+                .all(|pair| pair[0].object_id < pair[1].object_id)
+        );
+
+        if node.is_leaf() {
+            return 1;
+        }
+
+        assert_eq!(node.children.len(), node.entries.len() + 1);
+
+        let depths: BTreeSet<usize> = node
+            .children
+            .iter()
+            .map(|child| check_node(child, false))
+            .collect();
+        assert_eq!(depths.len(), 1, "leaves at different depths");
+
+        depths.first().unwrap() + 1
+    }
+
+    fn check(tree: &DatBTree, expected: &BTreeSet<u32>) {
+        check_node(&tree.root, true);
+
+        let ids: Vec<u32> = tree.entries().iter().map(|entry| entry.object_id).collect();
+        assert_eq!(ids, expected.iter().copied().collect::<Vec<_>>());
+
+        for &object_id in expected {
+            assert_eq!(tree.get(object_id).unwrap().object_id, object_id);
+        }
+    }
+
+    #[test]
+    fn stays_balanced_through_inserts_and_removes() {
+        let mut tree = DatBTree::default();
+        let mut expected = BTreeSet::new();
+
+        // Inserting with a stride visits the IDs out of order
+        for i in 0..5000u32 {
+            let object_id = i * 7919 % 5000;
+            assert!(tree.insert(entry(object_id)).is_none());
+            expected.insert(object_id);
+        }
+
+        check(&tree, &expected);
+        assert!(check_node(&tree.root, true) >= 3);
+
+        assert_eq!(tree.insert(entry(42)).unwrap().object_id, 42);
+
+        for i in 0..4990u32 {
+            let object_id = i * 3011 % 5000;
+            assert_eq!(tree.remove(object_id).unwrap().object_id, object_id);
+            expected.remove(&object_id);
+
+            if i % 500 == 0 {
+                check(&tree, &expected);
+            }
+        }
+
+        assert!(tree.remove(0).is_none());
+        check(&tree, &expected);
+        assert!(tree.root.is_leaf());
+        assert!(
+            tree.freed.is_empty(),
+            "nodes that were never written have no blocks"
+        );
+    }
+}
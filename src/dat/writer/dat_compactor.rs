@@ -0,0 +1,225 @@
+use std::io::{Read, Seek, Write};
+
+use crate::dat::{
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        types::{
+            dat_database::DatDatabase, dat_database_header::DatDatabaseHeader,
+            dat_directory_entry::DatDirectoryEntry,
+        },
+    },
+    writer::dat_writer::DatWriter,
+};
+
+/// How scattered the files in a DAT are
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FragmentationStats {
+    pub file_size: u32,
+    pub files: usize,
+    /// Files with at least one block that doesn't directly follow the last
+    pub fragmented_files: usize,
+    /// Blocks used by files
+    pub file_blocks: usize,
+    /// Places where a file's next block isn't the one right after the last,
+    /// i.e. the extra range reads needed to read every file
+    pub discontinuities: usize,
+    pub free_blocks: u32,
+}
+
+impl FragmentationStats {
+    /// Measure a DAT by following the chain of every file in it
+    pub fn measure<R: Read + Seek>(reader: &mut R) -> Result<FragmentationStats, DatError> {
+        let db = DatDatabase::read(reader)?;
+        let files = db.list_files(true)?;
+
+        Self::measure_files(reader, &db.header, &files)
+    }
+
+    fn measure_files<R: Read + Seek>(
+        reader: &mut R,
+        header: &DatDatabaseHeader,
+        files: &[DatDirectoryEntry],
+    ) -> Result<FragmentationStats, DatError> {
+        let mut stats = FragmentationStats {
+            file_size: header.file_size,
+            files: files.len(),
+            free_blocks: header.free_count,
+            ..Default::default()
+        };
+
+        for entry in files {
+            let mut validator = BlockChainValidator::new(header.block_size, header.file_size)
+                .for_object(entry.object_id);
+            DatBlockReader::walk(reader, entry.file_offset, entry.file_size, &mut validator)?;
+
+            let discontinuities = validator
+                .blocks()
+                .windows(2)
+                .filter(|pair| pair[1] != pair[0] + header.block_size)
+                .count();
+
+            stats.file_blocks += validator.blocks().len();
+            stats.discontinuities += discontinuities;
+
+            if discontinuities > 0 {
+                stats.fragmented_files += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Share of block-to-block steps that jump somewhere else, from 0 to 1
+    pub fn fragmentation(&self) -> f64 {
+        let steps = self.file_blocks.saturating_sub(self.files);
+
+        if steps == 0 {
+            0.0
+        } else {
+            self.discontinuities as f64 / steps as f64
+        }
+    }
+}
+
+/// Before and after statistics from a compaction
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CompactionReport {
+    pub before: FragmentationStats,
+    pub after: FragmentationStats,
+}
+
+/// Rewrite a DAT into `dest` with every file stored contiguously in object ID
+/// order, a freshly built directory and no free list
+///
+/// The LRU fields are cleared, as they point into the old layout. Everything
+/// else in the header, and every entry's flags, date and iteration, is
+/// carried over as is.
+pub fn compact<R, W>(source: &mut R, dest: &mut W) -> Result<CompactionReport, DatError>
+where
+    R: Read + Seek,
+    W: Read + Write + Seek,
+{
+    let db = DatDatabase::read(source)?;
+
+    compact_database(&db, source, dest)
+}
+
+/// Compact a DAT whose directory has already been read, e.g. through an
+/// index
+pub fn compact_database<R, W>(
+    db: &DatDatabase,
+    source: &mut R,
+    dest: &mut W,
+) -> Result<CompactionReport, DatError>
+where
+    R: Read + Seek,
+    W: Read + Write + Seek,
+{
+    let mut files = db.list_files(true)?;
+    files.sort_by_key(|entry| entry.object_id);
+
+    let before = FragmentationStats::measure_files(source, &db.header, &files)?;
+
+    let mut writer = DatWriter::create_with_header(dest, db.header.clone())?;
+
+    for entry in &files {
+        let mut validator = BlockChainValidator::new(db.header.block_size, db.header.file_size)
+            .for_object(entry.object_id);
+        let data =
+            DatBlockReader::read(source, entry.file_offset, entry.file_size, &mut validator)?;
+        writer.insert_entry(*entry, &data)?;
+    }
+
+    let dest = writer.finish()?;
+    let after = FragmentationStats::measure(dest)?;
+
+    Ok(CompactionReport { before, after })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dat::{diff::read_entry, enums::dat_database_type::DatDatabaseType};
+
+    const BLOCK_SIZE: u32 = 256;
+
+    /// A DAT where files have been grown, shrunk and deleted so their blocks
+    /// are scattered and the free list isn't empty
+    fn fragmented_dat() -> Vec<u8> {
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, BLOCK_SIZE)
+                .unwrap();
+        writer.header_mut().new_lru = 0x1000;
+        writer.header_mut().old_lru = 0x2000;
+        writer.header_mut().use_lru = true;
+
+        for object_id in 0x06000000..0x06000100 {
+            writer
+                .write_file(object_id, &[object_id as u8; 300])
+                .unwrap();
+        }
+
+        let mut writer = DatWriter::open(writer.finish().unwrap()).unwrap();
+
+        for object_id in (0x06000001..0x06000100).step_by(5) {
+            writer.delete_file(object_id).unwrap();
+        }
+
+        // The deleted files' blocks are only freed by finish, so the grown
+        // files are spread over them next time round
+        let mut writer = DatWriter::open(writer.finish().unwrap()).unwrap();
+
+        for object_id in (0x06000000..0x06000100).step_by(3) {
+            writer
+                .write_file(object_id, &[!object_id as u8; 900])
+                .unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn compacting_keeps_every_file_and_drops_the_free_space() {
+        let source = fragmented_dat();
+        let mut dest = Cursor::new(Vec::new());
+        let report = compact(&mut Cursor::new(source.clone()), &mut dest).unwrap();
+
+        assert!(report.before.fragmented_files > 0);
+        assert!(report.before.free_blocks > 0);
+        assert_eq!(report.after.fragmented_files, 0);
+        assert_eq!(report.after.files, report.before.files);
+
+        let mut source = Cursor::new(source);
+        let old = DatDatabase::read(&mut source).unwrap();
+        let new = DatDatabase::read(&mut dest).unwrap();
+
+        assert_eq!(new.header.free_count, 0);
+        assert_eq!(new.header.free_head, 0);
+        assert_eq!((new.header.new_lru, new.header.old_lru), (0, 0));
+        assert!(!new.header.use_lru);
+        assert!(new.header.file_size < old.header.file_size);
+        assert_eq!(dest.get_ref().len() as u32, new.header.file_size);
+
+        let old_files = old.list_files(true).unwrap();
+        assert_eq!(new.list_files(true).unwrap().len(), old_files.len());
+
+        for old_entry in &old_files {
+            let new_entry = new.find_file(old_entry.object_id).unwrap();
+
+            assert_eq!(new_entry.bit_flags, old_entry.bit_flags);
+            assert_eq!(new_entry.file_size, old_entry.file_size);
+            assert_eq!(new_entry.date, old_entry.date);
+            assert_eq!(new_entry.iteration, old_entry.iteration);
+            assert_eq!(
+                read_entry(&mut dest, &new.header, new_entry).unwrap(),
+                read_entry(&mut source, &old.header, old_entry).unwrap()
+            );
+        }
+    }
+}
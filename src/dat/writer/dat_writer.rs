@@ -0,0 +1,623 @@
+use std::{
+    collections::HashSet,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dat::{
+    enums::dat_database_type::DatDatabaseType,
+    error::DatError,
+    reader::{
+        block_chain_validator::BlockChainValidator,
+        dat_block_reader::DatBlockReader,
+        types::{
+            dat_database_header::{
+                DAT_FILE_TYPE, DAT_HEADER_OFFSET, DAT_HEADER_SIZE, DatDatabaseHeader,
+            },
+            dat_directory::DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+            dat_directory_entry::DatDirectoryEntry,
+            dat_directory_header::DatDirectoryHeader,
+            dat_entry_flags::DatEntryFlags,
+        },
+    },
+    writer::dat_btree::{DatBTree, DatBTreeNode},
+};
+
+/// Edits the files in an existing DAT in place
+///
+/// File data is written as soon as it's added, into blocks taken from the
+/// free list (or appended to the end of the DAT once it's empty). The
+/// directory B-tree is held in memory and only written back, along with the
+/// header, by `flush`.
+///
+/// Nothing the header currently points at is overwritten before then:
+/// replaced files and changed directory nodes are written to new blocks, and
+/// the blocks of replaced, deleted and moved chains are only returned to the
+/// free list once the new header has been written. So a DatWriter that's
+/// dropped, or fails, before flushing leaves the DAT's directory and files
+/// as they were, apart from the free list, whose blocks may have been
+/// reused. The writer can't make sure its writes reach the disk in order;
+/// sync the underlying file if that matters.
+///
+/// Every flush that changes anything moves the root node, which is what
+/// invalidates any DatIndex saved for the DAT.
+pub struct DatWriter<W> {
+    inner: W,
+    header: DatDatabaseHeader,
+    tree: DatBTree,
+    /// Blocks the edited DAT no longer uses, only freed once the header no
+    /// longer points at them
+    released_blocks: Vec<u32>,
+}
+
+impl<W: Read + Write + Seek> DatWriter<W> {
+    /// Open a DAT for editing, reading its header and directory tree
+    pub fn open(mut inner: W) -> Result<Self, DatError> {
+        let header = DatDatabaseHeader::read(&mut inner)?;
+
+        if header.block_size <= 4 {
+            return Err(DatError::InvalidData(format!(
+                "Block size {} is too small to hold a pointer and data",
+                header.block_size
+            )));
+        }
+
+        let root = Self::read_node(&mut inner, &header, header.btree, &mut HashSet::new())?;
+
+        Ok(Self::from_parts(inner, header, DatBTree::new(root)))
+    }
+
+    /// Create a new, empty DAT
+    pub fn create(
+        inner: W,
+        database_type: DatDatabaseType,
+        block_size: u32,
+    ) -> Result<Self, DatError> {
+        let header = DatDatabaseHeader {
+            file_type: DAT_FILE_TYPE,
+            block_size,
+            file_size: 0,
+            data_set: database_type.as_u32(),
+            data_subset: 0,
+            free_head: 0,
+            free_tail: 0,
+            free_count: 0,
+            btree: 0,
+            new_lru: 0,
+            old_lru: 0,
+            use_lru: false,
+            master_map_id: 0,
+            engine_pack_version: 0,
+            game_pack_version: 0,
+            version_major: vec![0; 16],
+            version_minor: 0,
+        };
+
+        Self::create_with_header(inner, header)
+    }
+
+    /// Create a new, empty DAT using the given header, e.g. one copied from
+    /// another DAT
+    ///
+    /// The header's size, free list, B-tree and LRU fields are reset.
+    /// Everything before the first block (including the header) is zeroed and
+    /// the header itself is written on `flush`. Blocks start at the first
+    /// multiple of the block size after the header.
+    pub fn create_with_header(mut inner: W, header: DatDatabaseHeader) -> Result<Self, DatError> {
+        if header.block_size <= 4 {
+            return Err(DatError::InvalidData(format!(
+                "Block size {} is too small to hold a pointer and data",
+                header.block_size
+            )));
+        }
+
+        let header_end = DAT_HEADER_OFFSET as u32 + DAT_HEADER_SIZE as u32;
+        let first_block = header_end.next_multiple_of(header.block_size);
+
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(&vec![0; first_block as usize])?;
+
+        let header = DatDatabaseHeader {
+            file_size: first_block,
+            free_head: 0,
+            free_tail: 0,
+            free_count: 0,
+            btree: 0,
+            new_lru: 0,
+            old_lru: 0,
+            use_lru: false,
+            ..header
+        };
+
+        Ok(Self::from_parts(inner, header, DatBTree::default()))
+    }
+
+    /// Start editing a DAT whose header and (empty or partially built)
+    /// directory are already known, e.g. one that's being created from scratch
+    pub fn from_parts(inner: W, header: DatDatabaseHeader, tree: DatBTree) -> Self {
+        Self {
+            inner,
+            header,
+            tree,
+            released_blocks: Vec::new(),
+        }
+    }
+
+    /// The header as it will be written by the next flush
+    pub fn header(&self) -> &DatDatabaseHeader {
+        &self.header
+    }
+
+    /// The header, for changing fields such as the version numbers
+    ///
+    /// The size, free list and B-tree fields are managed by the writer and
+    /// anything set on them here is overwritten.
+    pub fn header_mut(&mut self) -> &mut DatDatabaseHeader {
+        &mut self.header
+    }
+
+    /// Read the current contents of a file, as stored (i.e. still compressed
+    /// if its entry is)
+    pub fn read_file(&mut self, object_id: u32) -> Result<Option<Vec<u8>>, DatError> {
+        let Some(entry) = self.tree.get(object_id).copied() else {
+            return Ok(None);
+        };
+
+        let mut validator = BlockChainValidator::new(self.header.block_size, self.header.file_size)
+            .for_object(object_id);
+
+        Ok(Some(DatBlockReader::read(
+            &mut self.inner,
+            entry.file_offset,
+            entry.file_size,
+            &mut validator,
+        )?))
+    }
+
+    pub fn get_entry(&self, object_id: u32) -> Option<DatDirectoryEntry> {
+        self.tree.get(object_id).copied()
+    }
+
+    /// Every entry in object ID order
+    pub fn entries(&self) -> Vec<DatDirectoryEntry> {
+        self.tree.entries()
+    }
+
+    /// Add or replace a file
+    ///
+    /// A replaced file keeps its flags and has its iteration bumped; a new
+    /// one starts at iteration 1. Either way its date is set to now.
+    pub fn write_file(
+        &mut self,
+        object_id: u32,
+        data: &[u8],
+    ) -> Result<DatDirectoryEntry, DatError> {
+        let entry = match self.tree.get(object_id) {
+            // The data's written as given, so it isn't compressed any more
+            Some(existing) => DatDirectoryEntry {
+                bit_flags: existing.bit_flags & !DatEntryFlags::COMPRESSED,
+                date: now(),
+                iteration: existing.iteration.wrapping_add(1),
+                ..*existing
+            },
+            None => DatDirectoryEntry {
+                bit_flags: 0,
+                object_id,
+                file_offset: 0,
+                file_size: 0,
+                date: now(),
+                iteration: 1,
+            },
+        };
+
+        self.insert_entry(entry, data)
+    }
+
+    /// Add or replace a file, keeping the entry's flags, date and iteration
+    /// as given
+    ///
+    /// Only `file_offset` and `file_size` are filled in from where and how
+    /// much data was written. Returns the entry as stored.
+    pub fn insert_entry(
+        &mut self,
+        entry: DatDirectoryEntry,
+        data: &[u8],
+    ) -> Result<DatDirectoryEntry, DatError> {
+        let size = u32::try_from(data.len()).map_err(|_| {
+            DatError::InvalidData(format!(
+                "File {:08X} is too large for a DAT ({} bytes)",
+                entry.object_id,
+                data.len()
+            ))
+        })?;
+
+        if let Some(existing) = self.tree.get(entry.object_id).copied() {
+            let blocks = self.chain_blocks(
+                existing.file_offset,
+                existing.file_size,
+                Some(existing.object_id),
+            )?;
+            self.released_blocks.extend(blocks);
+        }
+
+        let file_offset = self.write_chain(data)?;
+
+        let entry = DatDirectoryEntry {
+            file_offset,
+            file_size: size,
+            ..entry
+        };
+        self.tree.insert(entry);
+
+        Ok(entry)
+    }
+
+    /// Remove a file, returning its blocks to the free list on the next
+    /// flush
+    pub fn delete_file(&mut self, object_id: u32) -> Result<Option<DatDirectoryEntry>, DatError> {
+        let Some(entry) = self.tree.get(object_id).copied() else {
+            return Ok(None);
+        };
+
+        let blocks = self.chain_blocks(entry.file_offset, entry.file_size, Some(object_id))?;
+        self.tree.remove(object_id);
+        self.released_blocks.extend(blocks);
+
+        Ok(Some(entry))
+    }
+
+    /// Write any changed directory nodes and the header
+    ///
+    /// The header is written once the new nodes are in place and again after
+    /// the old nodes' blocks have been freed, so stopping between the two
+    /// only leaks those blocks.
+    pub fn flush(&mut self) -> Result<(), DatError> {
+        for offset in std::mem::take(&mut self.tree.freed) {
+            let blocks = self.chain_blocks(offset, DAT_DIRECTORY_HEADER_OBJECT_SIZE, None)?;
+            self.released_blocks.extend(blocks);
+        }
+
+        let mut root = std::mem::take(&mut self.tree.root);
+        let result = self.write_node(&mut root);
+        self.tree.root = root;
+        self.header.btree = result?;
+        self.write_header()?;
+
+        if self.released_blocks.is_empty() {
+            return Ok(());
+        }
+
+        for block in std::mem::take(&mut self.released_blocks) {
+            self.free_block(block)?;
+        }
+
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<(), DatError> {
+        self.inner.seek(SeekFrom::Start(DAT_HEADER_OFFSET))?;
+        self.header.write(&mut self.inner)?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    /// Flush and hand back the underlying writer
+    pub fn finish(mut self) -> Result<W, DatError> {
+        self.flush()?;
+
+        Ok(self.inner)
+    }
+
+    fn read_node(
+        inner: &mut W,
+        header: &DatDatabaseHeader,
+        offset: u32,
+        seen: &mut HashSet<u32>,
+    ) -> Result<DatBTreeNode, DatError> {
+        if !seen.insert(offset) {
+            return Err(DatError::CorruptBlockChain {
+                object_id: None,
+                offset,
+                reason: "Directory node is referenced more than once".to_string(),
+            });
+        }
+
+        let mut validator = BlockChainValidator::new(header.block_size, header.file_size);
+        let buffer = DatBlockReader::read(
+            inner,
+            offset,
+            DAT_DIRECTORY_HEADER_OBJECT_SIZE,
+            &mut validator,
+        )?;
+        let node = DatDirectoryHeader::read(&mut Cursor::new(buffer))?;
+
+        let mut children = Vec::new();
+
+        if node.branches[0] != 0 {
+            for branch in &node.branches[..=node.entries.len()] {
+                children.push(Self::read_node(inner, header, *branch, seen)?);
+            }
+        }
+
+        Ok(DatBTreeNode {
+            offset: Some(offset),
+            dirty: false,
+            entries: node.entries,
+            children,
+        })
+    }
+
+    /// Write a node's children and then the node itself (if it changed) to
+    /// new blocks, returning its offset
+    fn write_node(&mut self, node: &mut DatBTreeNode) -> Result<u32, DatError> {
+        let mut branches = vec![0; 62];
+
+        for (branch, child) in branches.iter_mut().zip(node.children.iter_mut()) {
+            let previous = child.offset;
+            *branch = self.write_node(child)?;
+
+            // A child that moved means the branch pointing at it changed
+            if previous != child.offset {
+                node.dirty = true;
+            }
+        }
+
+        if let (Some(offset), false) = (node.offset, node.dirty) {
+            return Ok(offset);
+        }
+
+        let mut buffer = Vec::with_capacity(DAT_DIRECTORY_HEADER_OBJECT_SIZE as usize);
+        DatDirectoryHeader {
+            branches,
+            entry_count: node.entries.len() as u32,
+            entries: node.entries.clone(),
+        }
+        .write(&mut buffer)?;
+        buffer.resize(DAT_DIRECTORY_HEADER_OBJECT_SIZE as usize, 0);
+
+        if let Some(offset) = node.offset {
+            let blocks = self.chain_blocks(offset, DAT_DIRECTORY_HEADER_OBJECT_SIZE, None)?;
+            self.released_blocks.extend(blocks);
+        }
+
+        let offset = self.write_chain(&buffer)?;
+        node.offset = Some(offset);
+        node.dirty = false;
+
+        Ok(offset)
+    }
+
+    /// Offsets of the blocks making up the chain at `offset` holding `size`
+    /// bytes, read by following only the pointers
+    fn chain_blocks(
+        &mut self,
+        offset: u32,
+        size: u32,
+        object_id: Option<u32>,
+    ) -> Result<Vec<u32>, DatError> {
+        let mut validator = BlockChainValidator::new(self.header.block_size, self.header.file_size);
+
+        if let Some(object_id) = object_id {
+            validator = validator.for_object(object_id);
+        }
+
+        DatBlockReader::walk(&mut self.inner, offset, size, &mut validator)?;
+
+        Ok(validator.blocks().to_vec())
+    }
+
+    /// Write `data` as a chain in newly allocated blocks
+    ///
+    /// Returns the offset of the first block.
+    fn write_chain(&mut self, data: &[u8]) -> Result<u32, DatError> {
+        let data_size = (self.header.block_size - 4) as usize;
+        let block_count = data.len().div_ceil(data_size).max(1);
+        let mut blocks = Vec::with_capacity(block_count);
+
+        while blocks.len() < block_count {
+            let block = self.allocate_block()?;
+            blocks.push(block);
+        }
+
+        for (i, block) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).copied().unwrap_or(0);
+            let start = (i * data_size).min(data.len());
+            let end = (start + data_size).min(data.len());
+
+            self.write_block(*block, next, &data[start..end])?;
+        }
+
+        Ok(blocks[0])
+    }
+
+    /// Write a whole block, padding the data with zeroes
+    fn write_block(&mut self, offset: u32, next: u32, data: &[u8]) -> Result<(), DatError> {
+        let mut block = Vec::with_capacity(self.header.block_size as usize);
+        block.write_u32::<LittleEndian>(next)?;
+        block.extend_from_slice(data);
+        block.resize(self.header.block_size as usize, 0);
+
+        self.inner.seek(SeekFrom::Start(offset as u64))?;
+        self.inner.write_all(&block)?;
+
+        Ok(())
+    }
+
+    /// Take a block from the head of the free list, or append a new one to
+    /// the end of the DAT once the free list is empty
+    fn allocate_block(&mut self) -> Result<u32, DatError> {
+        if self.header.free_count == 0 || self.header.free_head == 0 {
+            let offset = self
+                .header
+                .file_size
+                .next_multiple_of(self.header.block_size);
+            self.header.file_size = offset
+                .checked_add(self.header.block_size)
+                .ok_or_else(|| DatError::InvalidData("DAT would grow past 4 GiB".to_string()))?;
+
+            return Ok(offset);
+        }
+
+        let offset = self.header.free_head;
+        let mut validator = BlockChainValidator::new(self.header.block_size, self.header.file_size);
+        validator.visit(offset)?;
+
+        self.inner.seek(SeekFrom::Start(offset as u64))?;
+        let next = self.inner.read_u32::<LittleEndian>()?;
+
+        self.header.free_count -= 1;
+
+        if self.header.free_count == 0 || next == 0 {
+            self.header.free_count = 0;
+            self.header.free_head = 0;
+            self.header.free_tail = 0;
+        } else {
+            self.header.free_head = next;
+        }
+
+        Ok(offset)
+    }
+
+    /// Put a block on the end of the free list
+    fn free_block(&mut self, offset: u32) -> Result<(), DatError> {
+        self.write_block(offset, 0, &[])?;
+
+        if self.header.free_count == 0 || self.header.free_tail == 0 {
+            self.header.free_head = offset;
+        } else {
+            self.inner
+                .seek(SeekFrom::Start(self.header.free_tail as u64))?;
+            self.inner.write_u32::<LittleEndian>(offset)?;
+        }
+
+        self.header.free_tail = offset;
+        self.header.free_count += 1;
+
+        Ok(())
+    }
+}
+
+/// The current time as the seconds since the epoch DAT entries store
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as u32)
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::{collections::BTreeMap, io::Cursor};
+
+    use super::*;
+    use crate::dat::{
+        diff::read_entry,
+        reader::{
+            dat_verifier::{DatVerifier, VerifyReport},
+            sync_file_reader::SyncFileRangeReader,
+            types::dat_database::DatDatabase,
+        },
+        writer::dat_btree::DAT_BTREE_MAX_ENTRIES,
+    };
+
+    const BLOCK_SIZE: u32 = 256;
+
+    /// Contents for an object, from empty up to several blocks long
+    fn contents(object_id: u32, version: u8) -> Vec<u8> {
+        let length = (object_id as usize * 37 + version as usize * 101) % 1200;
+
+        (0..length).map(|i| (i as u8) ^ version).collect()
+    }
+
+    /// Reopen the DAT with the reader, make sure it holds exactly `expected`
+    /// and that DatVerifier finds nothing wrong with it
+    async fn check(dat: &Cursor<Vec<u8>>, expected: &BTreeMap<u32, Vec<u8>>) -> VerifyReport {
+        let mut dat = Cursor::new(dat.get_ref().clone());
+        let db = DatDatabase::read(&mut dat).unwrap();
+        let entries = db.list_files(true).unwrap();
+
+        assert_eq!(entries.len(), expected.len());
+
+        for entry in &entries {
+            let data = read_entry(&mut dat, &db.header, entry).unwrap();
+            assert_eq!(Some(&data), expected.get(&entry.object_id));
+        }
+
+        let report = DatVerifier::verify(&mut SyncFileRangeReader::new(dat))
+            .await
+            .unwrap();
+
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.files, expected.len());
+
+        report
+    }
+
+    /// Object IDs in a scrambled but repeatable order
+    fn scrambled(count: u32) -> Vec<u32> {
+        let mut ids: Vec<u32> = (0..count).map(|i| 0x06000000 + i).collect();
+        let mut state = 12345u32;
+
+        for i in (1..ids.len()).rev() {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            ids.swap(i, (state >> 8) as usize % (i + 1));
+        }
+
+        ids
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_splits_replaces_and_merges() {
+        let ids = scrambled(2000);
+        let mut expected = BTreeMap::new();
+
+        // Enough entries for a three level tree, with a flush part way
+        // through so some nodes are rewritten rather than new
+        let mut writer =
+            DatWriter::create(Cursor::new(Vec::new()), DatDatabaseType::Portal, BLOCK_SIZE)
+                .unwrap();
+
+        for (i, &object_id) in ids.iter().enumerate() {
+            let data = contents(object_id, 0);
+            writer.write_file(object_id, &data).unwrap();
+            expected.insert(object_id, data);
+
+            if i == 700 {
+                writer.flush().unwrap();
+            }
+        }
+
+        let dat = writer.finish().unwrap();
+        let grown = check(&dat, &expected).await;
+        assert!(grown.directories > 1 + 2000 / DAT_BTREE_MAX_ENTRIES);
+
+        // Replace a third of the files with larger and smaller contents
+        let mut writer = DatWriter::open(dat).unwrap();
+
+        for &object_id in ids.iter().step_by(3) {
+            let data = contents(object_id, 1);
+            let entry = writer.write_file(object_id, &data).unwrap();
+            assert_eq!(entry.iteration, 2);
+            expected.insert(object_id, data);
+        }
+
+        let dat = writer.finish().unwrap();
+        check(&dat, &expected).await;
+
+        // Delete almost everything, merging nodes until the tree shrinks
+        let mut writer = DatWriter::open(dat).unwrap();
+
+        for &object_id in ids.iter().skip(50) {
+            assert!(writer.delete_file(object_id).unwrap().is_some());
+            expected.remove(&object_id);
+        }
+
+        assert!(writer.delete_file(0x07000000).unwrap().is_none());
+
+        let shrunk = check(&writer.finish().unwrap(), &expected).await;
+        assert_eq!(shrunk.directories, 1);
+        assert!(shrunk.free_blocks > 0);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod dat_btree;
+pub mod dat_compactor;
+pub mod dat_writer;
@@ -21,7 +21,7 @@ use std::{
 
 use image::{DynamicImage, ImageBuffer, Pixel, Rgba, RgbaImage};
 
-use crate::dat::file_types::texture::Texture;
+use crate::dat::{error::DatError, file_types::texture::Texture};
 
 #[derive(Debug)]
 pub struct Icon {
@@ -36,27 +36,27 @@ pub struct Icon {
 }
 
 impl Icon {
-    pub fn blend(&self) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, std::io::Error> {
+    pub fn blend(&self) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, DatError> {
         // TODO: Remove clones
 
         let mut texture_stack: Vec<Texture> = vec![];
 
-        if self.underlay.is_some() {
-            texture_stack.push(self.underlay.clone().unwrap());
+        if let Some(underlay) = &self.underlay {
+            texture_stack.push(underlay.clone());
         }
 
         texture_stack.push(self.base.clone());
 
-        if self.overlay.is_some() {
-            texture_stack.push(self.overlay.clone().unwrap());
+        if let Some(overlay) = &self.overlay {
+            texture_stack.push(overlay.clone());
         }
 
-        if self.overlay2.is_some() {
-            texture_stack.push(self.overlay2.clone().unwrap());
+        if let Some(overlay2) = &self.overlay2 {
+            texture_stack.push(overlay2.clone());
         }
 
-        if self.effect.is_some() {
-            texture_stack.push(self.effect.clone().unwrap());
+        if let Some(effect) = &self.effect {
+            texture_stack.push(effect.clone());
         }
 
         println!("Blending {} texture(s)", texture_stack.len());
@@ -64,9 +64,7 @@ impl Icon {
         // We now have a Vec<Texture> with at least one element so we create
         // our final ImageBuffer from the first layer and blend in the rest
         let base_buf = texture_stack[0].export()?;
-        let mut blended_image: RgbaImage =
-            ImageBuffer::from_raw(self.width as u32, self.height as u32, base_buf)
-                .expect("Failed to create ImageBuffer");
+        let mut blended_image: RgbaImage = self.layer_image(base_buf)?;
 
         if texture_stack.len() == 1 {
             println!("Early return since we only have one layer.");
@@ -74,12 +72,9 @@ impl Icon {
         }
 
         // Write any remaining textures in the stack
-        for l in 1..texture_stack.len() {
-            let next_layer = &texture_stack[l];
+        for next_layer in texture_stack.iter().skip(1) {
             let next_layer_buf = next_layer.export()?;
-            let next_layer_img: RgbaImage =
-                ImageBuffer::from_raw(self.width as u32, self.height as u32, next_layer_buf)
-                    .expect("Failed to create ImageBuffer");
+            let next_layer_img: RgbaImage = self.layer_image(next_layer_buf)?;
 
             for x in 0..self.width {
                 for y in 0..self.height {
@@ -93,7 +88,17 @@ impl Icon {
         Ok(blended_image)
     }
 
-    pub fn export(&self) -> Result<Vec<u8>, std::io::Error> {
+    /// Wrap a single exported layer in an ImageBuffer the size of the icon
+    fn layer_image(&self, buf: Vec<u8>) -> Result<RgbaImage, DatError> {
+        ImageBuffer::from_raw(self.width, self.height, buf).ok_or_else(|| {
+            DatError::InvalidData(format!(
+                "Icon layer is too small for the icon's dimensions ({}x{})",
+                self.width, self.height
+            ))
+        })
+    }
+
+    pub fn export(&self) -> Result<Vec<u8>, DatError> {
         let blended = self.blend()?;
 
         let image = DynamicImage::ImageRgba8(blended).resize(
@@ -104,12 +109,12 @@ impl Icon {
 
         let mut buffer = Vec::new();
         let mut cursor = Cursor::new(&mut buffer);
-        let _ = image.write_to(&mut cursor, image::ImageFormat::Png);
+        image.write_to(&mut cursor, image::ImageFormat::Png)?;
 
         Ok(buffer)
     }
 
-    pub fn export_to_file(&self, path: &str) -> Result<(), std::io::Error> {
+    pub fn export_to_file(&self, path: &str) -> Result<(), DatError> {
         let blended = self.blend()?;
 
         let image = DynamicImage::ImageRgba8(blended).resize(
@@ -122,9 +127,7 @@ impl Icon {
         let file = File::create(path)?;
         let mut writer = BufWriter::new(file);
 
-        image
-            .write_to(&mut writer, image::ImageFormat::Png)
-            .expect("Failed to write image to disk.");
+        image.write_to(&mut writer, image::ImageFormat::Png)?;
 
         Ok(())
     }
@@ -1,10 +1,10 @@
 pub mod cli_helper;
 
-use std::{error::Error, io::Cursor};
+use std::process::ExitCode;
 
-use crate::cli_helper::find_file_by_id;
 use clap::{Parser, Subcommand};
-use libac_rs::dat::enums::dat_file_type::DatFileType;
+use cli_helper::OutputFormat;
+use libac_rs::dat::error::DatError;
 
 #[derive(Parser)]
 #[command(name = "dat")]
@@ -12,22 +12,56 @@ use libac_rs::dat::enums::dat_file_type::DatFileType;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(
+        long,
+        global = true,
+        help = "Don't read or write the sidecar index (<dat>.idx) next to the DAT file"
+    )]
+    no_index: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Treat DATs as this type (Portal, Cell, Language) instead of detecting it"
+    )]
+    database_type: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Directory of <object ID>.bin or .png files to use in place of the DAT's own (for list and single-object extract)"
+    )]
+    overlay: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Extract {
         #[arg(
-            help = "Path to DAT file (e.g., ./client_portal.dat)",
+            help = "Path or URI to DAT file (e.g., ./client_portal.dat)",
             short('f'),
             long("file")
         )]
         dat_file: String,
-        #[arg(help = "Object ID to extract (e.g., 0321)")]
-        object_id: String,
+        #[arg(
+            help = "Object ID to extract (e.g., 0321)",
+            required_unless_present = "all"
+        )]
+        object_id: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "object_id",
+            help = "Extract every object into <output_dir>/<Type>/<ID>.<ext>"
+        )]
+        all: bool,
+        #[arg(
+            long = "type",
+            requires = "all",
+            help = "With --all, only extract objects of this type (e.g., Texture, Wave, Landblock)"
+        )]
+        file_type: Option<String>,
         #[arg(short, long, default_value = "./")]
         output_dir: String,
     },
+    #[command(about = "Dump a raw byte range or block chain from a DAT")]
     Read {
         #[arg(
             help = "Path or URI to DAT file (e.g., ./client_portal.dat)",
@@ -35,223 +69,248 @@ enum Commands {
             long("file")
         )]
         uri: String,
-        #[arg(short('o'), long("offset"), help = "Object ID to extract (e.g., 0321)")]
+        #[arg(
+            short('o'),
+            long("offset"),
+            help = "Offset to read from, in decimal or 0x hex (e.g., 0x190)"
+        )]
         offset: String,
-        #[arg(short('s'), long("size"))]
+        #[arg(short('s'), long("size"), help = "Number of bytes to read")]
         file_size: String,
+        #[arg(
+            long,
+            help = "Follow the block chain starting at the offset instead of reading straight through"
+        )]
+        chain: bool,
+        #[arg(long, help = "Write the raw bytes to stdout instead of a hex dump")]
+        binary: bool,
     },
     List {
-        #[arg(help = "Path to DAT file")]
+        #[arg(help = "Path or URI to DAT file")]
         dat_file: String,
         #[arg(long, help = "Print only the total count of files")]
         count: bool,
-        #[arg(long = "type", help = "Filter files by type (Texture, Unknown)")]
+        #[arg(long = "type", help = "Filter files by type (e.g., Texture, Wave, Landblock)")]
         file_type: Option<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    #[command(about = "List the objects added, removed and modified between two DATs")]
+    Diff {
+        #[arg(help = "Path or URI to the old DAT file")]
+        old_dat_file: String,
+        #[arg(help = "Path or URI to the new DAT file")]
+        new_dat_file: String,
+        #[arg(
+            long,
+            help = "Directory to write old/new/difference images of modified textures to"
+        )]
+        images: Option<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        #[arg(long, help = "Leave out objects whose only change is their date")]
+        ignore_date: bool,
+    },
+    #[command(about = "Create or apply patches holding the changes between two DATs")]
+    Patch {
+        #[command(subcommand)]
+        command: PatchCommands,
+    },
+    #[command(about = "Rewrite a DAT into a new file with every file stored contiguously")]
+    Compact {
+        #[arg(help = "Path or URI to DAT file")]
+        dat_file: String,
+        #[arg(short, long, help = "Path of the compacted DAT file to create")]
+        output: String,
+    },
+    #[command(about = "Build a new DAT from a directory of files named by hex object ID")]
+    Pack {
+        #[arg(help = "Directory of files to pack (e.g., ./06000001.bin)")]
+        input_dir: String,
+        #[arg(short, long, help = "Path of the DAT file to create")]
+        output: String,
+        #[arg(
+            long = "type",
+            default_value = "portal",
+            help = "Database type (Portal, Cell, Language)"
+        )]
+        database_type: String,
+        #[arg(
+            long,
+            default_value_t = 1024,
+            help = "Size of each block in bytes, including its 4 byte pointer to the next"
+        )]
+        block_size: u32,
+    },
+    #[command(about = "Show what a DAT's header says about it")]
+    Info {
+        #[arg(help = "Path or URI to DAT file")]
+        dat_file: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    #[command(about = "Check a DAT's B-tree, block chains and free list for corruption")]
+    Verify {
+        #[arg(help = "Path or URI to DAT file")]
+        dat_file: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatchCommands {
+    #[command(about = "Write a patch that turns one DAT into another")]
+    Create {
+        #[arg(help = "Path or URI to the old DAT file")]
+        old_dat_file: String,
+        #[arg(help = "Path or URI to the new DAT file")]
+        new_dat_file: String,
+        #[arg(short, long, help = "Path of the patch file to create")]
+        output: String,
+    },
+    #[command(about = "Apply a patch to a DAT in place")]
+    Apply {
+        #[arg(help = "Path to the DAT file to patch")]
+        dat_file: String,
+        #[arg(help = "Path to the patch file")]
+        patch_file: String,
     },
 }
 
 #[cfg(feature = "tokio")]
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    use libac_rs::dat::{
-        file_types::{dat_file::DatFile, texture::Texture},
-        reader::file_reader::FileRangeReader,
-        reader::types::dat_database::DatDatabase,
-    };
-    
-    
-
+async fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    match cli.command {
-        Commands::Extract {
-            dat_file,
-            object_id,
-            output_dir,
-        } => {
-            use libac_rs::dat::reader::dat_file_reader::DatFileReader;
+    exit_code(run(cli).await)
+}
 
-            println!(
-                "cli::extract: {:?}, {:?}, {:?}!",
-                dat_file, object_id, output_dir
-            );
+#[cfg(not(feature = "tokio"))]
+fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-            // Use async file operations with FileRangeReader for database reading
-            let file = tokio::fs::File::open(&dat_file).await?;
-            let compat_file = tokio_util::compat::TokioAsyncReadCompatExt::compat(file);
-            let mut range_reader = FileRangeReader::new(compat_file);
-            
-            let dat = DatDatabase::read_async(&mut range_reader).await?;
-            let found_file = find_file_by_id(&dat, &object_id).await?;
-            println!("Found file: {:?}", found_file);
+    exit_code(futures::executor::block_on(run(cli)))
+}
 
-            // Read the file into a buffer
-            // TODO: This is messy
+/// Print a failed command's error as a message rather than with Debug, as
+/// returning it from main would
+fn exit_code(result: Result<(), DatError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-            // TODO: Can this setup be simplified?
-            let file = tokio::fs::File::open(&dat_file).await?;
-            let compat_file = tokio_util::compat::TokioAsyncReadCompatExt::compat(file);
+async fn run(cli: Cli) -> Result<(), DatError> {
+    use crate::cli_helper::{
+        apply_patch, compact_dat, create_patch, diff_dats, extract_all_command, extract_object,
+        DatOpenOptions, info_dat, list_dat, pack_directory, parse_database_type, parse_number,
+        read_raw, verify_dat,
+    };
 
-            // My actual code
-            let mut file_reader: FileRangeReader<tokio_util::compat::Compat<tokio::fs::File>> =
-                FileRangeReader::new(compat_file);
-            let mut reader = DatFileReader::new(
-                found_file.file_size as usize,
-                dat.header.block_size as usize,
-            )?;
-            let buf = reader
-                .read_file(&mut file_reader, found_file.file_offset)
-                .await
-                .unwrap();
+    let options = DatOpenOptions {
+        use_index: !cli.no_index,
+        database_type: cli
+            .database_type
+            .as_deref()
+            .map(parse_database_type)
+            .transpose()?,
+        overlay: cli.overlay,
+    };
 
-            // Step 3: Convert the buffer into our file
-            // This is the common part
-            let mut buf_reader = Cursor::new(buf);
-            match found_file.file_type() {
-                libac_rs::dat::enums::dat_file_type::DatFileType::Texture => {
-                    let outer_file: DatFile<Texture> = DatFile::read(&mut buf_reader)?;
-                    let texture = outer_file.inner;
-                    let output_path = format!("{}.png", object_id);
-                    texture.to_png(&output_path, 1)?;
-                    println!("Texture saved to {:?}", output_path);
-                }
-                _ => {
-                    println!(
-                        "Unsupported file type for extraction: {:?}",
-                        found_file.file_type()
-                    );
-                }
-            }
-        }
-        Commands::Read {
-            uri,
-            offset,
-            file_size,
-        } => {
-            println!("uri: {}, offset: {}, file_size: {}", uri, offset, file_size);
-        }
-        Commands::List {
+    match cli.command {
+        Commands::Extract {
             dat_file,
-            count,
+            object_id: None,
             file_type,
+            output_dir,
+            ..
         } => {
-            // Use async database reading with RangeReader
-            let file = tokio::fs::File::open(&dat_file).await?;
-            let compat_file = tokio_util::compat::TokioAsyncReadCompatExt::compat(file);
-            let mut range_reader = FileRangeReader::new(compat_file);
-            
-            let dat = DatDatabase::read_async(&mut range_reader).await?;
-            let mut files = dat.list_files(true)?;
-
-            // Filter by type if specified
-            if let Some(type_str) = file_type {
-                let filter_type = match type_str.to_lowercase().as_str() {
-                    "texture" => DatFileType::Texture,
-                    "unknown" => DatFileType::Unknown,
-                    _ => {
-                        eprintln!(
-                            "Invalid file type: {}. Valid types are: Texture, Unknown",
-                            type_str
-                        );
-                        return Ok(());
-                    }
-                };
-                files.retain(|file| file.file_type() == filter_type);
-            }
-
-            if count {
-                println!("{}", files.len());
-            } else {
-                println!(
-                    "{:<10} {:<10} {:<10} {:<10}",
-                    "ID", "OFFSET", "SIZE", "TYPE"
-                );
-                for file in files {
-                    println!(
-                        "{:08X} {:<10} {:<10} {:<10}",
-                        file.object_id,
-                        file.file_offset,
-                        file.file_size,
-                        file.file_type()
-                    );
-                }
+            if !extract_all_command(&dat_file, &output_dir, file_type.as_deref(), &options).await?
+            {
+                std::process::exit(1);
             }
         }
-    }
-
-    Ok(())
-}
-
-#[cfg(not(feature = "tokio"))]
-fn main() -> Result<(), Box<dyn Error>> {
-    use libac_rs::dat::reader::types::dat_database::DatDatabase;
-    use std::fs::File;
-
-    let cli = Cli::parse();
-
-    match cli.command {
         Commands::Extract {
             dat_file,
-            object_id,
+            object_id: Some(object_id),
             output_dir,
+            ..
         } => {
-            println!(
-                "Extract: {:?}, {:?}, {:?}!",
-                dat_file, object_id, output_dir
-            );
-
-            // TODO
-            // 1. Determine method to use from uri
-            // 2. Do the read
+            extract_object(&dat_file, &object_id, &output_dir, &options).await?;
         }
         Commands::Read {
             uri,
             offset,
             file_size,
+            chain,
+            binary,
         } => {
-            println!("uri: {}, offset: {}, file_size: {}", uri, offset, file_size);
+            let offset = parse_number(&offset)?;
+            let size = parse_number(&file_size)?;
+            read_raw(&uri, offset, size, chain, binary).await?;
         }
         Commands::List {
             dat_file,
             count,
             file_type,
+            format,
         } => {
-            let mut db_file = File::open(&dat_file)?;
-            let dat = DatDatabase::read(&mut db_file)?;
-            let mut files = dat.list_files(true)?;
-
-            // Filter by type if specified
-            if let Some(type_str) = file_type {
-                let filter_type = match type_str.to_lowercase().as_str() {
-                    "texture" => DatFileType::Texture,
-                    "unknown" => DatFileType::Unknown,
-                    _ => {
-                        eprintln!(
-                            "Invalid file type: {}. Valid types are: Texture, Unknown",
-                            type_str
-                        );
-                        return Ok(());
-                    }
-                };
-                files.retain(|file| file.file_type() == filter_type);
+            list_dat(&dat_file, count, file_type.as_deref(), format, &options).await?;
+        }
+        Commands::Pack {
+            input_dir,
+            output,
+            database_type,
+            block_size,
+        } => {
+            let count = pack_directory(&input_dir, &output, &database_type, block_size)?;
+            println!("Packed {} files into {}", count, output);
+        }
+        Commands::Diff {
+            old_dat_file,
+            new_dat_file,
+            images,
+            format,
+            ignore_date,
+        } => {
+            diff_dats(
+                &old_dat_file,
+                &new_dat_file,
+                images.as_deref(),
+                format,
+                ignore_date,
+                &options,
+            )
+            .await?;
+        }
+        Commands::Patch { command } => match command {
+            PatchCommands::Create {
+                old_dat_file,
+                new_dat_file,
+                output,
+            } => create_patch(&old_dat_file, &new_dat_file, &output, &options).await?,
+            PatchCommands::Apply {
+                dat_file,
+                patch_file,
+            } => apply_patch(&dat_file, &patch_file)?,
+        },
+        Commands::Compact { dat_file, output } => {
+            compact_dat(&dat_file, &output, &options).await?;
+            println!("Compacted {} into {}", dat_file, output);
+        }
+        Commands::Info { dat_file, format } => {
+            if !info_dat(&dat_file, format, &options).await? {
+                std::process::exit(1);
             }
-
-            if count {
-                println!("{}", files.len());
-            } else {
-                println!(
-                    "{:<10} {:<10} {:<10} {:<10}",
-                    "ID", "OFFSET", "SIZE", "TYPE"
-                );
-                for file in files {
-                    println!(
-                        "{:08X} {:<10} {:<10} {:<10}",
-                        file.object_id,
-                        file.file_offset,
-                        file.file_size,
-                        file.file_type()
-                    );
-                }
+        }
+        Commands::Verify { dat_file, format } => {
+            if !verify_dat(&dat_file, format, &options).await? {
+                std::process::exit(1);
             }
         }
     }